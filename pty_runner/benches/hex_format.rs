@@ -0,0 +1,66 @@
+//! Times hex output of a full 100x300 screen, written through a BufWriter
+//! the way the CLI writes stdout, and fails if it slows down.
+//!
+//! Run with `cargo bench --bench hex_format`. The bound is relative to reading
+//! the same cells from vt100, which every formatter has to do, so it holds on
+//! any machine: the original per-cell `print!` took about 12 times that, a
+//! `write!` per field 4 times, formatting as bytes about 1.3 times.
+
+use pty_runner::format::{screen_formatter, RenderOptions};
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+const ROWS: u16 = 100;
+const COLS: u16 = 300;
+const RUNS: usize = 50;
+
+/// Fastest of `RUNS` runs of `run`, the least disturbed by other load
+fn best_of(mut run: impl FnMut()) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let started = Instant::now();
+            run();
+            started.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn main() {
+    let mut parser = vt100::Parser::new(ROWS, COLS, 0);
+    for i in 0..ROWS as usize * COLS as usize / 10 {
+        parser.process(format!("\x1b[3{}m0123456789", i % 8).as_bytes());
+    }
+    let screen = parser.screen();
+    let formatter = screen_formatter("hex", (8, 16)).expect("hex is a screen format");
+    let options = RenderOptions::default();
+
+    let reading = best_of(|| {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                std::hint::black_box(screen.cell(row, col).map(|cell| cell.contents()));
+            }
+        }
+    });
+    let formatting = best_of(|| {
+        let hex = formatter.format(screen, ROWS, COLS, &options);
+        let mut out = BufWriter::new(std::io::sink());
+        out.write_all(hex.as_bytes()).unwrap();
+        out.flush().unwrap();
+    });
+
+    println!(
+        "hex {}x{}: {:.2} ms ({:.2} ms reading the cells, {:.1}x)",
+        ROWS,
+        COLS,
+        formatting.as_secs_f64() * 1000.0,
+        reading.as_secs_f64() * 1000.0,
+        formatting.as_secs_f64() / reading.as_secs_f64()
+    );
+    assert!(
+        formatting < reading * 2,
+        "hex formatting took {:?}, over twice the {:?} spent reading the cells",
+        formatting,
+        reading
+    );
+}
@@ -0,0 +1,347 @@
+//! Text attributes vt100 does not track: dim, blink, strikethrough, hidden
+//! (hidden has no hex attrs bit; JSON, HTML and SVG report it)
+//!
+//! vt100 only keeps bold, italic, underline and inverse per cell. To recover
+//! the rest, the same output is fed to a second "shadow" parser with every
+//! SGR sequence replaced by one setting the foreground to a palette index
+//! whose bits are the extra attributes in effect. Everything else passes
+//! through untouched, so both parsers place, scroll and erase cells
+//! identically and a cell's shadow foreground gives its extra attributes.
+
+/// Faint text (SGR 2, cleared by 22)
+pub const ATTR_DIM: u8 = 0x10;
+/// Blinking text (SGR 5 or 6, cleared by 25)
+pub const ATTR_BLINK: u8 = 0x20;
+/// Crossed-out text (SGR 9, cleared by 29)
+pub const ATTR_STRIKETHROUGH: u8 = 0x40;
+/// Concealed text (SGR 8, cleared by 28)
+pub const ATTR_HIDDEN: u8 = 0x80;
+
+/// Longest CSI parameter string buffered; longer sequences are applied as
+/// SGR while they stream past, and dropped if they turn out to be anything
+/// else (no real sequence but SGR gets that long)
+const MAX_CSI_LEN: usize = 64;
+
+enum ScanState {
+    Normal,
+    Escape,
+    /// Parameter and intermediate bytes of a CSI sequence so far
+    Csi(Vec<u8>),
+    /// A CSI sequence past `MAX_CSI_LEN`
+    LongCsi(LongCsi),
+}
+
+/// A CSI sequence too long to buffer, applied as SGR one field at a time
+struct LongCsi {
+    /// None once a byte rules SGR out
+    sgr: Option<SgrFields>,
+    /// The field being read, capped at `MAX_CSI_LEN`
+    field: Vec<u8>,
+}
+
+impl LongCsi {
+    fn push(&mut self, byte: u8) {
+        match byte {
+            b';' => {
+                if let Some(sgr) = &mut self.sgr {
+                    sgr.apply(&self.field);
+                }
+                self.field.clear();
+            }
+            b'0'..=b'9' | b':' if self.field.len() < MAX_CSI_LEN => self.field.push(byte),
+            b'0'..=b'9' | b':' => {}
+            _ => self.sgr = None,
+        }
+    }
+
+    /// Attributes after the sequence, if it is SGR
+    fn finish(&mut self) -> Option<u8> {
+        let mut sgr = self.sgr.take()?;
+        sgr.apply(&self.field);
+        Some(sgr.bits)
+    }
+}
+
+/// Shadow parser tracking the extra attributes of every cell
+/// Feed it the same bytes, in the same order, as the screen's own parser
+pub struct ExtraAttrs {
+    state: ScanState,
+    /// Extra attributes currently in effect
+    bits: u8,
+    parser: vt100::Parser,
+}
+
+impl ExtraAttrs {
+    pub fn new(rows: u16, cols: u16) -> Self {
+        Self::with_scrollback(rows, cols, 0)
+    }
+
+    /// Shadow parser that also keeps `scrollback` rows scrolled off the top,
+    /// to read the extra attributes of the screen parser's scrollback
+    pub fn with_scrollback(rows: u16, cols: u16, scrollback: usize) -> Self {
+        Self {
+            state: ScanState::Normal,
+            bits: 0,
+            parser: vt100::Parser::new(rows, cols, scrollback),
+        }
+    }
+
+    /// Process output; sequences may be split across calls
+    pub fn process(&mut self, data: &[u8]) {
+        let shadow = self.rewrite(data);
+        self.parser.process(&shadow);
+    }
+
+    /// Resize along with the screen's own parser
+    pub fn set_size(&mut self, rows: u16, cols: u16) {
+        self.parser.set_size(rows, cols);
+    }
+
+    /// Screen whose cells carry the extra attributes (see `extra_attr_bits`)
+    pub fn screen(&self) -> &vt100::Screen {
+        self.parser.screen()
+    }
+
+    /// The shadow parser itself, for copying out its scrollback (whose cells
+    /// carry the extra attributes like the screen's, see `cell_attr_bits`)
+    pub fn parser_mut(&mut self) -> &mut vt100::Parser {
+        &mut self.parser
+    }
+
+    /// Replace each SGR sequence in `data` with the shadow encoding
+    fn rewrite(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            match &mut self.state {
+                ScanState::Normal => {
+                    if byte == 0x1B {
+                        self.state = ScanState::Escape;
+                    } else {
+                        out.push(byte);
+                    }
+                }
+                ScanState::Escape => match byte {
+                    b'[' => self.state = ScanState::Csi(Vec::new()),
+                    0x1B => out.push(0x1B),
+                    _ => {
+                        // RIS resets every attribute
+                        if byte == b'c' {
+                            self.bits = 0;
+                        }
+                        out.extend([0x1B, byte]);
+                        self.state = ScanState::Normal;
+                    }
+                },
+                ScanState::Csi(params) => match byte {
+                    0x20..=0x3F if params.len() < MAX_CSI_LEN => params.push(byte),
+                    0x20..=0x3F => {
+                        let mut long = LongCsi {
+                            sgr: Some(SgrFields::new(self.bits)),
+                            field: Vec::new(),
+                        };
+                        for &b in params.iter().chain([&byte]) {
+                            long.push(b);
+                        }
+                        self.state = ScanState::LongCsi(long);
+                    }
+                    0x40..=0x7E => {
+                        let params = std::mem::take(params);
+                        self.state = ScanState::Normal;
+                        if byte == b'm'
+                            && params
+                                .iter()
+                                .all(|b| b.is_ascii_digit() || b";:".contains(b))
+                        {
+                            self.bits = apply_sgr(self.bits, &params);
+                            push_shadow_sgr(&mut out, self.bits);
+                        } else {
+                            out.extend(b"\x1b[");
+                            out.extend(params);
+                            out.push(byte);
+                        }
+                    }
+                    _ => {
+                        // Not a sequence we understand: hand it over unchanged
+                        out.extend(b"\x1b[");
+                        out.extend(std::mem::take(params));
+                        out.push(byte);
+                        self.state = ScanState::Normal;
+                    }
+                },
+                ScanState::LongCsi(long) => match byte {
+                    0x20..=0x3F => long.push(byte),
+                    0x40..=0x7E => {
+                        if let (b'm', Some(bits)) = (byte, long.finish()) {
+                            self.bits = bits;
+                            push_shadow_sgr(&mut out, bits);
+                        }
+                        self.state = ScanState::Normal;
+                    }
+                    _ => {
+                        out.push(byte);
+                        self.state = ScanState::Normal;
+                    }
+                },
+            }
+        }
+        out
+    }
+}
+
+/// Extra attribute bits (`ATTR_*`) of the cell at `row`, `col` of a screen
+/// from `ExtraAttrs::screen`; zero outside the screen
+pub fn extra_attr_bits(screen: &vt100::Screen, row: u16, col: u16) -> u8 {
+    screen.cell(row, col).map_or(0, cell_attr_bits)
+}
+
+/// Extra attribute bits (`ATTR_*`) of a cell of the shadow parser
+pub fn cell_attr_bits(cell: &vt100::Cell) -> u8 {
+    match cell.fgcolor() {
+        vt100::Color::Idx(bits) => bits,
+        _ => 0,
+    }
+}
+
+/// Attributes after applying the SGR parameters `params` to `bits`
+fn apply_sgr(bits: u8, params: &[u8]) -> u8 {
+    let mut sgr = SgrFields::new(bits);
+    for field in params.split(|&b| b == b';') {
+        sgr.apply(field);
+    }
+    sgr.bits
+}
+
+/// SGR parameters applied one `;`-separated field at a time
+struct SgrFields {
+    bits: u8,
+    /// Fields still belonging to an extended color
+    color: ColorFields,
+}
+
+#[derive(Clone, Copy)]
+enum ColorFields {
+    None,
+    /// The kind (5 indexed, 2 RGB) comes next
+    Kind,
+    /// This many color values come next
+    Values(usize),
+}
+
+impl SgrFields {
+    fn new(bits: u8) -> Self {
+        Self {
+            bits,
+            color: ColorFields::None,
+        }
+    }
+
+    fn apply(&mut self, field: &[u8]) {
+        // Extended colors: skip the color's own fields
+        match self.color {
+            ColorFields::None => {}
+            ColorFields::Kind => {
+                self.color = match field {
+                    b"5" => ColorFields::Values(1),
+                    b"2" => ColorFields::Values(3),
+                    _ => ColorFields::None,
+                };
+                return;
+            }
+            ColorFields::Values(count) => {
+                self.color = if count > 1 {
+                    ColorFields::Values(count - 1)
+                } else {
+                    ColorFields::None
+                };
+                return;
+            }
+        }
+        let field = String::from_utf8_lossy(field);
+        // Colon sub-parameters belong to their field (e.g. 38:2::1:2:3)
+        let (code, has_subparams) = match field.split_once(':') {
+            Some((code, _)) => (code, true),
+            None => (&field[..], false),
+        };
+        let bits = &mut self.bits;
+        match code.parse::<u16>().unwrap_or(0) {
+            0 => *bits = 0,
+            2 => *bits |= ATTR_DIM,
+            22 => *bits &= !ATTR_DIM,
+            5 | 6 => *bits |= ATTR_BLINK,
+            25 => *bits &= !ATTR_BLINK,
+            8 => *bits |= ATTR_HIDDEN,
+            28 => *bits &= !ATTR_HIDDEN,
+            9 => *bits |= ATTR_STRIKETHROUGH,
+            29 => *bits &= !ATTR_STRIKETHROUGH,
+            38 | 48 | 58 if !has_subparams => self.color = ColorFields::Kind,
+            _ => {}
+        }
+    }
+}
+
+fn push_shadow_sgr(out: &mut Vec<u8>, bits: u8) {
+    if bits == 0 {
+        out.extend(b"\x1b[m");
+    } else {
+        out.extend(format!("\x1b[0;38;5;{}m", bits).into_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_attributes_per_cell() {
+        let mut attrs = ExtraAttrs::new(2, 10);
+        attrs.process(b"\x1b[2ma\x1b[5;1mb\x1b[22mc\x1b[9;38;5;200md\x1b[0me\r\n\x1b[8mf");
+        let bits: Vec<u8> = (0..5)
+            .map(|col| extra_attr_bits(attrs.screen(), 0, col))
+            .collect();
+        assert_eq!(
+            bits,
+            [
+                ATTR_DIM,
+                ATTR_DIM | ATTR_BLINK,
+                ATTR_BLINK,
+                ATTR_BLINK | ATTR_STRIKETHROUGH,
+                0
+            ]
+        );
+        assert_eq!(extra_attr_bits(attrs.screen(), 1, 0), ATTR_HIDDEN);
+    }
+
+    #[test]
+    fn sequences_split_across_chunks() {
+        let mut attrs = ExtraAttrs::new(1, 4);
+        attrs.process(b"\x1b");
+        attrs.process(b"[2");
+        attrs.process(b"mx\x1b[?25l\x1b[>4;1my");
+        assert_eq!(extra_attr_bits(attrs.screen(), 0, 0), ATTR_DIM);
+        // Private-marker sequences ending in 'm' are not SGR
+        assert_eq!(extra_attr_bits(attrs.screen(), 0, 1), ATTR_DIM);
+        assert!(attrs.screen().hide_cursor());
+    }
+
+    #[test]
+    fn sgr_sequences_longer_than_the_buffer_still_apply() {
+        // A palette color past the buffer must not leak into the shadow
+        let long = format!(
+            "\x1b[{}2;38;5;200;9mx\x1b[{}1;29my",
+            "0;".repeat(40),
+            "1;".repeat(40)
+        );
+        let mut attrs = ExtraAttrs::new(1, 4);
+        for chunk in long.as_bytes().chunks(7) {
+            attrs.process(chunk);
+        }
+        assert_eq!(
+            extra_attr_bits(attrs.screen(), 0, 0),
+            ATTR_DIM | ATTR_STRIKETHROUGH
+        );
+        assert_eq!(extra_attr_bits(attrs.screen(), 0, 1), ATTR_DIM);
+        // Long non-SGR sequences are dropped rather than misread
+        attrs.process(format!("\x1b[?{}25lz", "1;".repeat(40)).as_bytes());
+        assert_eq!(extra_attr_bits(attrs.screen(), 0, 2), ATTR_DIM);
+    }
+}
@@ -0,0 +1,589 @@
+//! Batch mode: many captures described by one TOML manifest
+//!
+//! Each `[[case]]` table mirrors the single-run flags. Cases run
+//! concurrently on a few worker threads (see `run_cases`), each in its own
+//! PTY, and their results are reported in manifest order; relative paths in
+//! a case are resolved against the manifest's directory.
+
+use crate::compare::compare_output;
+use crate::format::{
+    format_json_state, format_report, screen_formatter, HexVersion, JsonDetails, RenderOptions,
+};
+use crate::runner::{run, CaptureResult, RunConfig, SpawnError, TimeoutKind};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// One test case from a manifest
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchCase {
+    /// Name shown in the summary
+    pub name: String,
+    /// Program to run; a bare name is looked up on PATH
+    pub executable: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Raw keyboard input file
+    pub keyboard_input: Option<PathBuf>,
+    pub stdin_file: Option<PathBuf>,
+    #[serde(default = "default_rows")]
+    pub rows: u16,
+    #[serde(default = "default_cols")]
+    pub cols: u16,
+    /// Timeout in milliseconds
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Golden file the output is compared against
+    pub expect: Option<PathBuf>,
+    /// Output format: hex, text, json, html or svg
+    #[serde(default = "default_output")]
+    pub output: String,
+    /// Where the formatted capture is written
+    pub output_path: Option<PathBuf>,
+    /// Kill the program once it writes more than this many bytes
+    pub max_output_bytes: Option<usize>,
+    /// Hex cell encoding, 1 (the default) or 2 (see `--hex-version`)
+    #[serde(default = "default_hex_version")]
+    pub hex_version: u8,
+}
+
+fn default_rows() -> u16 {
+    25
+}
+
+fn default_cols() -> u16 {
+    80
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+fn default_output() -> String {
+    "hex".to_string()
+}
+
+fn default_hex_version() -> u8 {
+    1
+}
+
+impl BatchCase {
+    /// The `hex_version` of the case as a `HexVersion`
+    fn hex_version(&self) -> HexVersion {
+        if self.hex_version == 1 {
+            HexVersion::V1
+        } else {
+            HexVersion::V2
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    case: Vec<toml::Value>,
+}
+
+/// Parse a manifest of `[[case]]` tables
+/// Errors name the offending case (by name if it has one, else by number)
+pub fn parse_manifest(text: &str) -> Result<Vec<BatchCase>> {
+    let manifest: Manifest = toml::from_str(text).context("Invalid manifest")?;
+    manifest
+        .case
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let label = match value.get("name").and_then(toml::Value::as_str) {
+                Some(name) => format!("case {:?}", name),
+                None => format!("case #{}", i + 1),
+            };
+            let case: BatchCase = value
+                .try_into()
+                .map_err(|e: toml::de::Error| anyhow::anyhow!("{}: {}", label, e.message()))?;
+            if !(1..=2).contains(&case.hex_version) {
+                anyhow::bail!("{}: hex_version must be 1 or 2", label);
+            }
+            Ok(case)
+        })
+        .collect()
+}
+
+/// A finished case
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub name: String,
+    pub outcome: CaseOutcome,
+    /// Wall-clock time the case took
+    pub duration: Duration,
+}
+
+/// What to do with a case's expect file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectMode {
+    /// Compare the capture against it
+    Compare,
+    /// Replace it with the capture (see `update_expect_file`)
+    Update { force: bool },
+}
+
+/// How a case ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaseOutcome {
+    /// Ran to completion and matched its expect file, if any
+    Pass,
+    /// Output differs from the expect file; one line per difference
+    Fail(Vec<String>),
+    /// Killed by the timeout
+    Timeout,
+    /// Could not be run (missing file, spawn failure, ...)
+    Error(String),
+    /// The expect file was rewritten with the new capture
+    Updated,
+    /// The expect file already held the new capture
+    UpToDate,
+}
+
+impl CaseOutcome {
+    pub fn passed(&self) -> bool {
+        matches!(
+            self,
+            CaseOutcome::Pass | CaseOutcome::Updated | CaseOutcome::UpToDate
+        )
+    }
+
+    /// Short label for the summary table
+    pub fn label(&self) -> &'static str {
+        match self {
+            CaseOutcome::Pass => "pass",
+            CaseOutcome::Fail(_) => "fail",
+            CaseOutcome::Timeout => "timeout",
+            CaseOutcome::Error(_) => "error",
+            CaseOutcome::Updated => "updated",
+            CaseOutcome::UpToDate => "up to date",
+        }
+    }
+}
+
+/// Run one case, writing its output file and comparing against its expect
+/// file; relative paths are taken from `base_dir`
+pub fn run_case(case: &BatchCase, base_dir: &Path, mode: ExpectMode) -> CaseOutcome {
+    match try_run_case(case, base_dir, mode) {
+        Ok(outcome) => outcome,
+        Err(e) => CaseOutcome::Error(format!("{:#}", e)),
+    }
+}
+
+fn try_run_case(case: &BatchCase, base_dir: &Path, mode: ExpectMode) -> Result<CaseOutcome> {
+    let resolve = |path: &Path| base_dir.join(path);
+    let read = |path: &Path, what: &str| {
+        fs::read(resolve(path)).with_context(|| format!("Failed to read {}: {:?}", what, path))
+    };
+
+    // A bare program name is looked up on PATH rather than next to the manifest
+    let executable = if case.executable.components().count() > 1 {
+        resolve(&case.executable)
+    } else {
+        case.executable.clone()
+    };
+    let mut config = RunConfig::new(executable);
+    config.log_prefix = format!("[{}] ", case.name);
+    config.args = case.args.iter().map(Into::into).collect();
+    config.working_dir = Some(base_dir.to_path_buf()).filter(|dir| dir.is_dir());
+    config.rows = case.rows;
+    config.cols = case.cols;
+    config.timeout = Duration::from_millis(case.timeout);
+    config.max_output_bytes = case.max_output_bytes;
+    config.keyboard_input = match &case.keyboard_input {
+        Some(path) => vec![read(path, "keyboard input")?],
+        None => Vec::new(),
+    };
+    config.stdin = match &case.stdin_file {
+        Some(path) => Some(read(path, "stdin file")?),
+        None => None,
+    };
+
+    let result = match run(&config) {
+        Ok(result) => result,
+        Err(e) if e.is::<SpawnError>() => return Ok(CaseOutcome::Error(format!("{:#}", e))),
+        Err(e) => return Err(e),
+    };
+    if result.timed_out == Some(TimeoutKind::Interrupted) {
+        return Ok(CaseOutcome::Error("Interrupted".to_string()));
+    }
+    if result.truncated {
+        return Ok(CaseOutcome::Error(format!(
+            "Output exceeded {} bytes",
+            case.max_output_bytes.unwrap_or_default()
+        )));
+    }
+
+    let options = RenderOptions {
+        extra_attrs: Some(result.extra_attrs.clone()),
+        hex_version: case.hex_version(),
+        ..Default::default()
+    };
+    let formatted = if case.output == "json" {
+        let details = JsonDetails {
+            exit_status: result.exit_status.as_ref(),
+            timed_out: result.exit_status.is_none(),
+            ..Default::default()
+        };
+        format_json_state(&result.screen, case.rows, case.cols, &options, &details)?
+    } else if case.output == "both" {
+        let exit = result.exit_label();
+        format_report(&result.screen, case.rows, case.cols, &options, &exit)
+    } else {
+        screen_formatter(&case.output, (8, 16))
+            .with_context(|| format!("Unknown output format: {:?}", case.output))?
+            .format(&result.screen, case.rows, case.cols, &options)
+    };
+    if let Some(path) = &case.output_path {
+        write_atomically(&resolve(path), formatted.as_bytes())
+            .with_context(|| format!("Failed to write output: {:?}", path))?;
+    }
+
+    if let (Some(path), ExpectMode::Update { force }) = (&case.expect, mode) {
+        return Ok(
+            match update_expect_file(&resolve(path), formatted.as_bytes(), &result, force) {
+                Ok(true) => CaseOutcome::Updated,
+                Ok(false) => CaseOutcome::UpToDate,
+                Err(e) => CaseOutcome::Error(format!("{:#}", e)),
+            },
+        );
+    }
+    if result.exit_status.is_none() {
+        return Ok(CaseOutcome::Timeout);
+    }
+    if let Some(path) = &case.expect {
+        let expected = read(path, "expected output")?;
+        let differences = compare_output(
+            &case.output,
+            &expected,
+            formatted.as_bytes(),
+            case.cols,
+            &[],
+        );
+        if !differences.is_empty() {
+            return Ok(CaseOutcome::Fail(differences));
+        }
+    }
+    Ok(CaseOutcome::Pass)
+}
+
+/// Run `cases` on up to `jobs` worker threads (each case gets its own PTY)
+/// Results come back in manifest order whatever order the cases finish in
+pub fn run_cases(
+    cases: &[BatchCase],
+    base_dir: &Path,
+    jobs: usize,
+    mode: ExpectMode,
+) -> Vec<CaseResult> {
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(vec![None; cases.len()]);
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, cases.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(case) = cases.get(index) else {
+                    break;
+                };
+                eprintln!("[{}] Running case", case.name);
+                let started = Instant::now();
+                let outcome = run_case(case, base_dir, mode);
+                let result = CaseResult {
+                    name: case.name.clone(),
+                    outcome,
+                    duration: started.elapsed(),
+                };
+                outcomes.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    outcomes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every case is run"))
+        .collect()
+}
+
+/// Write `data` to a temporary file next to `path`, sync it and rename it
+/// into place, so readers never see a partial file
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)
+}
+
+/// Write a new capture to an expect (golden) file, creating its directory
+/// Refuses, unless `force` is set, when the run timed out or the program was
+/// killed by a signal, since such a capture is rarely what should be kept
+/// Returns false if the file already held exactly `data`
+pub fn update_expect_file(
+    path: &Path,
+    data: &[u8],
+    result: &CaptureResult,
+    force: bool,
+) -> Result<bool> {
+    if !force {
+        let problem = match (&result.exit_status, result.signal()) {
+            (None, _) => Some("timed out".to_string()),
+            (Some(_), Some(signal)) => Some(format!("was terminated by {}", signal)),
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            anyhow::bail!(
+                "Not updating {:?}: the run {} (use --update-expect-force to update anyway)",
+                path,
+                problem
+            );
+        }
+    }
+    if fs::read(path).is_ok_and(|existing| existing == data) {
+        return Ok(false);
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    }
+    write_atomically(path, data).with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(true)
+}
+
+/// Summary table with one row per case and a pass count
+pub fn format_summary(results: &[CaseResult]) -> String {
+    let width = results
+        .iter()
+        .map(|result| result.name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("case".len());
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<width$}  result", "case");
+    for CaseResult { name, outcome, .. } in results {
+        let detail = match outcome {
+            CaseOutcome::Fail(differences) => format!(" ({} difference(s))", differences.len()),
+            CaseOutcome::Error(message) => format!(" ({})", message),
+            _ => String::new(),
+        };
+        let _ = writeln!(out, "{:<width$}  {}{}", name, outcome.label(), detail);
+    }
+    let passed = results
+        .iter()
+        .filter(|result| result.outcome.passed())
+        .count();
+    let _ = writeln!(out, "{} of {} cases passed", passed, results.len());
+    out
+}
+
+/// JUnit XML report: a `<testsuite>` named `suite` with one `<testcase>` per
+/// result; mismatches and timeouts are `<failure>`s (of type "mismatch" and
+/// "timeout"), cases that could not run are `<error>`s
+pub fn format_junit(suite: &str, results: &[CaseResult]) -> String {
+    let count =
+        |pred: fn(&CaseOutcome) -> bool| results.iter().filter(|r| pred(&r.outcome)).count();
+    let failures = count(|o| matches!(o, CaseOutcome::Fail(_) | CaseOutcome::Timeout));
+    let errors = count(|o| matches!(o, CaseOutcome::Error(_)));
+    let total: Duration = results.iter().map(|r| r.duration).sum();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        out,
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        xml_escape(suite),
+        results.len(),
+        failures,
+        errors,
+        total.as_secs_f64()
+    );
+    for result in results {
+        let _ = write!(
+            out,
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            xml_escape(&result.name),
+            xml_escape(suite),
+            result.duration.as_secs_f64()
+        );
+        match &result.outcome {
+            CaseOutcome::Pass | CaseOutcome::Updated | CaseOutcome::UpToDate => {
+                out.push_str("/>\n")
+            }
+            CaseOutcome::Fail(differences) => {
+                let message = format!(
+                    "{} difference(s): {}",
+                    differences.len(),
+                    differences.first().map(String::as_str).unwrap_or("")
+                );
+                let _ = writeln!(
+                    out,
+                    ">\n    <failure type=\"mismatch\" message=\"{}\">{}</failure>\n  </testcase>",
+                    xml_escape(&message),
+                    xml_escape(&differences.join("\n"))
+                );
+            }
+            CaseOutcome::Timeout => out.push_str(
+                ">\n    <failure type=\"timeout\" message=\"timed out\"/>\n  </testcase>\n",
+            ),
+            CaseOutcome::Error(message) => {
+                let _ = writeln!(
+                    out,
+                    ">\n    <error message=\"{}\"/>\n  </testcase>",
+                    xml_escape(message)
+                );
+            }
+        }
+    }
+    out.push_str("</testsuite>\n");
+    out
+}
+
+/// Escape text for an XML attribute or element, dropping the characters
+/// XML 1.0 cannot represent at all (C0 controls other than tab, CR and LF,
+/// U+FFFE and U+FFFF)
+fn xml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Kept as references so attribute values keep their line breaks
+            '\n' => out.push_str("&#10;"),
+            '\r' => out.push_str("&#13;"),
+            '\t' => out.push_str("&#9;"),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_cases_get_defaults() {
+        let cases = parse_manifest(
+            "[[case]]\nname = \"a\"\nexecutable = \"prog\"\n\n\
+             [[case]]\nname = \"b\"\nexecutable = \"prog\"\nrows = 3\noutput = \"text\"\n",
+        )
+        .unwrap();
+        assert_eq!(cases.len(), 2);
+        assert_eq!(
+            (cases[0].rows, cases[0].cols, cases[0].timeout),
+            (25, 80, 5000)
+        );
+        assert_eq!(cases[0].output, "hex");
+        assert_eq!(cases[0].hex_version(), HexVersion::V1);
+        assert_eq!((cases[1].rows, cases[1].output.as_str()), (3, "text"));
+    }
+
+    #[test]
+    fn manifest_cases_can_opt_into_hex_version_2() {
+        let cases = parse_manifest("[[case]]\nname = \"a\"\nexecutable = \"p\"\nhex_version = 2\n");
+        assert_eq!(cases.unwrap()[0].hex_version(), HexVersion::V2);
+        let bad = parse_manifest("[[case]]\nname = \"a\"\nexecutable = \"p\"\nhex_version = 3\n");
+        assert_eq!(
+            format!("{:#}", bad.unwrap_err()),
+            "case \"a\": hex_version must be 1 or 2"
+        );
+    }
+
+    #[test]
+    fn manifest_errors_name_case_and_field() {
+        let unknown = parse_manifest("[[case]]\nname = \"menu\"\nexecutable = \"p\"\nrowz = 3\n");
+        let message = format!("{:#}", unknown.unwrap_err());
+        assert!(message.contains("case \"menu\""), "{}", message);
+        assert!(message.contains("rowz"), "{}", message);
+
+        let missing = parse_manifest("[[case]]\nname = \"x\"\n[[case]]\nexecutable = \"p\"\n");
+        let message = format!("{:#}", missing.unwrap_err());
+        assert!(message.contains("case \"x\""), "{}", message);
+        assert!(message.contains("executable"), "{}", message);
+    }
+
+    fn result(name: &str, outcome: CaseOutcome, ms: u64) -> CaseResult {
+        CaseResult {
+            name: name.to_string(),
+            outcome,
+            duration: Duration::from_millis(ms),
+        }
+    }
+
+    #[test]
+    fn summary_lists_every_case() {
+        let summary = format_summary(&[
+            result("first", CaseOutcome::Pass, 0),
+            result(
+                "second",
+                CaseOutcome::Fail(vec!["a".to_string(), "b".to_string()]),
+                0,
+            ),
+            result("t", CaseOutcome::Timeout, 0),
+        ]);
+        assert_eq!(
+            summary,
+            "case    result\nfirst   pass\nsecond  fail (2 difference(s))\nt       timeout\n\
+             1 of 3 cases passed\n"
+        );
+    }
+
+    #[test]
+    fn junit_marks_each_outcome() {
+        let report = format_junit(
+            "tests",
+            &[
+                result("ok", CaseOutcome::Pass, 1500),
+                result(
+                    "menu",
+                    CaseOutcome::Fail(vec!["(0,0): expected 'a' got '<'".to_string()]),
+                    20,
+                ),
+                result("slow", CaseOutcome::Timeout, 300),
+                result("gone", CaseOutcome::Error("no such file".to_string()), 0),
+            ],
+        );
+        assert_eq!(
+            report,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuite name=\"tests\" tests=\"4\" failures=\"2\" errors=\"1\" time=\"1.820\">\n\
+             \x20 <testcase name=\"ok\" classname=\"tests\" time=\"1.500\"/>\n\
+             \x20 <testcase name=\"menu\" classname=\"tests\" time=\"0.020\">\n\
+             \x20   <failure type=\"mismatch\" message=\"1 difference(s): (0,0): expected &apos;a&apos; got &apos;&lt;&apos;\">\
+             (0,0): expected &apos;a&apos; got &apos;&lt;&apos;</failure>\n\
+             \x20 </testcase>\n\
+             \x20 <testcase name=\"slow\" classname=\"tests\" time=\"0.300\">\n\
+             \x20   <failure type=\"timeout\" message=\"timed out\"/>\n\
+             \x20 </testcase>\n\
+             \x20 <testcase name=\"gone\" classname=\"tests\" time=\"0.000\">\n\
+             \x20   <error message=\"no such file\"/>\n\
+             \x20 </testcase>\n\
+             </testsuite>\n"
+        );
+    }
+
+    #[test]
+    fn xml_escape_drops_invalid_controls() {
+        assert_eq!(
+            xml_escape("a&b\x1b[0m\u{7}\tc\n\u{ffff}"),
+            "a&amp;b[0m&#9;c&#10;"
+        );
+    }
+}
@@ -0,0 +1,24 @@
+//! Test helper that prints a fixed set of ANSI sequences
+//!
+//! Used by the integration tests as a portable child program, so they do not
+//! depend on a compiled C program or platform tools being on PATH.
+
+use std::io::Write;
+
+/// Two lines exercising the plain, bold, 16-color, truecolor and combined
+/// attribute paths of the hex encoder
+const FIXTURE: &str = concat!(
+    "A",
+    "\x1b[1;31mB\x1b[0m",
+    "\x1b[44mC\x1b[0m",
+    "\x1b[38;2;1;2;3mD\x1b[0m",
+    "\n",
+    "\x1b[3;4;7mE\x1b[0m",
+    "\n",
+);
+
+fn main() {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(FIXTURE.as_bytes()).unwrap();
+    stdout.flush().unwrap();
+}
@@ -0,0 +1,1160 @@
+//! Comparing captured output against expected (golden) output
+
+use crate::format::{
+    ColorMode, HEX_ATTR_WIDE, HEX_CELL_LEN, HEX_COLOR_DEFAULT, HEX_COLOR_INDEXED, HEX_COLOR_RGB,
+    HEX_CURSOR_LEN, HEX_INDEXED_CELL_LEN, HEX_INDEXED_HEADER, HEX_SCROLLBACK_HEADER,
+    REPORT_EXIT_TRAILER, REPORT_HEX_SEPARATOR, REPORT_NO_EXIT, TEXT_SCROLLBACK_MARKER,
+    TITLE_TRAILER, WIDE_CONTINUATION_CODEPOINT,
+};
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fmt::Write as _;
+
+/// Rectangle of the screen excluded from comparisons
+/// Parts that extend past the screen edge are simply never hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mask {
+    pub row: u16,
+    pub col: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Mask {
+    pub fn contains(&self, row: u16, col: u16) -> bool {
+        row >= self.row
+            && row < self.row.saturating_add(self.height)
+            && col >= self.col
+            && col < self.col.saturating_add(self.width)
+    }
+}
+
+/// Parse a ROW,COL,WIDTH,HEIGHT mask rectangle
+pub fn parse_mask(value: &str) -> Result<Mask, String> {
+    let fields: Vec<u16> = value
+        .split(',')
+        .map(|field| field.trim().parse::<u16>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("expected ROW,COL,WIDTH,HEIGHT, got {:?}", value))?;
+    match fields[..] {
+        [row, col, width, height] => Ok(Mask {
+            row,
+            col,
+            width,
+            height,
+        }),
+        _ => Err(format!("expected ROW,COL,WIDTH,HEIGHT, got {:?}", value)),
+    }
+}
+
+/// True if any mask covers the given cell
+pub fn is_masked(masks: &[Mask], row: u16, col: u16) -> bool {
+    masks.iter().any(|mask| mask.contains(row, col))
+}
+
+/// One cell decoded from the hex format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexCell {
+    pub codepoint: u32,
+    /// Further codepoints of a multi-codepoint cell (combining marks, ZWJ
+    /// sequences), empty for ordinary cells
+    pub combining: Vec<u32>,
+    pub fg: HexColor,
+    pub bg: HexColor,
+    pub attrs: u8,
+}
+
+/// A cell color decoded from the hex format; always `Rgb` unless the output
+/// was written in `ColorMode::Indexed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HexColor {
+    Rgb(u8, u8, u8),
+    /// Palette index
+    Indexed(u8),
+    /// The terminal's default color
+    Default,
+}
+
+impl fmt::Display for HexColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexColor::Rgb(r, g, b) => write!(f, "{:02X}{:02X}{:02X}", r, g, b),
+            HexColor::Indexed(idx) => write!(f, "index {}", idx),
+            HexColor::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl HexCell {
+    /// Decode a single hex cell: 22 chars, or a `+NN` multi-codepoint cell
+    pub fn parse(text: &str) -> Option<Self> {
+        Self::parse_as(text, ColorMode::Rgb)
+    }
+
+    /// Decode a single hex cell written in `color_mode` (26 chars, plus any
+    /// extra codepoints, for `ColorMode::Indexed`)
+    pub fn parse_as(text: &str, color_mode: ColorMode) -> Option<Self> {
+        if text.len() != hex_cell_len(text, color_mode)? {
+            return None;
+        }
+        let field = |range: std::ops::Range<usize>| {
+            text.get(range)
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        };
+        let (codepoints, colors) = match text.strip_prefix('+') {
+            Some(_) => {
+                let count = field(1..3)? as usize;
+                let codepoints = (0..count)
+                    .map(|i| field(3 + i * 8..11 + i * 8))
+                    .collect::<Option<Vec<u32>>>()?;
+                (codepoints, 3 + count * 8)
+            }
+            None => (vec![field(0..8)?], 8),
+        };
+        let (&codepoint, combining) = codepoints.split_first()?;
+        let byte =
+            |start: usize| field(colors + start..colors + start + 2).map(|value| value as u8);
+        let (fg, bg, attrs) = match color_mode {
+            ColorMode::Rgb => (
+                HexColor::Rgb(byte(0)?, byte(2)?, byte(4)?),
+                HexColor::Rgb(byte(6)?, byte(8)?, byte(10)?),
+                byte(12)?,
+            ),
+            ColorMode::Indexed => {
+                let color = |start: usize| {
+                    let (r, g, b) = (byte(start + 2)?, byte(start + 4)?, byte(start + 6)?);
+                    match byte(start)? {
+                        HEX_COLOR_DEFAULT if (r, g, b) == (0, 0, 0) => Some(HexColor::Default),
+                        HEX_COLOR_INDEXED if (r, g) == (0, 0) => Some(HexColor::Indexed(b)),
+                        HEX_COLOR_RGB => Some(HexColor::Rgb(r, g, b)),
+                        _ => None,
+                    }
+                };
+                (color(0)?, color(8)?, byte(16)?)
+            }
+        };
+        Some(Self {
+            codepoint,
+            combining: combining.to_vec(),
+            fg,
+            bg,
+            attrs,
+        })
+    }
+
+    /// Character for display in diff messages
+    pub fn display_char(&self) -> char {
+        char::from_u32(self.codepoint).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+
+    /// Full cell contents, including any combining codepoints
+    pub fn text(&self) -> String {
+        std::iter::once(self.codepoint)
+            .chain(self.combining.iter().copied())
+            .map(|cp| char::from_u32(cp).unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    /// Quoted contents for diff messages: 'X' for one codepoint, "e\u{301}" for more,
+    /// followed by "(wide)" for a wide character
+    pub fn display(&self) -> String {
+        if self.is_wide_continuation() {
+            return "(wide continuation)".to_string();
+        }
+        let quoted = if self.combining.is_empty() {
+            format!("{:?}", self.display_char())
+        } else {
+            format!("{:?}", self.text())
+        };
+        if self.attrs & HEX_ATTR_WIDE != 0 {
+            format!("{} (wide)", quoted)
+        } else {
+            quoted
+        }
+    }
+
+    /// True for the column covered by the right half of a wide character
+    pub fn is_wide_continuation(&self) -> bool {
+        self.codepoint == WIDE_CONTINUATION_CODEPOINT && !self.is_masked()
+    }
+
+    /// True for the sentinel written for masked cells
+    pub fn is_masked(&self) -> bool {
+        self.codepoint == 0 && self.attrs == 0xFF
+    }
+
+    /// Describe the cell for diff messages, e.g. 'X' fg=F0F0F0 bg=000000 attrs=00
+    pub fn describe(&self) -> String {
+        format!(
+            "{} fg={} bg={} attrs={:02X}",
+            self.display(),
+            self.fg,
+            self.bg,
+            self.attrs
+        )
+    }
+}
+
+/// Cell fields to leave out of a comparison
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IgnoreFields {
+    /// Foreground and background colors
+    pub colors: bool,
+    /// The attributes byte, except `HEX_ATTR_WIDE`
+    pub attrs: bool,
+}
+
+impl HexCell {
+    /// The cell with the `ignore`d fields reset (colors to default,
+    /// attributes to none), so that comparing only looks at the others
+    pub fn without(&self, ignore: IgnoreFields) -> HexCell {
+        let mut cell = self.clone();
+        if ignore.colors {
+            cell.fg = HexColor::Default;
+            cell.bg = HexColor::Default;
+        }
+        if ignore.attrs {
+            cell.attrs &= HEX_ATTR_WIDE;
+        }
+        cell
+    }
+}
+
+/// Color mode of hex output, and the output after its `HEX_INDEXED_HEADER`
+/// line if it has one
+pub fn split_hex_header(text: &str) -> (ColorMode, &str) {
+    let text = text.trim_start();
+    match text.split_once('\n') {
+        Some((header, rest)) if header.trim_end() == HEX_INDEXED_HEADER => {
+            (ColorMode::Indexed, rest)
+        }
+        None if text.trim_end() == HEX_INDEXED_HEADER => (ColorMode::Indexed, ""),
+        _ => (ColorMode::Rgb, text),
+    }
+}
+
+/// Decode a hex dump into cells, ignoring surrounding whitespace, any
+/// trailing partial cell (such as the cursor trailer) and any later lines
+/// (such as the scrollback section)
+pub fn parse_hex_cells(text: &str) -> Result<Vec<HexCell>> {
+    let (color_mode, text) = split_hex_header(text);
+    let line = text.trim().lines().next().unwrap_or("");
+    Ok(decode_hex_line(line, color_mode)?.0)
+}
+
+/// Decode a hex capture into cells like `parse_hex_cells`, but reject
+/// anything that is not one of its known trailers: after the cells only the
+/// 10-char cursor trailer may follow on the same line, and later lines may
+/// only be a `TITLE` line or a `SCROLLBACK <rows>` header with its line of
+/// cells
+pub fn parse_hex_capture(text: &str) -> Result<Vec<HexCell>> {
+    let (color_mode, text) = split_hex_header(text);
+    let text = text.trim();
+    let mut lines = text.lines();
+    let (cells, rest) = decode_hex_line(lines.next().unwrap_or(""), color_mode)?;
+    check_line_end(rest, cells.len(), text, true)?;
+    while let Some(line) = lines.next() {
+        let offset = line.as_ptr() as usize - text.as_ptr() as usize;
+        if line.trim().is_empty() || title_of(line).is_some() {
+            continue;
+        }
+        let scrollback_rows = line
+            .strip_prefix(HEX_SCROLLBACK_HEADER)
+            .and_then(|rows| rows.strip_prefix(' '))
+            .and_then(|rows| rows.trim_end().parse::<usize>().ok());
+        if scrollback_rows.is_none() {
+            anyhow::bail!("Unexpected line at offset {}: {:?}", offset, line);
+        }
+        let line = lines.next().unwrap_or("");
+        let (scrollback, rest) =
+            decode_hex_line(line, color_mode).context("Invalid scrollback section")?;
+        check_line_end(rest, scrollback.len(), text, false)?;
+    }
+    Ok(cells)
+}
+
+/// Fail on the `rest` of a line after its whole cells, unless it is empty or
+/// (with `cursor`) the cursor trailer; `text` is the capture `rest` belongs to
+fn check_line_end(rest: &str, cells: usize, text: &str, cursor: bool) -> Result<()> {
+    let rest = rest.trim_end();
+    let is_hex = rest.bytes().all(|b| b.is_ascii_hexdigit());
+    if rest.is_empty() || (cursor && is_hex && rest.len() == HEX_CURSOR_LEN) {
+        return Ok(());
+    }
+    let offset = rest.as_ptr() as usize - text.as_ptr() as usize;
+    if is_hex {
+        anyhow::bail!(
+            "Truncated hex cell at offset {} (after {} cells)",
+            offset,
+            cells
+        );
+    }
+    anyhow::bail!(
+        "Invalid hex cell at offset {} (after {} cells)",
+        offset,
+        cells
+    )
+}
+
+/// Decode the whole cells at the start of `line`, returning them with the
+/// rest of the line (a partial cell or trailer)
+fn decode_hex_line(line: &str, color_mode: ColorMode) -> Result<(Vec<HexCell>, &str)> {
+    let mut cells = Vec::new();
+    let mut start = 0;
+    while start < line.len() {
+        let rest = &line[start..];
+        let invalid = || anyhow::anyhow!("Invalid hex cell at offset {}", start);
+        let len = hex_cell_len(rest, color_mode).ok_or_else(invalid)?;
+        if rest.len() < len && !rest.starts_with('+') {
+            break;
+        }
+        let cell = rest
+            .get(..len)
+            .and_then(|cell| HexCell::parse_as(cell, color_mode))
+            .ok_or_else(invalid)?;
+        cells.push(cell);
+        start += len;
+    }
+    Ok((cells, &line[start..]))
+}
+
+/// Length of the hex cell at the start of `text`, read from its `+NN` prefix
+/// for multi-codepoint cells
+fn hex_cell_len(text: &str, color_mode: ColorMode) -> Option<usize> {
+    let len = match color_mode {
+        ColorMode::Rgb => HEX_CELL_LEN,
+        ColorMode::Indexed => HEX_INDEXED_CELL_LEN,
+    };
+    if text.starts_with('+') {
+        let count = text
+            .get(1..3)
+            .and_then(|hex| usize::from_str_radix(hex, 16).ok())?;
+        Some(len + 3 + (count.checked_sub(1)? * 8))
+    } else {
+        Some(len)
+    }
+}
+
+/// Compare two text lines treating masked columns of `row` as equal
+fn text_lines_match_masked(expected: &str, actual: &str, row: u16, masks: &[Mask]) -> bool {
+    let expected = text_columns(expected);
+    let actual = text_columns(actual);
+    (0..expected.len().max(actual.len())).all(|col| {
+        is_masked(masks, row, col as u16)
+            || expected.get(col).map_or(" ", String::as_str)
+                == actual.get(col).map_or(" ", String::as_str)
+    })
+}
+
+/// Split a text line into the screen columns it covers: a wide character
+/// takes its column and an empty one after it, and zero-width codepoints
+/// (such as combining marks) join the character before them
+fn text_columns(line: &str) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    let mut last: Option<usize> = None;
+    for ch in line.chars() {
+        match (char_width(ch), last) {
+            (0, Some(index)) => columns[index].push(ch),
+            (width, _) => {
+                last = Some(columns.len());
+                columns.push(ch.to_string());
+                if width == 2 {
+                    columns.push(String::new());
+                }
+            }
+        }
+    }
+    columns
+}
+
+/// Columns `ch` advances the cursor by on the screen (0, 1 or 2)
+fn char_width(ch: char) -> usize {
+    if ch.is_ascii() {
+        return 1;
+    }
+    let mut parser = vt100::Parser::new(1, 3, 0);
+    parser.process(ch.to_string().as_bytes());
+    parser.screen().cursor_position().1 as usize
+}
+
+/// Title from a `TITLE <title>` trailer line
+fn title_of(line: &str) -> Option<&str> {
+    line.strip_prefix(TITLE_TRAILER)?.strip_prefix(' ')
+}
+
+/// Compare two decoded screens cell by cell, returning a description of each
+/// difference: the character when it differs, otherwise the colors and
+/// attributes (cells covered by `masks` always match)
+pub fn compare_states(
+    expected: &[HexCell],
+    actual: &[HexCell],
+    cols: u16,
+    masks: &[Mask],
+) -> Vec<String> {
+    let mut differences = Vec::new();
+    if expected.len() != actual.len() {
+        differences.push(format!(
+            "cell count: expected {} got {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+    let cols = cols.max(1) as usize;
+    for (i, (e, a)) in expected.iter().zip(actual).enumerate() {
+        let (row, col) = (i / cols, i % cols);
+        if e == a || is_masked(masks, row as u16, col as u16) {
+            continue;
+        }
+        let (e_text, a_text) = if e.codepoint != a.codepoint || e.combining != a.combining {
+            (e.display(), a.display())
+        } else {
+            (e.describe(), a.describe())
+        };
+        differences.push(format!(
+            "row {} col {}: expected {} got {}",
+            row, col, e_text, a_text
+        ));
+    }
+    differences
+}
+
+/// Compare formatted output with expected output, returning a description of
+/// each difference (empty when they match)
+/// Hex output is compared cell by cell, text line by line, others byte-wise
+/// Cells covered by `masks` always match in hex and text comparisons
+pub fn compare_output(
+    format: &str,
+    expected: &[u8],
+    actual: &[u8],
+    cols: u16,
+    masks: &[Mask],
+) -> Vec<String> {
+    let expected_text = String::from_utf8_lossy(expected);
+    let actual_text = String::from_utf8_lossy(actual);
+
+    match format {
+        "hex" => {
+            let (expected_mode, expected_body) = split_hex_header(&expected_text);
+            let (actual_mode, actual_body) = split_hex_header(&actual_text);
+            if expected_mode != actual_mode {
+                return vec![format!(
+                    "color mode: expected {:?} got {:?}",
+                    expected_mode, actual_mode
+                )];
+            }
+            let (expected_cells, actual_cells) = match (
+                parse_hex_cells(&expected_text),
+                parse_hex_cells(&actual_text),
+            ) {
+                (Ok(e), Ok(a)) => (e, a),
+                (Err(e), _) => return vec![format!("expected file is not valid hex: {}", e)],
+                (_, Err(e)) => return vec![format!("captured output is not valid hex: {}", e)],
+            };
+
+            let mut differences = compare_states(&expected_cells, &actual_cells, cols, masks);
+            // Sections after the screen line (scrollback) must match exactly
+            let sections = |text: &str| {
+                text.trim()
+                    .split_once('\n')
+                    .map(|(_, rest)| {
+                        rest.lines()
+                            .filter(|line| title_of(line).is_none())
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                            .trim()
+                            .to_string()
+                    })
+                    .unwrap_or_default()
+            };
+            if sections(expected_body) != sections(actual_body) {
+                differences.push("scrollback section differs".to_string());
+            }
+            let title = |text: &str| text.lines().skip(1).find_map(title_of).map(str::to_string);
+            let (expected_title, actual_title) = (title(expected_body), title(actual_body));
+            if expected_title != actual_title {
+                differences.push(format!(
+                    "title: expected {:?} got {:?}",
+                    expected_title, actual_title
+                ));
+            }
+            differences
+        }
+        "text" => {
+            let expected_lines: Vec<&str> = expected_text.lines().collect();
+            let actual_lines: Vec<&str> = actual_text.lines().collect();
+            // Masks are in screen coordinates, which start below the scrollback marker
+            let screen_start = actual_lines
+                .iter()
+                .position(|line| line.starts_with(TEXT_SCROLLBACK_MARKER))
+                .map_or(0, |i| i + 1);
+            let mut differences = Vec::new();
+            for i in 0..expected_lines.len().max(actual_lines.len()) {
+                let e = expected_lines.get(i).copied().unwrap_or("");
+                let a = actual_lines.get(i).copied().unwrap_or("");
+                let masked = i >= screen_start
+                    && text_lines_match_masked(e, a, (i - screen_start) as u16, masks);
+                if e != a && !masked {
+                    differences.push(format!("line {}: expected {:?} got {:?}", i, e, a));
+                }
+            }
+            differences
+        }
+        "both" => {
+            // The text grid is derived from the hex cells, so compare those
+            // (honouring masks) and then the cursor and exit lines
+            let (Some(expected_report), Some(actual_report)) =
+                (split_report(&expected_text), split_report(&actual_text))
+            else {
+                return vec![format!("report has no {:?} line", REPORT_HEX_SEPARATOR)];
+            };
+            let mut differences = compare_output(
+                "hex",
+                expected_report.0.as_bytes(),
+                actual_report.0.as_bytes(),
+                cols,
+                masks,
+            );
+            // A replay has no exit to compare
+            let no_exit = format!("{} {}", REPORT_EXIT_TRAILER, REPORT_NO_EXIT);
+            let is_exit = |line: &str| line.starts_with(&format!("{} ", REPORT_EXIT_TRAILER));
+            for (e, a) in expected_report.1.iter().zip(&actual_report.1) {
+                let replayed = is_exit(e) && is_exit(a) && (*e == no_exit || *a == no_exit);
+                if e != a && !replayed {
+                    differences.push(format!("expected {:?} got {:?}", e, a));
+                }
+            }
+            if expected_report.1.len() != actual_report.1.len() {
+                differences.push(format!(
+                    "report trailer: expected {} lines got {}",
+                    expected_report.1.len(),
+                    actual_report.1.len()
+                ));
+            }
+            differences
+        }
+        "hash" => {
+            // One digest per file; a missing trailing newline is not a difference
+            let (expected_hash, actual_hash) = (expected_text.trim(), actual_text.trim());
+            if expected_hash == actual_hash {
+                Vec::new()
+            } else {
+                vec![format!(
+                    "screen hash: expected {} got {}",
+                    expected_hash, actual_hash
+                )]
+            }
+        }
+        _ => {
+            if expected == actual {
+                Vec::new()
+            } else {
+                vec![format!(
+                    "output differs ({} bytes expected, {} bytes captured)",
+                    expected.len(),
+                    actual.len()
+                )]
+            }
+        }
+    }
+}
+
+/// Split a "both" report into its hex dump and the `CURSOR` and `EXIT`
+/// lines after it
+fn split_report(text: &str) -> Option<(String, Vec<&str>)> {
+    let (_, rest) = text.rsplit_once(&format!("\n{}\n", REPORT_HEX_SEPARATOR))?;
+    let lines: Vec<&str> = rest.lines().collect();
+    // The hex dump is one line, two with the indexed color mode header
+    let hex_lines = lines
+        .iter()
+        .position(|line| line.starts_with("CURSOR "))
+        .unwrap_or(lines.len());
+    Some((lines[..hex_lines].join("\n"), lines[hex_lines..].to_vec()))
+}
+
+/// Format a cell-level diff report between expected and actual hex cells:
+/// a detailed listing (capped at `limit`) followed by a screen-shaped
+/// overview where '.' is a matching (or masked) cell and '#' a differing one
+pub fn format_cell_diff(
+    expected: &[HexCell],
+    actual: &[HexCell],
+    rows: u16,
+    cols: u16,
+    masks: &[Mask],
+    limit: usize,
+) -> String {
+    let mut out = String::new();
+    let cell_count = rows as usize * cols as usize;
+
+    if expected.len() != cell_count {
+        let _ = writeln!(
+            out,
+            "Dimension mismatch: expected file has {} cells, screen is {} rows x {} cols ({} cells)",
+            expected.len(),
+            rows,
+            cols,
+            cell_count
+        );
+        return out;
+    }
+
+    let cell_differs = |i: usize| {
+        let (row, col) = ((i / cols as usize) as u16, (i % cols as usize) as u16);
+        expected[i] != actual[i] && !is_masked(masks, row, col)
+    };
+    let differing: Vec<usize> = (0..cell_count).filter(|&i| cell_differs(i)).collect();
+    let _ = writeln!(out, "{} of {} cells differ", differing.len(), cell_count);
+
+    for &i in differing.iter().take(limit) {
+        let (row, col) = (i / cols as usize, i % cols as usize);
+        let _ = writeln!(out, "row {} col {}:", row, col);
+        let _ = writeln!(out, "  expected {}", expected[i].describe());
+        let _ = writeln!(out, "  actual   {}", actual[i].describe());
+    }
+    if differing.len() > limit {
+        let _ = writeln!(out, "... and {} more", differing.len() - limit);
+    }
+
+    if !differing.is_empty() {
+        out.push('\n');
+        for row in 0..rows as usize {
+            let line: String = (0..cols as usize)
+                .map(|col| {
+                    if cell_differs(row * cols as usize + col) {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+/// Render expected and actual screens side by side as text, with a third
+/// column marking each differing cell: '!' where the characters differ and
+/// '*' where only the colors or attributes do (masked cells always match)
+pub fn render_diff(
+    expected: &[HexCell],
+    actual: &[HexCell],
+    rows: u16,
+    cols: u16,
+    masks: &[Mask],
+) -> String {
+    let cols = cols as usize;
+    let row_text = |cells: &[HexCell], row: usize| -> String {
+        cells
+            .iter()
+            .skip(row * cols)
+            .take(cols)
+            .filter(|cell| cell.codepoint != WIDE_CONTINUATION_CODEPOINT)
+            .map(|cell| {
+                if cell.display_char().is_control() {
+                    "?".to_string()
+                } else {
+                    cell.text()
+                }
+            })
+            .collect()
+    };
+    let marker = |row: usize, col: usize| {
+        let i = row * cols + col;
+        match (expected.get(i), actual.get(i)) {
+            _ if is_masked(masks, row as u16, col as u16) => ' ',
+            (Some(e), Some(a)) if e == a => ' ',
+            (Some(e), Some(a)) if e.codepoint == a.codepoint && e.combining == a.combining => '*',
+            _ => '!',
+        }
+    };
+
+    // Wide enough for the header even on narrow screens
+    let width = cols.max("expected".len());
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<width$} | {:<width$} |", "expected", "actual",);
+    for row in 0..rows as usize {
+        let markers: String = (0..cols).map(|col| marker(row, col)).collect();
+        let _ = writeln!(
+            out,
+            "{:<width$} | {:<width$} | {}",
+            row_text(expected, row),
+            row_text(actual, row),
+            markers.trim_end(),
+        );
+    }
+    out
+}
+
+/// Render decoded cells as ANSI text that redraws the screen: one line per
+/// row, each cell preceded by the SGR sequence for its colors and attributes
+/// whenever they change; emulating it on an empty screen of the same size
+/// gives back the same cells (default colors aside in `ColorMode::Rgb`,
+/// where they were written as plain RGB)
+/// Later rows follow a CR LF and the last row has no line break, so the
+/// screen never scrolls; masked cells become blanks
+pub fn format_ansi_cells(cells: &[HexCell], rows: u16, cols: u16) -> String {
+    let cols = cols as usize;
+    let mut out = String::new();
+    let mut style = None;
+    for row in 0..rows as usize {
+        if row > 0 {
+            out.push_str("\r\n");
+        }
+        // A `HexVersion::V1` wide character is followed by a plain cell
+        let mut covered = false;
+        for cell in cells.iter().skip(row * cols).take(cols) {
+            let masked = cell.is_masked();
+            if cell.is_wide_continuation() || std::mem::take(&mut covered) {
+                // Covered by the wide character before it
+                continue;
+            }
+            let sgr = if masked {
+                "\x1b[0m".to_string()
+            } else {
+                ansi_sgr(cell)
+            };
+            if style.as_ref() != Some(&sgr) {
+                out.push_str(&sgr);
+                style = Some(sgr);
+            }
+            match cell.text() {
+                _ if masked => out.push(' '),
+                text if text.chars().any(char::is_control) => out.push('?'),
+                text => {
+                    covered = cell.attrs & HEX_ATTR_WIDE == 0 && is_wide_text(&text);
+                    out.push_str(&text)
+                }
+            }
+        }
+    }
+    out.push_str("\x1b[0m");
+    out
+}
+
+/// True if `text` takes two columns on the screen, for cells without the
+/// `HEX_ATTR_WIDE` bit to say so
+fn is_wide_text(text: &str) -> bool {
+    if text.is_ascii() {
+        return false;
+    }
+    let mut parser = vt100::Parser::new(1, 2, 0);
+    parser.process(text.as_bytes());
+    parser.screen().cell(0, 0).is_some_and(vt100::Cell::is_wide)
+}
+
+/// SGR sequence setting exactly the colors and attributes of `cell`
+fn ansi_sgr(cell: &HexCell) -> String {
+    // Attribute bits of the hex format and their SGR parameters
+    const ATTR_SGR: [(u8, u8); 7] = [
+        (0x01, 1),
+        (0x10, 2),
+        (0x02, 3),
+        (0x04, 4),
+        (0x20, 5),
+        (0x08, 7),
+        (0x40, 9),
+    ];
+    let mut sgr = "\x1b[0".to_string();
+    for (bit, param) in ATTR_SGR {
+        if cell.attrs & bit != 0 {
+            let _ = write!(sgr, ";{}", param);
+        }
+    }
+    for (color, base) in [(cell.fg, 38), (cell.bg, 48)] {
+        match color {
+            HexColor::Rgb(r, g, b) => {
+                let _ = write!(sgr, ";{};2;{};{};{}", base, r, g, b);
+            }
+            HexColor::Indexed(idx) => {
+                let _ = write!(sgr, ";{};5;{}", base, idx);
+            }
+            HexColor::Default => {}
+        }
+    }
+    sgr.push('m');
+    sgr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_cell() -> HexCell {
+        HexCell {
+            codepoint: ' ' as u32,
+            combining: Vec::new(),
+            fg: HexColor::Rgb(240, 240, 240),
+            bg: HexColor::Rgb(0, 0, 0),
+            attrs: 0,
+        }
+    }
+
+    #[test]
+    fn hex_cells_round_trip() {
+        let cell = HexCell::parse("00000041CD313100000001").unwrap();
+        assert_eq!(cell.display_char(), 'A');
+        assert_eq!(cell.fg, HexColor::Rgb(205, 49, 49));
+        assert_eq!(cell.attrs, 1);
+        assert!(HexCell::parse("0000004").is_none());
+        assert!(parse_hex_cells("zz000041CD313100000001").is_err());
+
+        // e + combining acute, followed by a cursor trailer
+        let cells = parse_hex_cells("+020000006500000301F0F0F0000000000000000000000001").unwrap();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].text(), "e\u{301}");
+        assert_eq!(cells[0].display(), "\"e\\u{301}\"");
+        assert!(parse_hex_cells("+0200000065").is_err());
+    }
+
+    #[test]
+    fn hex_captures_allow_only_known_trailers() {
+        let cell = "00000041F0F0F000000000";
+        let capture = format!(
+            "{0}{0}0000000101\nSCROLLBACK 1\n{0}\nTITLE hi there\n",
+            cell
+        );
+        assert_eq!(parse_hex_capture(&capture).unwrap().len(), 2);
+        assert_eq!(parse_hex_capture(cell).unwrap().len(), 1);
+
+        let error = |text: &str| parse_hex_capture(text).unwrap_err().to_string();
+        assert_eq!(
+            error(&format!("{}{}", cell, &cell[..15])),
+            "Truncated hex cell at offset 22 (after 1 cells)"
+        );
+        assert_eq!(
+            error(&format!("{}garbage", cell)),
+            "Invalid hex cell at offset 22 (after 1 cells)"
+        );
+        assert_eq!(
+            error(&format!("{}\ngarbage", cell)),
+            "Unexpected line at offset 23: \"garbage\""
+        );
+        assert_eq!(
+            error(&format!("{0}\nSCROLLBACK 1\n{0}0000000101", cell)),
+            "Truncated hex cell at offset 58 (after 1 cells)"
+        );
+        // The lenient parser used for comparisons drops the same leftovers
+        assert_eq!(
+            parse_hex_cells(&format!("{}garbage", cell)).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn indexed_hex_cells_keep_the_color_kind() {
+        // 'A' in palette color 4 on the default background, bold
+        let hex = format!("{}\n00000041010000040000000001", HEX_INDEXED_HEADER);
+        let cells = parse_hex_cells(&(hex.clone() + "0000004202123456020000FF00")).unwrap();
+        assert_eq!(cells.len(), 2);
+        assert_eq!(
+            (cells[0].fg, cells[0].bg, cells[0].attrs),
+            (HexColor::Indexed(4), HexColor::Default, 1)
+        );
+        assert_eq!(
+            (cells[1].fg, cells[1].bg),
+            (HexColor::Rgb(0x12, 0x34, 0x56), HexColor::Rgb(0, 0, 0xFF))
+        );
+        assert_eq!(cells[0].describe(), "'A' fg=index 4 bg=default attrs=01");
+        // Unknown tags and stray bytes in index values are rejected
+        assert!(parse_hex_cells(&format!(
+            "{}\n00000041030000040000000001",
+            HEX_INDEXED_HEADER
+        ))
+        .is_err());
+        assert!(parse_hex_cells(&format!(
+            "{}\n00000041011200040000000001",
+            HEX_INDEXED_HEADER
+        ))
+        .is_err());
+
+        let rgb = "00000041CD313100000001";
+        assert_eq!(
+            compare_output("hex", rgb.as_bytes(), hex.as_bytes(), 1, &[]),
+            ["color mode: expected Rgb got Indexed"]
+        );
+        assert!(compare_output("hex", hex.as_bytes(), hex.as_bytes(), 1, &[]).is_empty());
+    }
+
+    #[test]
+    fn hex_comparison_covers_scrollback_section() {
+        let cell = "00000041F0F0F000000000";
+        let with = |rows: &str| format!("{}\nSCROLLBACK 1\n{}", cell, rows);
+        assert_eq!(parse_hex_cells(&with(cell)).unwrap().len(), 1);
+        assert!(
+            compare_output("hex", with(cell).as_bytes(), with(cell).as_bytes(), 1, &[]).is_empty()
+        );
+        let other = "00000042F0F0F000000000";
+        assert_eq!(
+            compare_output("hex", with(cell).as_bytes(), with(other).as_bytes(), 1, &[]),
+            ["scrollback section differs"]
+        );
+    }
+
+    #[test]
+    fn report_comparison_masks_cells_and_checks_trailers() {
+        let report = |text: &str, cell: &str, exit: &str| {
+            format!("{}\n-- hex --\n{}\nCURSOR 0,1\nEXIT {}\n", text, cell, exit)
+        };
+        let a = "00000041F0F0F000000000";
+        let b = "00000042F0F0F000000000";
+        let expected = report("A", a, "0");
+        let mask = Mask {
+            row: 0,
+            col: 0,
+            width: 1,
+            height: 1,
+        };
+        let capture = report("B", b, "0");
+        assert_eq!(
+            compare_output("both", expected.as_bytes(), capture.as_bytes(), 1, &[]).len(),
+            1
+        );
+        assert!(
+            compare_output("both", expected.as_bytes(), capture.as_bytes(), 1, &[mask]).is_empty()
+        );
+        assert_eq!(
+            compare_output(
+                "both",
+                expected.as_bytes(),
+                report("A", a, "1").as_bytes(),
+                1,
+                &[]
+            ),
+            ["expected \"EXIT 0\" got \"EXIT 1\""]
+        );
+        // A replayed report matches whatever exit the run had
+        let replayed = report("A", a, "NONE");
+        assert!(
+            compare_output("both", expected.as_bytes(), replayed.as_bytes(), 1, &[]).is_empty()
+        );
+        assert!(
+            compare_output("both", replayed.as_bytes(), expected.as_bytes(), 1, &[]).is_empty()
+        );
+    }
+
+    #[test]
+    fn hex_comparison_reports_title_separately() {
+        let cell = "00000041F0F0F000000000";
+        let with = |title: &str| format!("{}\nTITLE {}", cell, title);
+        assert!(compare_output(
+            "hex",
+            with("a;b").as_bytes(),
+            with("a;b").as_bytes(),
+            1,
+            &[]
+        )
+        .is_empty());
+        assert_eq!(
+            compare_output("hex", with("a;b").as_bytes(), with("c").as_bytes(), 1, &[]),
+            ["title: expected Some(\"a;b\") got Some(\"c\")"]
+        );
+    }
+
+    #[test]
+    fn state_comparison_reports_character_before_colors() {
+        let expected = vec![blank_cell(); 3];
+        let mut actual = expected.clone();
+        actual[1].codepoint = 'x' as u32;
+        actual[2].fg = HexColor::Rgb(255, 0, 0);
+
+        let differences = compare_states(&expected, &actual, 3, &[]);
+        assert_eq!(differences.len(), 2);
+        assert!(differences[0].starts_with("row 0 col 1: expected ' '"));
+        assert!(differences[1].ends_with("got ' ' fg=FF0000 bg=000000 attrs=00"));
+        assert_eq!(
+            compare_states(&expected, &actual[..2], 3, &[])[0],
+            "cell count: expected 3 got 2"
+        );
+    }
+
+    #[test]
+    fn side_by_side_diff_marks_characters_and_colors() {
+        let expected = vec![blank_cell(); 4];
+        let mut actual = expected.clone();
+        actual[1].codepoint = 'x' as u32;
+        actual[2].fg = HexColor::Rgb(255, 0, 0);
+
+        assert_eq!(
+            render_diff(&expected, &actual, 2, 2, &[]),
+            "expected | actual   |\n         |  x       |  !\n         |          | *\n"
+        );
+    }
+
+    #[test]
+    fn cell_diff_report_and_overview() {
+        let expected = vec![blank_cell(); 4];
+        let mut actual = expected.clone();
+        actual[3].codepoint = 'x' as u32;
+
+        let report = format_cell_diff(&expected, &actual, 2, 2, &[], 10);
+        assert!(report.starts_with("1 of 4 cells differ\nrow 1 col 1:\n"));
+        assert!(report.ends_with("\n..\n.#\n"));
+
+        let capped = format_cell_diff(&expected, &vec![actual[3].clone(); 4], 2, 2, &[], 1);
+        assert!(capped.contains("... and 3 more"));
+
+        let mismatch = format_cell_diff(&expected, &actual, 3, 2, &[], 10);
+        assert_eq!(
+            mismatch,
+            "Dimension mismatch: expected file has 4 cells, screen is 3 rows x 2 cols (6 cells)\n"
+        );
+    }
+
+    #[test]
+    fn ignored_fields_are_reset() {
+        let mut styled = blank_cell();
+        styled.codepoint = 'x' as u32;
+        styled.fg = HexColor::Rgb(255, 0, 0);
+        styled.bg = HexColor::Indexed(4);
+        styled.attrs = 0x01;
+
+        let plain = styled.without(IgnoreFields {
+            colors: true,
+            attrs: true,
+        });
+        assert_eq!(plain.codepoint, 'x' as u32);
+        assert_eq!(
+            (plain.fg, plain.bg, plain.attrs),
+            (HexColor::Default, HexColor::Default, 0)
+        );
+
+        let colors_only = styled.without(IgnoreFields {
+            colors: true,
+            attrs: false,
+        });
+        assert_eq!(colors_only.attrs, 0x01);
+        assert_eq!(styled.without(IgnoreFields::default()), styled);
+    }
+
+    #[test]
+    fn masks_parse_and_clamp() {
+        let mask = parse_mask("1,2,3,4").unwrap();
+        assert_eq!(
+            mask,
+            Mask {
+                row: 1,
+                col: 2,
+                width: 3,
+                height: 4
+            }
+        );
+        assert!(parse_mask("1,2,3").is_err());
+        assert!(parse_mask("a,b,c,d").is_err());
+
+        // A mask running off the screen edge still covers the visible part
+        let edge = parse_mask("0,78,65535,65535").unwrap();
+        assert!(edge.contains(24, 79));
+        assert!(!edge.contains(0, 77));
+    }
+
+    #[test]
+    fn masked_cells_always_match() {
+        let expected = vec![blank_cell(); 6];
+        let mut actual = expected.clone();
+        for cell in &mut actual[..4] {
+            cell.codepoint = 'x' as u32;
+        }
+        let to_hex = |cells: &[HexCell]| {
+            cells
+                .iter()
+                .map(|c| format!("{:08X}F0F0F0000000{:02X}", c.codepoint, c.attrs))
+                .collect::<String>()
+        };
+        let (e, a) = (to_hex(&expected), to_hex(&actual));
+
+        // Overlapping masks covering columns 0-1 of both rows
+        let overlapping = [
+            parse_mask("0,0,2,1").unwrap(),
+            parse_mask("0,1,1,2").unwrap(),
+        ];
+        let diffs = compare_output("hex", e.as_bytes(), a.as_bytes(), 3, &overlapping);
+        assert_eq!(
+            diffs,
+            [
+                "row 0 col 2: expected ' ' got 'x'",
+                "row 1 col 0: expected ' ' got 'x'"
+            ]
+        );
+
+        let whole_screen = [parse_mask("0,0,100,100").unwrap()];
+        assert!(compare_output("hex", e.as_bytes(), a.as_bytes(), 3, &whole_screen).is_empty());
+        let report = format_cell_diff(&expected, &actual, 2, 3, &whole_screen, 10);
+        assert_eq!(report, "0 of 6 cells differ\n");
+
+        let clock = [parse_mask("0,5,5,1").unwrap()];
+        assert!(compare_output("text", b"time 12:00\n", b"time 13:37\n", 10, &clock).is_empty());
+
+        // Masks count screen columns, which a wide character takes two of
+        let digits = [parse_mask("0,3,2,1").unwrap()];
+        let (screen, expect) = ("你X12\n".as_bytes(), "你X99\n".as_bytes());
+        assert!(compare_output("text", expect, screen, 5, &digits).is_empty());
+        let shifted = [parse_mask("0,2,2,1").unwrap()];
+        assert_eq!(compare_output("text", expect, screen, 5, &shifted).len(), 1);
+        let combining = "e\u{301}\u{4f60}ab\n".as_bytes();
+        let masked_b = [parse_mask("0,4,1,1").unwrap()];
+        assert!(compare_output(
+            "text",
+            combining,
+            "e\u{301}\u{4f60}ax\n".as_bytes(),
+            5,
+            &masked_b
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn wide_cells_are_described_and_kept_when_ignoring_attrs() {
+        let cells = parse_hex_cells("00004F60F0F0F00000008100000000F0F0F000000080").unwrap();
+        assert_eq!(
+            cells[0].describe(),
+            "'你' (wide) fg=F0F0F0 bg=000000 attrs=81"
+        );
+        assert_eq!(cells[1].display(), "(wide continuation)");
+        assert!(cells[1].is_wide_continuation() && !cells[1].is_masked());
+        let ignore = IgnoreFields {
+            attrs: true,
+            ..Default::default()
+        };
+        assert_eq!(cells[0].without(ignore).attrs, HEX_ATTR_WIDE);
+
+        // Every style on a wide character is no masked cell
+        let styled = parse_hex_cells("00004F60F0F0F0000000FF00000000F0F0F000000080").unwrap();
+        assert!(!styled[0].is_masked());
+        let ansi = format_ansi_cells(&styled, 1, 2);
+        assert!(ansi.contains('你'), "{:?}", ansi);
+    }
+
+    #[test]
+    fn ansi_rendering_redraws_the_same_cells() {
+        use crate::attrs::ExtraAttrs;
+        use crate::format::{format_hex_state, HexVersion, RenderOptions};
+
+        // Styles, a wide character, a combining mark and a full last row
+        let data = "\x1b[1;31mred\x1b[0m \x1b[2;4;48;5;21mdim\x1b[0m\r\n\
+                    \x1b[7;38;2;1;2;3m\u{4e2d}e\u{301}\x1b[9mx\x1b[0m\r\nabcdefghij";
+        let hex = |data: &[u8], color_mode: ColorMode, hex_version: HexVersion| {
+            let mut parser = vt100::Parser::new(3, 10, 0);
+            parser.process(data);
+            let mut extra = ExtraAttrs::new(3, 10);
+            extra.process(data);
+            let options = RenderOptions {
+                extra_attrs: Some(extra.screen().clone()),
+                color_mode,
+                hex_version,
+                ..Default::default()
+            };
+            format_hex_state(parser.screen(), 3, 10, &options)
+        };
+
+        for color_mode in [ColorMode::Rgb, ColorMode::Indexed] {
+            for version in [HexVersion::V1, HexVersion::V2] {
+                let original = hex(data.as_bytes(), color_mode, version);
+                let cells = parse_hex_cells(&original).unwrap();
+                let ansi = format_ansi_cells(&cells, 3, 10);
+                assert_eq!(hex(ansi.as_bytes(), color_mode, version), original);
+            }
+        }
+    }
+}
@@ -0,0 +1,560 @@
+//! Byte-stream normalization applied to captured output before emulation
+//!
+//! Removes platform-specific noise (window titles, ConPTY erase and reset
+//! quirks) so the same program renders identically everywhere.
+
+/// How newlines in child input are rewritten before being sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewlineMode {
+    /// Every LF becomes CRLF (what Windows ConPTY programs expect)
+    Crlf,
+    /// CRLF and lone CR become LF
+    Lf,
+    /// CRLF and lone LF become CR (the byte a real Enter key sends)
+    Cr,
+    /// Bytes are sent unchanged
+    Raw,
+}
+
+impl NewlineMode {
+    /// CRLF on Windows, raw elsewhere (matching what each PTY expects)
+    pub fn platform_default() -> Self {
+        if cfg!(windows) {
+            NewlineMode::Crlf
+        } else {
+            NewlineMode::Raw
+        }
+    }
+}
+
+/// Parse a newline mode name: "crlf", "lf", "cr" or "raw"
+pub fn parse_newline_mode(value: &str) -> Result<NewlineMode, String> {
+    match value {
+        "crlf" => Ok(NewlineMode::Crlf),
+        "lf" => Ok(NewlineMode::Lf),
+        "cr" => Ok(NewlineMode::Cr),
+        "raw" => Ok(NewlineMode::Raw),
+        _ => Err(format!("expected crlf, lf, cr or raw, got {:?}", value)),
+    }
+}
+
+/// Rewrite the newlines in `data` according to `mode`
+/// This works on bytes, so a 0x0A or 0x0D inside an escape sequence is
+/// rewritten too; use `NewlineMode::Raw` for binary input
+pub fn convert_newlines(data: &[u8], mode: NewlineMode) -> Vec<u8> {
+    let newline: &[u8] = match mode {
+        NewlineMode::Crlf => return normalize_line_endings(data),
+        NewlineMode::Raw => return data.to_vec(),
+        NewlineMode::Lf => b"\n",
+        NewlineMode::Cr => b"\r",
+    };
+    let mut result = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'\r' if data.get(i + 1) == Some(&b'\n') => {
+                result.extend_from_slice(newline);
+                i += 2;
+            }
+            b'\r' | b'\n' => {
+                result.extend_from_slice(newline);
+                i += 1;
+            }
+            byte => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+/// Normalize line endings: ensure all lines end with \r\n (CRLF) for Windows ConPTY
+/// Used for child input in `NewlineMode::Crlf`, the default on Windows
+pub fn normalize_line_endings(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'\r' && i + 1 < data.len() && data[i + 1] == b'\n' {
+            // Already CRLF, keep as is
+            result.push(b'\r');
+            result.push(b'\n');
+            i += 2;
+        } else if data[i] == b'\n' {
+            // LF only, convert to CRLF
+            result.push(b'\r');
+            result.push(b'\n');
+            i += 1;
+        } else if data[i] == b'\r' {
+            // CR only (rare), keep as is
+            result.push(b'\r');
+            i += 1;
+        } else {
+            // Normal character
+            result.push(data[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
+/// Filter out OSC (Operating System Command) sequences
+/// OSC sequences start with ESC ] and end with BEL (0x07) or ESC \
+/// These are often used for window titles (OSC 0/1/2), hyperlinks (OSC 8) and
+/// working-directory reports (OSC 7), and can differ between platforms
+/// DCS (ESC P), SOS (ESC X), PM (ESC ^) and APC (ESC _) strings, which end
+/// with ESC \ only, are removed the same way; nothing else is touched
+pub fn filter_osc_sequences(data: &[u8]) -> Vec<u8> {
+    let mut filter = OscFilter::new();
+    let mut result = filter.feed(data);
+    result.extend(filter.finish());
+    result
+}
+
+/// Position of an `OscFilter` within the byte stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OscState {
+    /// Passing bytes through
+    Normal,
+    /// Saw ESC outside a string sequence; waiting for the introducer
+    Escape,
+    /// Inside a string sequence, dropping bytes
+    /// `bel_ends` is true for OSC, which may also be terminated by BEL
+    InString { bel_ends: bool },
+    /// Saw ESC inside a string sequence; waiting to see if '\' follows
+    InStringEscape { bel_ends: bool },
+}
+
+/// Streaming filter for OSC and other string sequences whose state carries
+/// across `feed` calls, so a sequence split between chunks (even between
+/// ESC and ']') is still removed
+///
+/// A string that is never terminated (e.g. the program was killed mid
+/// sequence) is not swallowed: `finish` drops only its introducer and
+/// re-emits everything after it, so later output still reaches the screen
+pub struct OscFilter {
+    state: OscState,
+    /// Bytes of the current string sequence after its introducer
+    pending: Vec<u8>,
+    /// Pass OSC sequences through instead of removing them
+    keep_osc: bool,
+    /// Pass every byte through, only watching for titles
+    pass_all: bool,
+    /// Last window title set with OSC 0, 1 or 2
+    title: Option<String>,
+}
+
+impl OscFilter {
+    pub fn new() -> Self {
+        Self {
+            state: OscState::Normal,
+            pending: Vec::new(),
+            keep_osc: false,
+            pass_all: false,
+            title: None,
+        }
+    }
+
+    /// Filter that passes OSC sequences through unchanged (DCS, SOS, PM and
+    /// APC strings are still removed)
+    pub fn keeping_osc() -> Self {
+        Self {
+            keep_osc: true,
+            ..Self::new()
+        }
+    }
+
+    /// Filter that removes nothing but still records the window title
+    pub fn passthrough() -> Self {
+        Self {
+            pass_all: true,
+            ..Self::new()
+        }
+    }
+
+    /// Last window title set with OSC 0, 1 or 2, seen in any fed chunk; the
+    /// icon name OSC 1 sets is folded into the title, so whichever of them
+    /// came last is reported
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Filter the next chunk of data, returning the bytes to keep
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        let filtered = self.filter(data);
+        if self.pass_all {
+            data.to_vec()
+        } else {
+            filtered
+        }
+    }
+
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (OscState::Normal, 0x1b) => OscState::Escape,
+                (OscState::Normal, _) => {
+                    result.push(byte);
+                    OscState::Normal
+                }
+                // ESC ] starts an OSC; ESC P / X / ^ / _ start DCS, SOS, PM, APC
+                (OscState::Escape, b']') => OscState::InString { bel_ends: true },
+                (OscState::Escape, b'P' | b'X' | b'^' | b'_') => {
+                    OscState::InString { bel_ends: false }
+                }
+                (OscState::Escape, 0x1b) => {
+                    result.push(0x1b);
+                    OscState::Escape
+                }
+                (OscState::Escape, _) => {
+                    result.push(0x1b);
+                    result.push(byte);
+                    OscState::Normal
+                }
+                // ESC \ (ST) terminates any string sequence, BEL only an OSC
+                (OscState::InStringEscape { bel_ends }, b'\\') => {
+                    // Drop the ESC of the terminator, which was kept as body
+                    self.pending.pop();
+                    if bel_ends {
+                        self.end_osc(&mut result, b"\x1b\\");
+                    }
+                    self.pending.clear();
+                    OscState::Normal
+                }
+                (
+                    OscState::InString { bel_ends: true }
+                    | OscState::InStringEscape { bel_ends: true },
+                    0x07,
+                ) => {
+                    self.end_osc(&mut result, b"\x07");
+                    self.pending.clear();
+                    OscState::Normal
+                }
+                (OscState::InString { bel_ends } | OscState::InStringEscape { bel_ends }, 0x1b) => {
+                    self.pending.push(byte);
+                    OscState::InStringEscape { bel_ends }
+                }
+                (OscState::InString { bel_ends } | OscState::InStringEscape { bel_ends }, _) => {
+                    self.pending.push(byte);
+                    OscState::InString { bel_ends }
+                }
+            };
+        }
+
+        result
+    }
+
+    /// Handle a complete OSC whose body is in `pending`: record a title and,
+    /// when keeping OSC, re-emit the sequence with its terminator
+    fn end_osc(&mut self, result: &mut Vec<u8>, terminator: &[u8]) {
+        // The title is everything after the first ';', so it may contain more
+        if let Some(title) = [&b"0;"[..], b"1;", b"2;"]
+            .iter()
+            .find_map(|prefix| self.pending.strip_prefix(*prefix))
+        {
+            self.title = Some(String::from_utf8_lossy(title).into_owned());
+        }
+        if self.keep_osc {
+            result.extend_from_slice(b"\x1b]");
+            result.extend_from_slice(&self.pending);
+            result.extend_from_slice(terminator);
+        }
+    }
+
+    /// Flush at end of data: a pending lone ESC is kept as a normal character,
+    /// and the body of an unterminated string sequence is re-emitted without
+    /// its introducer
+    pub fn finish(&mut self) -> Vec<u8> {
+        let pending = match self.state {
+            OscState::Normal => Vec::new(),
+            OscState::Escape => vec![0x1b],
+            OscState::InString { .. } | OscState::InStringEscape { .. } => {
+                std::mem::take(&mut self.pending)
+            }
+        };
+        self.state = OscState::Normal;
+        if self.pass_all {
+            // Every byte was already passed through by `feed`
+            Vec::new()
+        } else {
+            pending
+        }
+    }
+}
+
+impl Default for OscFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Normalize erase sequences to use default colors
+/// Windows ConPTY's ESC[K (erase to end of line) uses current colors
+/// Linux PTY doesn't send ESC[K, just ends the line
+/// Insert reset before ESC[K to ensure spaces are written with default colors
+pub fn normalize_erase_sequences(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        // Look for ESC[K (erase to end of line)
+        if i + 2 < data.len() && data[i] == 0x1b && data[i + 1] == b'[' && data[i + 2] == b'K' {
+            // Insert explicit reset before the erase
+            // This ensures spaces are written with default colors on Windows ConPTY
+            result.extend_from_slice(b"\x1b[0;39;49m");
+            // Then add the erase sequence
+            result.extend_from_slice(&data[i..i + 3]);
+            i += 3;
+        } else {
+            result.push(data[i]);
+            i += 1;
+        }
+    }
+
+    result
+}
+
+/// Normalize ANSI reset sequence: ESC [m or ESC [0m should always reset to default
+/// On Windows ConPTY, sometimes the foreground color persists after reset
+/// This function ensures reset sequences are followed by explicit default color codes
+pub fn normalize_reset_sequences(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    
+    while i < data.len() {
+        // Check for ESC [ ... m sequence
+        if i + 2 < data.len() && data[i] == 0x1b && data[i + 1] == b'[' {
+            // Find the end of the CSI sequence (ends with 'm')
+            let start = i;
+            i += 2;
+            let mut params = Vec::new();
+            let mut current_num = String::new();
+            
+            while i < data.len() {
+                if data[i] == b';' {
+                    if !current_num.is_empty() {
+                        params.push(current_num.clone());
+                        current_num.clear();
+                    }
+                    i += 1;
+                } else if data[i] == b'm' {
+                    if !current_num.is_empty() {
+                        params.push(current_num.clone());
+                    }
+                    
+                    // Check if this is a reset sequence (no params or param 0)
+                    let is_reset = params.is_empty() || (params.len() == 1 && params[0] == "0");
+                    
+                    if is_reset {
+                        // Replace with explicit reset + set default colors
+                        // ESC[0;39;49m = reset + default foreground + default background
+                        result.extend_from_slice(b"\x1b[0;39;49m");
+                    } else {
+                        // Keep original sequence
+                        result.extend_from_slice(&data[start..=i]);
+                    }
+                    
+                    i += 1;
+                    break;
+                } else if data[i].is_ascii_digit() {
+                    current_num.push(data[i] as char);
+                    i += 1;
+                } else {
+                    // Not a valid SGR sequence, keep original
+                    result.extend_from_slice(&data[start..=i]);
+                    i += 1;
+                    break;
+                }
+            }
+        } else {
+            result.push(data[i]);
+            i += 1;
+        }
+    }
+    
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn osc_filter_removes_sequences() {
+        assert_eq!(filter_osc_sequences(b"a\x1b]0;title\x07b"), b"ab");
+        assert_eq!(filter_osc_sequences(b"a\x1b]0;title\x1b\\b"), b"ab");
+        assert_eq!(filter_osc_sequences(b"\x1b[31mred\x1b"), b"\x1b[31mred\x1b");
+    }
+
+    #[test]
+    fn osc_filter_removes_dcs_apc_pm_sos_strings() {
+        assert_eq!(
+            filter_osc_sequences(b"before\x1bP1$r0m\x1b\\after"),
+            b"beforeafter"
+        );
+        assert_eq!(
+            filter_osc_sequences(b"a\x1b_apc\x1b\\b\x1b^pm\x1b\\c"),
+            b"abc"
+        );
+        assert_eq!(filter_osc_sequences(b"a\x1bXsos\x1b\\b"), b"ab");
+        // BEL does not terminate a DCS string
+        assert_eq!(filter_osc_sequences(b"a\x1bPx\x07y\x1b\\b"), b"ab");
+    }
+
+    #[test]
+    fn osc_filter_handles_split_at_esc_bracket() {
+        let mut filter = OscFilter::new();
+        let mut out = filter.feed(b"before\x1b");
+        out.extend(filter.feed(b"]0;title\x07after"));
+        out.extend(filter.finish());
+        assert_eq!(out, b"beforeafter");
+    }
+
+    #[test]
+    fn osc_filter_handles_split_terminator() {
+        let mut filter = OscFilter::new();
+        let mut out = filter.feed(b"x\x1b]2;t\x1b");
+        out.extend(filter.feed(b"\\y"));
+        out.extend(filter.finish());
+        assert_eq!(out, b"xy");
+    }
+
+    #[test]
+    fn osc_filter_keeps_split_non_osc_escape() {
+        let mut filter = OscFilter::new();
+        let mut out = filter.feed(b"a\x1b");
+        out.extend(filter.feed(b"[1mb"));
+        out.extend(filter.finish());
+        assert_eq!(out, b"a\x1b[1mb");
+    }
+
+    #[test]
+    fn osc_filter_reemits_unterminated_string_without_introducer() {
+        assert_eq!(
+            filter_osc_sequences(b"a\x1b]0;never ends\r\nmore"),
+            b"a0;never ends\r\nmore"
+        );
+        // ESC as the very last byte, inside and outside a string
+        assert_eq!(filter_osc_sequences(b"a\x1b]0;t\x1b"), b"a0;t\x1b");
+        assert_eq!(filter_osc_sequences(b"a\x1b"), b"a\x1b");
+    }
+
+    #[test]
+    fn osc_filter_handles_terminator_at_chunk_end() {
+        let mut filter = OscFilter::new();
+        let mut out = filter.feed(b"x\x1b]0;t\x07");
+        out.extend(filter.feed(b"y"));
+        out.extend(filter.finish());
+        assert_eq!(out, b"xy");
+    }
+
+    #[test]
+    fn osc_filter_removes_back_to_back_sequences() {
+        assert_eq!(
+            filter_osc_sequences(b"\x1b]0;a\x07\x1b]2;b\x1b\\\x1b]0;c\x07text"),
+            b"text"
+        );
+    }
+
+    #[test]
+    fn line_endings_become_crlf_once() {
+        assert_eq!(
+            normalize_line_endings(b"a\nb\r\nc\rd\n"),
+            b"a\r\nb\r\nc\rd\r\n"
+        );
+        assert_eq!(normalize_line_endings(b""), b"");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn input_is_sent_unchanged_by_default_off_windows() {
+        let data = b"a\nb\r\nc\rd";
+        assert_eq!(NewlineMode::platform_default(), NewlineMode::Raw);
+        assert_eq!(
+            convert_newlines(data, NewlineMode::platform_default()),
+            data
+        );
+    }
+
+    #[test]
+    fn line_endings_in_mixed_input() {
+        // Bare CRs (Enter keys) stay bare and never pair with a later LF
+        let data = b"\r\r\n\n\r\x1b[A\n\r";
+        assert_eq!(normalize_line_endings(data), b"\r\r\n\r\n\r\x1b[A\r\n\r");
+        assert_eq!(
+            convert_newlines(data, NewlineMode::Crlf),
+            normalize_line_endings(data)
+        );
+        assert_eq!(
+            convert_newlines(data, NewlineMode::Lf),
+            b"\n\n\n\n\x1b[A\n\n"
+        );
+        assert_eq!(
+            convert_newlines(data, NewlineMode::Cr),
+            b"\r\r\r\r\x1b[A\r\r"
+        );
+        assert_eq!(convert_newlines(data, NewlineMode::Raw), data);
+    }
+
+    #[test]
+    fn newline_modes_rewrite_every_line_ending() {
+        let data = b"a\nb\r\nc\rd";
+        assert_eq!(convert_newlines(data, NewlineMode::Crlf), b"a\r\nb\r\nc\rd");
+        assert_eq!(convert_newlines(data, NewlineMode::Lf), b"a\nb\nc\nd");
+        assert_eq!(convert_newlines(data, NewlineMode::Cr), b"a\rb\rc\rd");
+        assert_eq!(convert_newlines(data, NewlineMode::Raw), data);
+        assert_eq!(parse_newline_mode("cr"), Ok(NewlineMode::Cr));
+        assert!(parse_newline_mode("CRLF").is_err());
+    }
+
+    #[test]
+    fn newline_modes_rewrite_line_feeds_next_to_escape_sequences() {
+        // A 0x0A in input (e.g. Ctrl-J after an arrow key) cannot be told
+        // apart from a newline, so every mode but raw rewrites it
+        let data = b"\x1b[A\x0a";
+        assert_eq!(convert_newlines(data, NewlineMode::Cr), b"\x1b[A\r");
+        assert_eq!(convert_newlines(data, NewlineMode::Raw), data);
+    }
+
+    #[test]
+    fn osc_filter_records_last_title() {
+        let mut filter = OscFilter::new();
+        let mut out = filter.feed(b"\x1b]0;first\x07a\x1b]2;caf\xc3");
+        out.extend(filter.feed(b"\xa9; menu \xe2\x86\x92 1\x1b\\b\x1b]7;file:///tmp\x07"));
+        out.extend(filter.finish());
+        assert_eq!(out, b"ab");
+        assert_eq!(filter.title(), Some("caf\u{e9}; menu \u{2192} 1"));
+    }
+
+    #[test]
+    fn osc_filter_folds_icon_name_into_title() {
+        let mut filter = OscFilter::new();
+        assert_eq!(filter.feed(b"\x1b]2;window\x07\x1b]1;icon\x1b\\a"), b"a");
+        assert_eq!(filter.title(), Some("icon"));
+        filter.feed(b"\x1b]0;both\x07");
+        assert_eq!(filter.title(), Some("both"));
+        filter.feed(b"\x1b]10;?\x07\x1b]11;rgb:0/0/0\x07");
+        assert_eq!(filter.title(), Some("both"));
+    }
+
+    #[test]
+    fn osc_filter_can_keep_osc_but_not_other_strings() {
+        let mut filter = OscFilter::keeping_osc();
+        let mut out = filter.feed(b"\x1b]0;t\x07a\x1b]8;;x\x1b\\b\x1bPq\x1b\\c");
+        out.extend(filter.finish());
+        assert_eq!(out, b"\x1b]0;t\x07a\x1b]8;;x\x1b\\bc");
+        assert_eq!(filter.title(), Some("t"));
+    }
+
+    #[test]
+    fn osc_filter_passthrough_keeps_every_byte_but_sees_title() {
+        let data = b"\x1b]2;t\x1b\\a\x1bPq\x1b\\\x1b]0;unterminated";
+        let mut filter = OscFilter::passthrough();
+        let mut out = filter.feed(data);
+        out.extend(filter.finish());
+        assert_eq!(out, data);
+        assert_eq!(filter.title(), Some("t"));
+    }
+}
@@ -0,0 +1,1672 @@
+//! Rendering a terminal screen as hex, text or JSON
+
+use crate::attrs::{extra_attr_bits, ATTR_BLINK, ATTR_DIM, ATTR_HIDDEN, ATTR_STRIKETHROUGH};
+use crate::compare::{is_masked, Mask};
+use crate::palette::Palette;
+use anyhow::Result;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Colors used for cells whose color is Color::Default
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultColors {
+    pub fg: (u8, u8, u8),
+    pub bg: (u8, u8, u8),
+}
+
+impl Default for DefaultColors {
+    fn default() -> Self {
+        Self {
+            fg: (240, 240, 240),
+            bg: (0, 0, 0),
+        }
+    }
+}
+
+/// Options controlling how screen cells are rendered
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    pub defaults: DefaultColors,
+    /// RGB of ANSI colors 0-15
+    pub palette: Palette,
+    /// Cells rendered as the masked sentinel instead of their contents
+    pub masks: Vec<Mask>,
+    /// Character drawn at the cursor position in text output
+    pub cursor_marker: Option<char>,
+    /// Extra attributes of the screen's cells (`CaptureResult::extra_attrs`);
+    /// without it, dim, blink, strikethrough and hidden are never reported
+    pub extra_attrs: Option<vt100::Screen>,
+    /// Extra attribute bits of the scrollback's cells
+    /// (`CaptureResult::scrollback_extra_attrs`); without them, scrollback
+    /// cells are written without dim, blink, strikethrough and hidden
+    pub scrollback_extra_attrs: Option<Vec<Vec<u8>>>,
+    /// Hex cell encoding to write
+    pub hex_version: HexVersion,
+    /// Whether colors are resolved to RGB or kept as the program set them
+    pub color_mode: ColorMode,
+}
+
+/// How cell colors are written in hex and JSON output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Resolve every color to RGB (through the palette and default colors)
+    #[default]
+    Rgb,
+    /// Keep the kind of color the program set: the default color, a palette
+    /// index, or literal RGB; output does not depend on the palette
+    Indexed,
+}
+
+/// Revision of the hex cell encoding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HexVersion {
+    /// The encoding goldens were first written in: only the first codepoint
+    /// of each cell, so combining marks, variation selectors and joiners are
+    /// dropped, no `HEX_ATTR_WIDE` bit, and the column a wide character
+    /// covers written as a plain cell (a space)
+    #[default]
+    V1,
+    /// Every codepoint of the cell, with a `+NN` count prefix for cells
+    /// holding more than one, and `HEX_ATTR_WIDE` on both columns of wide
+    /// characters, the second with `WIDE_CONTINUATION_CODEPOINT`
+    V2,
+}
+
+/// A screen-only output format (no cursor, scrollback or title trailers)
+pub trait ScreenFormatter {
+    /// Render the visible `rows` x `cols` of `screen`
+    fn format(
+        &self,
+        screen: &vt100::Screen,
+        rows: u16,
+        cols: u16,
+        options: &RenderOptions,
+    ) -> String;
+
+    /// File extension for saved output, e.g. "hex"
+    fn extension(&self) -> &'static str;
+}
+
+/// The "hex" format (`format_hex_state`)
+pub struct HexFormatter;
+
+/// The "text" format (`format_text_state`)
+pub struct TextFormatter;
+
+/// The "html" format (`format_html_state`)
+pub struct HtmlFormatter;
+
+/// The "hash" format (`format_hash_state`)
+pub struct HashFormatter;
+
+/// The "svg" format (`format_svg_state`) with cells of this pixel size
+pub struct SvgFormatter {
+    pub cell_size: (u32, u32),
+}
+
+impl ScreenFormatter for HexFormatter {
+    fn format(
+        &self,
+        screen: &vt100::Screen,
+        rows: u16,
+        cols: u16,
+        options: &RenderOptions,
+    ) -> String {
+        format_hex_state(screen, rows, cols, options)
+    }
+
+    fn extension(&self) -> &'static str {
+        "hex"
+    }
+}
+
+impl ScreenFormatter for TextFormatter {
+    fn format(
+        &self,
+        screen: &vt100::Screen,
+        rows: u16,
+        cols: u16,
+        options: &RenderOptions,
+    ) -> String {
+        format_text_state(screen, rows, cols, options)
+    }
+
+    fn extension(&self) -> &'static str {
+        "txt"
+    }
+}
+
+impl ScreenFormatter for HtmlFormatter {
+    fn format(
+        &self,
+        screen: &vt100::Screen,
+        rows: u16,
+        cols: u16,
+        options: &RenderOptions,
+    ) -> String {
+        format_html_state(screen, rows, cols, options)
+    }
+
+    fn extension(&self) -> &'static str {
+        "html"
+    }
+}
+
+impl ScreenFormatter for HashFormatter {
+    fn format(
+        &self,
+        screen: &vt100::Screen,
+        rows: u16,
+        cols: u16,
+        options: &RenderOptions,
+    ) -> String {
+        format_hash_state(screen, rows, cols, options)
+    }
+
+    fn extension(&self) -> &'static str {
+        "sha256"
+    }
+}
+
+impl ScreenFormatter for SvgFormatter {
+    fn format(
+        &self,
+        screen: &vt100::Screen,
+        rows: u16,
+        cols: u16,
+        options: &RenderOptions,
+    ) -> String {
+        format_svg_state(screen, rows, cols, options, self.cell_size)
+    }
+
+    fn extension(&self) -> &'static str {
+        "svg"
+    }
+}
+
+/// Formatter for an output format name, if it is a screen-only format
+pub fn screen_formatter(name: &str, cell_size: (u32, u32)) -> Option<Box<dyn ScreenFormatter>> {
+    match name {
+        "hex" => Some(Box::new(HexFormatter)),
+        "text" => Some(Box::new(TextFormatter)),
+        "html" => Some(Box::new(HtmlFormatter)),
+        "svg" => Some(Box::new(SvgFormatter { cell_size })),
+        "hash" => Some(Box::new(HashFormatter)),
+        _ => None,
+    }
+}
+
+/// Parse an RRGGBB hex string (optionally prefixed with '#') into RGB
+pub fn parse_rgb(value: &str) -> Result<(u8, u8, u8), String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("expected RRGGBB hex color, got {:?}", value));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+/// Version of the JSON output schema, bumped on incompatible changes
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// Length of one cell in the hex format
+pub const HEX_CELL_LEN: usize = 22;
+
+/// Hex cell emitted for masked cells (attrs FF never occurs in real output)
+pub const MASKED_HEX_CELL: &str = "00000000000000000000FF";
+
+/// Header line starting hex output in `ColorMode::Indexed`
+pub const HEX_INDEXED_HEADER: &str = "HEX 2 indexed";
+
+/// Length of one cell in the indexed-color hex format
+pub const HEX_INDEXED_CELL_LEN: usize = 26;
+
+/// Masked cell in the indexed-color hex format
+pub const MASKED_HEX_INDEXED_CELL: &str = "000000000000000000000000FF";
+
+/// Color kind tags of the indexed-color hex format
+pub const HEX_COLOR_DEFAULT: u8 = 0x00;
+pub const HEX_COLOR_INDEXED: u8 = 0x01;
+pub const HEX_COLOR_RGB: u8 = 0x02;
+
+/// Codepoint emitted in `HexVersion::V2` for the column covered by the
+/// right half of a wide character, so columns stay aligned (empty cells are
+/// 00000020)
+pub const WIDE_CONTINUATION_CODEPOINT: u32 = 0;
+
+/// Hex attrs bit set (in `HexVersion::V2`) on both columns of a wide
+/// character: with a codepoint it marks the character as wide, with
+/// `WIDE_CONTINUATION_CODEPOINT` it marks the column its right half covers
+/// (whose attrs byte is just this bit, the styles staying with the
+/// character); the attrs byte has no other bit left
+pub const HEX_ATTR_WIDE: u8 = 0x80;
+
+/// Length of the hex cursor trailer written after the last cell
+pub const HEX_CURSOR_LEN: usize = 10;
+
+/// Header line starting the scrollback section of the hex format
+pub const HEX_SCROLLBACK_HEADER: &str = "SCROLLBACK";
+
+/// Start of the line separating scrollback from the screen in text output
+pub const TEXT_SCROLLBACK_MARKER: &str = "-- end of scrollback";
+
+/// Keyword starting the window title trailer in hex and text output
+pub const TITLE_TRAILER: &str = "TITLE";
+
+/// Line separating the text grid from the hex dump in the "both" report
+pub const REPORT_HEX_SEPARATOR: &str = "-- hex --";
+
+/// Keyword starting the exit status line of the "both" report
+pub const REPORT_EXIT_TRAILER: &str = "EXIT";
+
+/// Exit written in the "both" report of a replay, which runs no program;
+/// comparisons accept it in place of any exit
+pub const REPORT_NO_EXIT: &str = "NONE";
+
+/// Full contents of a cell, or a space for an empty cell
+pub fn cell_contents(cell: &vt100::Cell) -> String {
+    let contents = cell.contents();
+    if contents.is_empty() {
+        " ".to_string()
+    } else {
+        contents
+    }
+}
+
+/// Set once the warning about cells outside the screen has been printed
+static OUTSIDE_SCREEN_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Cell at `row`, `col`, or a blank one if that is outside the screen (the
+/// requested grid is larger than the parser's); warns on stderr the first
+/// time this happens in the process
+fn screen_cell(screen: &vt100::Screen, row: u16, col: u16) -> Cow<'_, vt100::Cell> {
+    match screen.cell(row, col) {
+        Some(cell) => Cow::Borrowed(cell),
+        None => {
+            if !OUTSIDE_SCREEN_WARNED.swap(true, Ordering::Relaxed) {
+                let (screen_rows, screen_cols) = screen.size();
+                eprintln!(
+                    "Warning: cell ({}, {}) is outside the {} rows x {} cols screen; rendering blanks there",
+                    row, col, screen_rows, screen_cols
+                );
+            }
+            Cow::Owned(vt100::Cell::default())
+        }
+    }
+}
+
+/// Contents of a cell as text output shows it: like `cell_contents`, except
+/// that the column covered by the right half of a wide character is empty
+fn cell_text(cell: &vt100::Cell) -> String {
+    if cell.is_wide_continuation() {
+        String::new()
+    } else {
+        cell_contents(cell)
+    }
+}
+
+/// Format terminal state as hex
+/// Format: 22 chars per cell = 8 (codepoint) + 6 (fg RGB) + 6 (bg RGB) + 2 (attrs)
+/// In `HexVersion::V2`, a cell with N > 1 codepoints is `+NN` followed by N
+/// 8-char codepoints instead of the single codepoint (V1 keeps only the
+/// first) and both columns of a wide character have the `HEX_ATTR_WIDE`
+/// bit, the second with codepoint 00000000 (V1 writes it as a space); a
+/// wide character reaching past the last column wraps, leaving that column
+/// blank
+///
+/// In `ColorMode::Indexed` the cells follow a `HEX_INDEXED_HEADER` line and
+/// each color is 8 chars, a kind tag then its value, making cells 26 chars:
+///   00000000 the default color
+///   010000II palette index II
+///   02RRGGBB literal RGB
+pub fn format_hex_state(
+    screen: &vt100::Screen,
+    rows: u16,
+    cols: u16,
+    options: &RenderOptions,
+) -> String {
+    // Built as bytes: pushing ASCII to a String goes through char encoding,
+    // which costs a good share of the time on large screens
+    let mut out = Vec::with_capacity(rows as usize * cols as usize * HEX_INDEXED_CELL_LEN);
+    let masked = match options.color_mode {
+        ColorMode::Rgb => MASKED_HEX_CELL,
+        ColorMode::Indexed => {
+            out.extend_from_slice(HEX_INDEXED_HEADER.as_bytes());
+            out.push(b'\n');
+            MASKED_HEX_INDEXED_CELL
+        }
+    };
+
+    for row in 0..rows {
+        for col in 0..cols {
+            if is_masked(&options.masks, row, col) {
+                out.extend_from_slice(masked.as_bytes());
+                continue;
+            }
+
+            let extra = extra_bits(options, row, col);
+            push_hex_cell(&mut out, &screen_cell(screen, row, col), options, extra);
+        }
+    }
+
+    hex_string(out)
+}
+
+/// `out` as a String; the hex cell encoding is all ASCII
+fn hex_string(out: Vec<u8>) -> String {
+    String::from_utf8(out).expect("hex output is ASCII")
+}
+
+/// `attrs::ATTR_*` bits of a screen cell, zero without `extra_attrs`
+fn extra_bits(options: &RenderOptions, row: u16, col: u16) -> u8 {
+    options
+        .extra_attrs
+        .as_ref()
+        .map_or(0, |attrs| extra_attr_bits(attrs, row, col))
+}
+
+/// `attrs::ATTR_*` bits of a scrollback cell, zero without
+/// `scrollback_extra_attrs`
+fn scrollback_extra_bits(options: &RenderOptions, row: usize, col: usize) -> u8 {
+    options
+        .scrollback_extra_attrs
+        .as_ref()
+        .and_then(|rows| rows.get(row)?.get(col).copied())
+        .unwrap_or(0)
+}
+
+/// Format terminal state as a single SHA-256 digest line
+/// The digest covers exactly the bytes `format_hex_state` writes for the
+/// screen (codepoint, colors and attributes of each cell, row-major), so two
+/// screens hash alike exactly when their hex output is identical
+pub fn format_hash_state(
+    screen: &vt100::Screen,
+    rows: u16,
+    cols: u16,
+    options: &RenderOptions,
+) -> String {
+    let hex = format_hex_state(screen, rows, cols, options);
+    format!("{}\n", crate::sha256::sha256_hex(hex.as_bytes()))
+}
+
+/// Append one cell in the 22-char hex encoding; `extra` holds the cell's
+/// `attrs::ATTR_*` bits
+fn push_hex_cell(out: &mut Vec<u8>, cell: &vt100::Cell, options: &RenderOptions, extra: u8) {
+    // Get codepoints (space if empty); cells holding more than one, such as
+    // combining marks or emoji sequences, get a +NN count prefix. Blank
+    // cells skip the String `contents` allocates (`has_contents` is also true
+    // for the column a wide character covers, which has none)
+    let contents = if cell.has_contents() {
+        Cow::Owned(cell_contents(cell))
+    } else {
+        Cow::Borrowed(" ")
+    };
+    let count = match options.hex_version {
+        HexVersion::V1 => 1,
+        HexVersion::V2 => contents.chars().count().min(0xFF),
+    };
+    // V1 writes the column a wide character covers like any other cell (a
+    // space in its own colors), as hex output always did before V2
+    let continuation = cell.is_wide_continuation() && options.hex_version == HexVersion::V2;
+    if continuation {
+        push_hex_digits::<8>(out, WIDE_CONTINUATION_CODEPOINT);
+    } else if count > 1 {
+        out.push(b'+');
+        push_hex_digits::<2>(out, count as u32);
+    }
+    if !continuation {
+        for ch in contents.chars().take(count) {
+            push_hex_digits::<8>(out, ch as u32);
+        }
+    }
+
+    // Get foreground and background colors
+    push_hex_color(out, cell.fgcolor(), options.defaults.fg, options);
+    push_hex_color(out, cell.bgcolor(), options.defaults.bg, options);
+
+    // Get attributes as a byte:
+    //   0x01 bold           0x10 dim/faint
+    //   0x02 italic         0x20 blink
+    //   0x04 underline      0x40 strikethrough
+    //   0x08 inverse        0x80 wide (V2 only, see `HEX_ATTR_WIDE`)
+    // Hidden (SGR 8) has no bit; FF with codepoint 00000000 and black on
+    // black colors marks masked cells, which no real cell matches
+    let attrs = if continuation {
+        HEX_ATTR_WIDE
+    } else {
+        let mut a = 0u8;
+        if cell.bold() {
+            a |= 0x01;
+        }
+        if cell.italic() {
+            a |= 0x02;
+        }
+        if cell.underline() {
+            a |= 0x04;
+        }
+        if cell.inverse() {
+            a |= 0x08;
+        }
+        if cell.is_wide() && options.hex_version == HexVersion::V2 {
+            a |= HEX_ATTR_WIDE;
+        }
+        a | (extra & (ATTR_DIM | ATTR_BLINK | ATTR_STRIKETHROUGH))
+    };
+
+    // Hex format: CCCCCCCC RRGGBB RRGGBB AA
+    push_hex_digits::<2>(out, attrs as u32);
+}
+
+/// Append the low `DIGITS` hex digits of `value`, uppercase; the hex
+/// formats write millions of these, far faster this way than through `write!`
+fn push_hex_digits<const DIGITS: usize>(out: &mut Vec<u8>, value: u32) {
+    let mut buf = [0u8; DIGITS];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = b"0123456789ABCDEF"[(value >> ((DIGITS - 1 - i) * 4)) as usize & 0xF];
+    }
+    out.extend_from_slice(&buf);
+}
+
+/// Append a cell color: RRGGBB, or in `ColorMode::Indexed` a kind tag and
+/// value (see `format_hex_state`)
+fn push_hex_color(
+    out: &mut Vec<u8>,
+    color: vt100::Color,
+    default: (u8, u8, u8),
+    options: &RenderOptions,
+) {
+    let (tag, (r, g, b)) = match (options.color_mode, color) {
+        (ColorMode::Rgb, _) => {
+            let (r, g, b) = resolve_color(color, default, &options.palette);
+            push_hex_digits::<6>(out, u32::from_be_bytes([0, r, g, b]));
+            return;
+        }
+        (ColorMode::Indexed, vt100::Color::Default) => (HEX_COLOR_DEFAULT, (0, 0, 0)),
+        (ColorMode::Indexed, vt100::Color::Idx(idx)) => (HEX_COLOR_INDEXED, (0, 0, idx)),
+        (ColorMode::Indexed, vt100::Color::Rgb(r, g, b)) => (HEX_COLOR_RGB, (r, g, b)),
+    };
+    push_hex_digits::<8>(out, u32::from_be_bytes([tag, r, g, b]));
+}
+
+/// Format the scrollback section for hex format (appended after the screen)
+/// Format: a `SCROLLBACK <rows>` header line, then one line with the rows'
+/// cells, oldest first, in the same 22-char encoding as the screen
+pub fn format_hex_scrollback(scrollback: &[Vec<vt100::Cell>], options: &RenderOptions) -> String {
+    let cells: usize = scrollback.iter().map(Vec::len).sum();
+    let mut out = Vec::with_capacity(cells * HEX_CELL_LEN + 32);
+    out.extend_from_slice(format!("\n{} {}\n", HEX_SCROLLBACK_HEADER, scrollback.len()).as_bytes());
+    for (row, cells) in scrollback.iter().enumerate() {
+        for (col, cell) in cells.iter().enumerate() {
+            let extra = scrollback_extra_bits(options, row, col);
+            push_hex_cell(&mut out, cell, options, extra);
+        }
+    }
+    hex_string(out)
+}
+
+/// Format cursor trailer for hex format (appended after the last cell)
+/// Format: 10 chars = 4 (row) + 4 (col) + 2 (visible: 01 shown, 00 hidden)
+pub fn format_hex_cursor(screen: &vt100::Screen) -> String {
+    let (row, col) = screen.cursor_position();
+    let visible = !screen.hide_cursor();
+    format!("{:04X}{:04X}{:02X}", row, col, visible as u8)
+}
+
+/// Format window title trailer for hex format as a `TITLE <title>` line
+/// after the cells (and scrollback, if any)
+pub fn format_hex_title(title: &str) -> String {
+    format!("\n{} {}", TITLE_TRAILER, escape_title(title))
+}
+
+/// Format window title trailer for text format as a `TITLE <title>` line
+pub fn format_text_title(title: &str) -> String {
+    format!("{} {}\n", TITLE_TRAILER, escape_title(title))
+}
+
+/// Escape control characters so a title always stays on one line
+fn escape_title(title: &str) -> String {
+    title
+        .chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Format cursor trailer for text format as a `CURSOR row,col` line
+/// A hidden cursor is reported as `CURSOR row,col hidden`
+pub fn format_text_cursor(screen: &vt100::Screen) -> String {
+    let (row, col) = screen.cursor_position();
+    if screen.hide_cursor() {
+        format!("CURSOR {},{} hidden\n", row, col)
+    } else {
+        format!("CURSOR {},{}\n", row, col)
+    }
+}
+
+/// Format the "both" report: the text grid, a `REPORT_HEX_SEPARATOR` line,
+/// the hex dump, then `CURSOR row,col` and `EXIT <exit>` lines, where `exit`
+/// is the exit code or why the child was stopped
+/// (`CaptureResult::exit_label`), or `REPORT_NO_EXIT`
+pub fn format_report(
+    screen: &vt100::Screen,
+    rows: u16,
+    cols: u16,
+    options: &RenderOptions,
+    exit: &str,
+) -> String {
+    let mut out = format_text_state(screen, rows, cols, options);
+    let _ = writeln!(out, "{}", REPORT_HEX_SEPARATOR);
+    out.push_str(&format_hex_state(screen, rows, cols, options));
+    out.push('\n');
+    out.push_str(&format_text_cursor(screen));
+    let _ = writeln!(out, "{} {}", REPORT_EXIT_TRAILER, exit);
+    out
+}
+
+/// Format terminal state as text (just the characters)
+/// The cursor cell is replaced by `options.cursor_marker` when set
+pub fn format_text_state(
+    screen: &vt100::Screen,
+    rows: u16,
+    cols: u16,
+    options: &RenderOptions,
+) -> String {
+    let cursor = screen.cursor_position();
+    let mut out = String::new();
+
+    for row in 0..rows {
+        let mut line = String::new();
+        for col in 0..cols {
+            let cell = screen_cell(screen, row, col);
+            match options.cursor_marker {
+                Some(marker) if (row, col) == cursor => line.push(marker),
+                _ => line.push_str(&cell_text(&cell)),
+            }
+        }
+        // Trim trailing spaces
+        let trimmed = line.trim_end();
+        out.push_str(trimmed);
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Format scrollback rows for text format (printed above the screen), oldest
+/// first, followed by a marker line carrying the row count
+pub fn format_text_scrollback(scrollback: &[Vec<vt100::Cell>]) -> String {
+    let mut out = String::new();
+    for row in scrollback {
+        let line: String = row.iter().map(cell_text).collect();
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    let _ = writeln!(
+        out,
+        "{} ({} rows) --",
+        TEXT_SCROLLBACK_MARKER,
+        scrollback.len()
+    );
+    out
+}
+
+/// Format the whole history as plain text: scrollback rows, oldest first,
+/// followed by the screen rows with no marker in between
+pub fn format_text_history(
+    scrollback: &[Vec<vt100::Cell>],
+    screen: &vt100::Screen,
+    cols: u16,
+) -> String {
+    let mut out = String::new();
+    for row in scrollback {
+        let line: String = row.iter().map(cell_text).collect();
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    for line in screen.rows(0, cols) {
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Styling shared by a run of cells in the HTML and SVG formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CellStyle {
+    fg: (u8, u8, u8),
+    bg: (u8, u8, u8),
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    /// `attrs::ATTR_*` bits (dim, blink, strikethrough; hidden is applied to
+    /// the colors instead)
+    extra: u8,
+}
+
+impl CellStyle {
+    /// Resolve a cell's style; inverse is applied by swapping the colors
+    fn new(cell: &vt100::Cell, options: &RenderOptions, extra: u8) -> Self {
+        let fg = resolve_color(cell.fgcolor(), options.defaults.fg, &options.palette);
+        let bg = resolve_color(cell.bgcolor(), options.defaults.bg, &options.palette);
+        let (fg, bg) = if cell.inverse() { (bg, fg) } else { (fg, bg) };
+        // Hidden text is drawn in the background color, like a terminal does
+        let fg = if extra & ATTR_HIDDEN != 0 { bg } else { fg };
+        Self {
+            fg,
+            bg,
+            bold: cell.bold(),
+            italic: cell.italic(),
+            underline: cell.underline(),
+            extra: extra & (ATTR_DIM | ATTR_BLINK | ATTR_STRIKETHROUGH),
+        }
+    }
+
+    /// Masked cells render as blanks in the default colors
+    fn plain(defaults: DefaultColors) -> Self {
+        Self {
+            fg: defaults.fg,
+            bg: defaults.bg,
+            bold: false,
+            italic: false,
+            underline: false,
+            extra: 0,
+        }
+    }
+
+    fn css(&self) -> String {
+        let mut css = format!(
+            "color:{};background:{}",
+            css_color(self.fg),
+            css_color(self.bg)
+        );
+        if self.bold {
+            css.push_str(";font-weight:bold");
+        }
+        if self.italic {
+            css.push_str(";font-style:italic");
+        }
+        if self.extra & ATTR_DIM != 0 {
+            css.push_str(";opacity:0.5");
+        }
+        let decorations: Vec<&str> = [
+            (self.underline, "underline"),
+            (self.extra & ATTR_STRIKETHROUGH != 0, "line-through"),
+            (self.extra & ATTR_BLINK != 0, "blink"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect();
+        if !decorations.is_empty() {
+            let _ = write!(css, ";text-decoration:{}", decorations.join(" "));
+        }
+        css
+    }
+}
+
+/// `#rrggbb` form of a color for HTML and SVG
+fn css_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Append `text` with the HTML special characters escaped
+fn push_html_escaped(out: &mut String, text: &str) {
+    for ch in text.chars() {
+        match ch {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            _ => out.push(ch),
+        }
+    }
+}
+
+/// Format terminal state as a standalone HTML document
+/// The screen is a `<pre>` block with one `<span>` per run of identically
+/// styled cells; rows keep their trailing blanks so backgrounds line up
+pub fn format_html_state(
+    screen: &vt100::Screen,
+    rows: u16,
+    cols: u16,
+    options: &RenderOptions,
+) -> String {
+    let defaults = options.defaults;
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str("<title>pty_runner</title>\n");
+    out.push_str("<style>pre { font-family: monospace; line-height: 1.2; }</style>\n");
+    out.push_str("</head>\n<body>\n");
+    let _ = write!(out, "<pre style=\"{}\">", CellStyle::plain(defaults).css());
+
+    for row in 0..rows {
+        let mut run: Option<(CellStyle, String)> = None;
+        for col in 0..cols {
+            let (style, text) = if is_masked(&options.masks, row, col) {
+                (CellStyle::plain(defaults), " ".to_string())
+            } else {
+                let cell = screen_cell(screen, row, col);
+                // The wide character itself already covers this column
+                if cell.is_wide_continuation() {
+                    continue;
+                }
+                let text = cell_contents(&cell);
+                (
+                    CellStyle::new(&cell, options, extra_bits(options, row, col)),
+                    text,
+                )
+            };
+
+            match &mut run {
+                Some((current, buf)) if *current == style => buf.push_str(&text),
+                _ => {
+                    if let Some((current, buf)) = run.take() {
+                        push_html_span(&mut out, &current, &buf);
+                    }
+                    run = Some((style, text));
+                }
+            }
+        }
+        if let Some((current, buf)) = run {
+            push_html_span(&mut out, &current, &buf);
+        }
+        out.push('\n');
+    }
+
+    out.push_str("</pre>\n</body>\n</html>\n");
+    out
+}
+
+fn push_html_span(out: &mut String, style: &CellStyle, text: &str) {
+    let _ = write!(out, "<span style=\"{}\">", style.css());
+    push_html_escaped(out, text);
+    out.push_str("</span>");
+}
+
+/// Format terminal state as a deterministic SVG image
+/// Each cell is `cell_size` (width, height) pixels; backgrounds other than the
+/// default are drawn as one `<rect>` per run, text as one `<text>` per row
+/// with a `<tspan>` per styled run, and underlines as thin rects
+pub fn format_svg_state(
+    screen: &vt100::Screen,
+    rows: u16,
+    cols: u16,
+    options: &RenderOptions,
+    cell_size: (u32, u32),
+) -> String {
+    let defaults = options.defaults;
+    let (cell_w, cell_h) = cell_size;
+    let (width, height) = (u32::from(cols) * cell_w, u32::from(rows) * cell_h);
+    let font_size = cell_h * 4 / 5;
+    let mut backgrounds = String::new();
+    let mut texts = String::new();
+    let mut underlines = String::new();
+
+    for row in 0..rows {
+        let top = u32::from(row) * cell_h;
+        let baseline = top + cell_h * 4 / 5;
+
+        // Resolve the row into (column, width in cells, style, text) runs
+        let mut runs: Vec<(u16, u16, CellStyle, String)> = Vec::new();
+        for col in 0..cols {
+            let (style, text, span) = if is_masked(&options.masks, row, col) {
+                (CellStyle::plain(defaults), " ".to_string(), 1)
+            } else {
+                let cell = screen_cell(screen, row, col);
+                if cell.is_wide_continuation() {
+                    continue;
+                }
+                let text = cell_contents(&cell);
+                let span = if cell.is_wide() { 2 } else { 1 };
+                let extra = extra_bits(options, row, col);
+                (CellStyle::new(&cell, options, extra), text, span)
+            };
+            match runs.last_mut() {
+                Some((_, len, current, buf)) if *current == style => {
+                    *len += span;
+                    buf.push_str(&text);
+                }
+                _ => runs.push((col, span, style, text)),
+            }
+        }
+
+        let mut line = String::new();
+        for (col, len, style, text) in &runs {
+            let x = u32::from(*col) * cell_w;
+            let run_w = u32::from(*len) * cell_w;
+            if style.bg != defaults.bg {
+                let _ = writeln!(
+                    backgrounds,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                    x,
+                    top,
+                    run_w,
+                    cell_h,
+                    css_color(style.bg)
+                );
+            }
+            if style.underline {
+                let _ = writeln!(
+                    underlines,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"1\" fill=\"{}\"/>",
+                    x,
+                    baseline + 1,
+                    run_w,
+                    css_color(style.fg)
+                );
+            }
+            if text.trim().is_empty() {
+                continue;
+            }
+            let _ = write!(line, "<tspan x=\"{}\" fill=\"{}\"", x, css_color(style.fg));
+            if style.bold {
+                line.push_str(" font-weight=\"bold\"");
+            }
+            if style.italic {
+                line.push_str(" font-style=\"italic\"");
+            }
+            line.push('>');
+            push_html_escaped(&mut line, text);
+            line.push_str("</tspan>");
+        }
+        if !line.is_empty() {
+            let _ = writeln!(texts, "<text y=\"{}\">{}</text>", baseline, line);
+        }
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">",
+        width, height
+    );
+    let _ = writeln!(
+        out,
+        "<rect x=\"0\" y=\"0\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+        width,
+        height,
+        css_color(defaults.bg)
+    );
+    out.push_str(&backgrounds);
+    let _ = writeln!(
+        out,
+        "<g font-family=\"monospace\" font-size=\"{}\" xml:space=\"preserve\">",
+        font_size
+    );
+    out.push_str(&texts);
+    out.push_str("</g>\n");
+    out.push_str(&underlines);
+    out.push_str("</svg>\n");
+    out
+}
+
+/// Machine-readable facts about one run, written as the JSON `meta` object
+/// and by --meta-file; values unknown for the run (e.g. after a spawn
+/// failure) are null
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunMeta {
+    pub executable: String,
+    pub args: Vec<String>,
+    pub rows: u16,
+    pub cols: u16,
+    /// Wall-clock time from spawn until the output was fully read
+    pub duration_ms: Option<u64>,
+    /// Wall-clock time from spawn until the program exited or was stopped
+    pub run_time_ms: Option<u64>,
+    /// Whether the program was stopped by a timeout rather than exiting
+    pub timed_out: bool,
+    /// Whether output past --max-output-bytes was dropped
+    pub output_truncated: bool,
+    /// Whether the program left the alternate screen active
+    pub alternate_screen: bool,
+    /// Whether the program switched to the alternate screen at any point
+    pub alternate_screen_used: bool,
+    pub exit_code: Option<u32>,
+    /// Description of the signal that terminated the child
+    pub signal: Option<String>,
+    /// Raw bytes read from the PTY
+    pub bytes_captured: Option<usize>,
+    /// Bytes left after OSC filtering
+    pub bytes_filtered: Option<usize>,
+    /// Input bytes sent to the program
+    pub input_bytes_sent: Option<usize>,
+    /// Input bytes written while the program's terminal was open; fewer than
+    /// sent means the rest never reached it
+    pub input_bytes_written: Option<usize>,
+    /// The write error that stopped the input, if one did
+    pub input_error: Option<String>,
+    /// Why the child could not be started
+    pub spawn_error: Option<String>,
+}
+
+/// Run details reported in JSON output next to the screen
+#[derive(Default)]
+pub struct JsonDetails<'a> {
+    /// Exit status of the child, None if it was killed or no program ran
+    pub exit_status: Option<&'a portable_pty::ExitStatus>,
+    /// The program was stopped before it exited (by a timeout or any other
+    /// kill); false when no program ran, as in a replay
+    pub timed_out: bool,
+    /// Adds a `scrollback` section
+    pub scrollback: Option<&'a [Vec<vt100::Cell>]>,
+    /// Adds a `title` field
+    pub title: Option<&'a str>,
+    /// Adds a `meta` object
+    pub meta: Option<&'a RunMeta>,
+}
+
+/// Top-level JSON document for the "json" output format
+#[derive(Serialize)]
+struct JsonState {
+    version: u32,
+    rows: u16,
+    cols: u16,
+    cursor: JsonCursor,
+    /// Whether the screen captured is the alternate screen (`ESC[?1049h`)
+    alternate_screen: bool,
+    exit: JsonExit,
+    cells: Vec<Vec<JsonCell>>,
+    /// Only present when scrollback capture was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scrollback: Option<JsonScrollback>,
+    /// Only present when title capture was requested and a title was set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<RunMeta>,
+}
+
+#[derive(Serialize)]
+struct JsonScrollback {
+    /// Rows scrolled off the top and kept (at most the requested N)
+    rows: usize,
+    /// Scrolled-off rows, oldest first
+    cells: Vec<Vec<JsonCell>>,
+}
+
+#[derive(Serialize)]
+struct JsonExit {
+    /// Child exit code, or null if the child was killed or no program ran
+    code: Option<u32>,
+    timed_out: bool,
+}
+
+#[derive(Serialize)]
+struct JsonCursor {
+    row: u16,
+    col: u16,
+    visible: bool,
+}
+
+#[derive(Serialize)]
+struct JsonCell {
+    char: String,
+    fg: JsonColor,
+    bg: JsonColor,
+    attrs: JsonAttrs,
+    /// Only present (as true) for cells covered by --mask with --mask-output
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    masked: bool,
+    /// Only present (as true) for a double-width character
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    wide: bool,
+    /// Only present (as true) for the column covered by the right half of a
+    /// double-width character; `char` is empty
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    wide_continuation: bool,
+}
+
+impl JsonCell {
+    /// Encode a screen cell, resolving colors exactly as in the hex format;
+    /// `extra` holds the cell's `attrs::ATTR_*` bits
+    fn new(cell: &vt100::Cell, options: &RenderOptions, extra: u8) -> Self {
+        // Empty cells are emitted as a space to keep rows uniform
+        let ch = cell_text(cell);
+        Self {
+            char: ch,
+            fg: JsonColor::new(cell.fgcolor(), options.defaults.fg, options),
+            bg: JsonColor::new(cell.bgcolor(), options.defaults.bg, options),
+            attrs: JsonAttrs {
+                bold: cell.bold(),
+                italic: cell.italic(),
+                underline: cell.underline(),
+                inverse: cell.inverse(),
+                dim: extra & ATTR_DIM != 0,
+                blink: extra & ATTR_BLINK != 0,
+                strikethrough: extra & ATTR_STRIKETHROUGH != 0,
+                hidden: extra & ATTR_HIDDEN != 0,
+            },
+            masked: false,
+            wide: cell.is_wide(),
+            wide_continuation: cell.is_wide_continuation(),
+        }
+    }
+
+    /// Fixed sentinel cell emitted for masked regions
+    fn masked(color_mode: ColorMode) -> Self {
+        let color = match color_mode {
+            ColorMode::Rgb => JsonColor::Rgb([0, 0, 0]),
+            ColorMode::Indexed => JsonColor::Kind(JsonColorKind::Default),
+        };
+        Self {
+            char: " ".to_string(),
+            fg: color,
+            bg: color,
+            attrs: JsonAttrs {
+                bold: false,
+                italic: false,
+                underline: false,
+                inverse: false,
+                dim: false,
+                blink: false,
+                strikethrough: false,
+                hidden: false,
+            },
+            masked: true,
+            wide: false,
+            wide_continuation: false,
+        }
+    }
+}
+
+/// A cell color: an `[r, g, b]` array, or in `ColorMode::Indexed` an object
+/// with its kind, e.g. `{"type":"indexed","value":4}`
+#[derive(Clone, Copy, Serialize)]
+#[serde(untagged)]
+enum JsonColor {
+    Rgb([u8; 3]),
+    Kind(JsonColorKind),
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonColorKind {
+    Default,
+    Indexed { value: u8 },
+    Rgb { value: [u8; 3] },
+}
+
+impl JsonColor {
+    fn new(color: vt100::Color, default: (u8, u8, u8), options: &RenderOptions) -> Self {
+        match (options.color_mode, color) {
+            (ColorMode::Rgb, _) => {
+                let (r, g, b) = resolve_color(color, default, &options.palette);
+                JsonColor::Rgb([r, g, b])
+            }
+            (ColorMode::Indexed, vt100::Color::Default) => JsonColor::Kind(JsonColorKind::Default),
+            (ColorMode::Indexed, vt100::Color::Idx(value)) => {
+                JsonColor::Kind(JsonColorKind::Indexed { value })
+            }
+            (ColorMode::Indexed, vt100::Color::Rgb(r, g, b)) => {
+                JsonColor::Kind(JsonColorKind::Rgb { value: [r, g, b] })
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonAttrs {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    inverse: bool,
+    /// The remaining attributes match the hex byte's 0x10, 0x20 and 0x40
+    /// bits (hidden has none) and are only present (as true) when set, so
+    /// cells without them serialize as before
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dim: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    blink: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    strikethrough: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    hidden: bool,
+}
+
+/// Format terminal state as a JSON document with one object per cell
+/// Colors are resolved exactly as in the hex format; `details` adds the exit
+/// status and any optional sections
+pub fn format_json_state(
+    screen: &vt100::Screen,
+    rows: u16,
+    cols: u16,
+    options: &RenderOptions,
+    details: &JsonDetails,
+) -> Result<String> {
+    let exit_status = details.exit_status;
+    let (cursor_row, cursor_col) = screen.cursor_position();
+
+    let mut cells = Vec::with_capacity(rows as usize);
+    for row in 0..rows {
+        let mut line = Vec::with_capacity(cols as usize);
+        for col in 0..cols {
+            if is_masked(&options.masks, row, col) {
+                line.push(JsonCell::masked(options.color_mode));
+                continue;
+            }
+
+            line.push(JsonCell::new(
+                &screen_cell(screen, row, col),
+                options,
+                extra_bits(options, row, col),
+            ));
+        }
+        cells.push(line);
+    }
+
+    let state = JsonState {
+        version: JSON_SCHEMA_VERSION,
+        rows,
+        cols,
+        cursor: JsonCursor {
+            row: cursor_row,
+            col: cursor_col,
+            visible: !screen.hide_cursor(),
+        },
+        alternate_screen: screen.alternate_screen(),
+        exit: JsonExit {
+            code: exit_status.map(|status| status.exit_code()),
+            timed_out: details.timed_out,
+        },
+        cells,
+        scrollback: details.scrollback.map(|rows| JsonScrollback {
+            rows: rows.len(),
+            cells: rows
+                .iter()
+                .enumerate()
+                .map(|(row, cells)| {
+                    cells
+                        .iter()
+                        .enumerate()
+                        .map(|(col, cell)| {
+                            JsonCell::new(cell, options, scrollback_extra_bits(options, row, col))
+                        })
+                        .collect()
+                })
+                .collect(),
+        }),
+        title: details.title.map(str::to_string),
+        meta: details.meta.cloned(),
+    };
+
+    let mut out = serde_json::to_string(&state)?;
+    out.push('\n');
+    Ok(out)
+}
+
+/// Resolve a vt100 color to RGB, using the given default for Color::Default
+pub fn color_to_rgb(color: vt100::Color, default: (u8, u8, u8)) -> (u8, u8, u8) {
+    resolve_color(color, default, &Palette::default())
+}
+
+/// Convert vt100 color to RGB, looking indexed colors up in `palette`
+pub fn resolve_color(
+    color: vt100::Color,
+    default: (u8, u8, u8),
+    palette: &Palette,
+) -> (u8, u8, u8) {
+    match color {
+        vt100::Color::Rgb(r, g, b) => (r, g, b),
+        vt100::Color::Idx(idx) => ansi_to_rgb(idx, palette),
+        vt100::Color::Default => default,
+    }
+}
+
+/// Convert ANSI color index to RGB through `palette`
+pub fn ansi_to_rgb(idx: u8, palette: &Palette) -> (u8, u8, u8) {
+    palette.rgb(idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_escapes_and_merges_runs() {
+        let mut parser = vt100::Parser::new(1, 6, 0);
+        parser.process("<&>\x1b[1m中\x1b[0m".as_bytes());
+        let html = format_html_state(parser.screen(), 1, 6, &RenderOptions::default());
+
+        let plain = "<span style=\"color:#f0f0f0;background:#000000\">";
+        let bold = "<span style=\"color:#f0f0f0;background:#000000;font-weight:bold\">";
+        let expected_row = format!(
+            "{}&lt;&amp;&gt;</span>{}中</span>{} </span>\n",
+            plain, bold, plain
+        );
+        assert!(html.contains(&expected_row), "{}", html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.ends_with("</pre>\n</body>\n</html>\n"));
+    }
+
+    #[test]
+    fn multi_codepoint_cells_keep_every_codepoint() {
+        let mut parser = vt100::Parser::new(1, 4, 0);
+        parser.process("e\u{301}\u{1F44D}".as_bytes());
+        let options = RenderOptions {
+            hex_version: HexVersion::V2,
+            ..Default::default()
+        };
+
+        let hex = format_hex_state(parser.screen(), 1, 4, &options);
+        assert!(hex.starts_with("+020000006500000301F0F0F000000000"));
+        assert!(hex[33..].starts_with("0001F44DF0F0F000000080"));
+        let cells = crate::compare::parse_hex_cells(&hex).unwrap();
+        assert_eq!(cells.len(), 4);
+        assert_eq!(cells[0].text(), "e\u{301}");
+        assert_eq!(cells[1].text(), "\u{1F44D}");
+
+        let text = format_text_state(parser.screen(), 1, 4, &options);
+        assert!(text.starts_with("e\u{301}\u{1F44D}"), "{:?}", text);
+    }
+
+    #[test]
+    fn hash_follows_the_hex_encoding() {
+        let mut parser = vt100::Parser::new(2, 4, 0);
+        parser.process(b"ab\x1b[1mc");
+        let options = RenderOptions::default();
+        let hash = format_hash_state(parser.screen(), 2, 4, &options);
+        let hex = format_hex_state(parser.screen(), 2, 4, &options);
+        assert_eq!(hash, crate::sha256::sha256_hex(hex.as_bytes()) + "\n");
+        assert_eq!(hash.len(), 65);
+
+        // Any attribute change alters the digest
+        let mut plain = vt100::Parser::new(2, 4, 0);
+        plain.process(b"abc");
+        assert_ne!(hash, format_hash_state(plain.screen(), 2, 4, &options));
+    }
+
+    #[test]
+    fn grapheme_clusters_per_hex_version() {
+        // vt100 attaches zero-width codepoints (combining marks, variation
+        // selectors, joiners) to the preceding cell; each emoji of a ZWJ
+        // sequence and each regional indicator of a flag gets its own cell
+        let mut parser = vt100::Parser::new(1, 10, 0);
+        parser.process(
+            "e\u{301}\u{2764}\u{FE0F}\u{1F468}\u{200D}\u{1F469}\u{1F1EF}\u{1F1F5}".as_bytes(),
+        );
+        let screen = parser.screen();
+        let v1 = RenderOptions::default();
+        let v2 = RenderOptions {
+            hex_version: HexVersion::V2,
+            ..Default::default()
+        };
+
+        let expected = [
+            "e\u{301}",
+            "\u{2764}\u{FE0F}",
+            "\u{1F468}\u{200D}",
+            "\0",
+            "\u{1F469}",
+            "\0",
+            "\u{1F1EF}",
+            "\u{1F1F5}",
+            " ",
+            " ",
+        ];
+        let cells = crate::compare::parse_hex_cells(&format_hex_state(screen, 1, 10, &v2)).unwrap();
+        let texts: Vec<String> = cells.iter().map(|cell| cell.text()).collect();
+        assert_eq!(texts, expected);
+
+        let hex = format_hex_state(screen, 1, 10, &v1);
+        assert_eq!(hex.len(), 10 * HEX_CELL_LEN);
+        let codepoints: Vec<&str> = (0..10).map(|i| &hex[i * 22..i * 22 + 8]).collect();
+        assert_eq!(
+            codepoints,
+            [
+                "00000065", "00002764", "0001F468", "00000020", "0001F469", "00000020", "0001F1EF",
+                "0001F1F5", "00000020", "00000020"
+            ]
+        );
+        // V1 has no wide bit, and the covered columns are plain spaces
+        let attrs: Vec<&str> = (0..10).map(|i| &hex[i * 22 + 20..i * 22 + 22]).collect();
+        assert_eq!(attrs, ["00"; 10]);
+
+        // Text and JSON always carry the full contents
+        let text = format_text_state(screen, 1, 10, &v1);
+        assert_eq!(
+            text,
+            expected.concat().replace('\0', "").trim_end().to_string() + "\n"
+        );
+        let json = format_json_state(screen, 1, 10, &v1, &JsonDetails::default()).unwrap();
+        assert!(json.contains("\"char\":\"e\u{301}\""), "{}", json);
+        assert!(json.contains("\"char\":\"\u{1F468}\u{200D}\""), "{}", json);
+    }
+
+    #[test]
+    fn wide_characters_keep_column_layout() {
+        let mut parser = vt100::Parser::new(1, 8, 0);
+        parser.process("中文ab".as_bytes());
+        let options = RenderOptions {
+            hex_version: HexVersion::V2,
+            ..Default::default()
+        };
+
+        let hex = format_hex_state(parser.screen(), 1, 8, &options);
+        let codepoints: Vec<&str> = (0..8).map(|i| &hex[i * 22..i * 22 + 8]).collect();
+        assert_eq!(
+            codepoints,
+            [
+                "00004E2D", "00000000", "00006587", "00000000", "00000061", "00000062", "00000020",
+                "00000020"
+            ]
+        );
+        let attrs: Vec<&str> = (0..8).map(|i| &hex[i * 22 + 20..i * 22 + 22]).collect();
+        assert_eq!(attrs, ["80", "80", "80", "80", "00", "00", "00", "00"]);
+
+        let text = format_text_state(parser.screen(), 1, 8, &options);
+        assert_eq!(text, "中文ab\n");
+
+        let json =
+            format_json_state(parser.screen(), 1, 8, &options, &JsonDetails::default()).unwrap();
+        assert!(json.contains(r#"{"char":"中","fg":[240,240,240],"bg":[0,0,0],"attrs":{"bold":false,"italic":false,"underline":false,"inverse":false},"wide":true}"#));
+        assert!(json.contains(r#""char":"","fg":[240,240,240],"bg":[0,0,0],"attrs":{"bold":false,"italic":false,"underline":false,"inverse":false},"wide_continuation":true}"#));
+    }
+
+    #[test]
+    fn wide_characters_at_every_column_including_the_last() {
+        let cases = [
+            (
+                "你好",
+                ["00004F60", "00000000", "0000597D", "00000000", "00000020"],
+                ["80", "80", "80", "80", "00"],
+                "你好\n\n",
+            ),
+            (
+                "a你好",
+                ["00000061", "00004F60", "00000000", "0000597D", "00000000"],
+                ["00", "80", "80", "80", "80"],
+                "a你好\n\n",
+            ),
+            (
+                "abc\x1b[1m你",
+                ["00000061", "00000062", "00000063", "00004F60", "00000000"],
+                ["00", "00", "00", "81", "80"],
+                "abc你\n\n",
+            ),
+            (
+                "abcd你",
+                ["00000061", "00000062", "00000063", "00000064", "00000020"],
+                ["00", "00", "00", "00", "00"],
+                "abcd\n你\n",
+            ),
+        ];
+        let options = RenderOptions {
+            hex_version: HexVersion::V2,
+            ..Default::default()
+        };
+        for (input, first_row, first_attrs, text) in cases {
+            let mut parser = vt100::Parser::new(2, 5, 0);
+            parser.process(input.as_bytes());
+
+            let hex = format_hex_state(parser.screen(), 2, 5, &options);
+            let codepoints: Vec<&str> = (0..5).map(|i| &hex[i * 22..i * 22 + 8]).collect();
+            assert_eq!(codepoints, first_row, "{}", input);
+            let attrs: Vec<&str> = (0..5).map(|i| &hex[i * 22 + 20..i * 22 + 22]).collect();
+            assert_eq!(attrs, first_attrs, "{}", input);
+            assert_eq!(
+                format_text_state(parser.screen(), 2, 5, &options),
+                text,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn ansi_palette_cube_and_grayscale() {
+        let palette = Palette::default();
+        assert_eq!(ansi_to_rgb(1, &palette), (205, 49, 49));
+        assert_eq!(ansi_to_rgb(15, &palette), (255, 255, 255));
+        assert_eq!(ansi_to_rgb(16, &palette), (0, 0, 0));
+        assert_eq!(ansi_to_rgb(196, &palette), (255, 0, 0));
+        assert_eq!(ansi_to_rgb(231, &palette), (255, 255, 255));
+        assert_eq!(ansi_to_rgb(232, &palette), (8, 8, 8));
+        assert_eq!(ansi_to_rgb(255, &palette), (238, 238, 238));
+
+        // Presets only change colors 0-15
+        let vga = Palette::load("vga").unwrap();
+        assert_eq!(ansi_to_rgb(1, &vga), (170, 0, 0));
+        assert_eq!(ansi_to_rgb(196, &vga), (255, 0, 0));
+        assert_eq!(ansi_to_rgb(232, &vga), (8, 8, 8));
+    }
+
+    #[test]
+    fn hex_formatting_a_large_screen_stays_fast() {
+        let (rows, cols) = (100, 300);
+        let mut parser = vt100::Parser::new(rows, cols, 0);
+        for i in 0..rows as usize * cols as usize / 10 {
+            parser.process(format!("\x1b[3{}m0123456789", i % 8).as_bytes());
+        }
+        let formatter = screen_formatter("hex", (8, 16)).unwrap();
+
+        let started = std::time::Instant::now();
+        let hex = formatter.format(parser.screen(), rows, cols, &RenderOptions::default());
+        // Tens of milliseconds even unoptimized; a per-cell regression would be far slower
+        // (benches/hex_format.rs holds optimized builds to a tight bound)
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(hex.len(), rows as usize * cols as usize * HEX_CELL_LEN);
+    }
+
+    #[test]
+    fn report_has_text_then_hex_then_cursor_and_exit() {
+        let mut parser = vt100::Parser::new(2, 2, 0);
+        parser.process(b"ok");
+        let options = RenderOptions::default();
+
+        let report = format_report(parser.screen(), 2, 2, &options, "3");
+        let hex = format_hex_state(parser.screen(), 2, 2, &options);
+        assert_eq!(
+            report,
+            format!("ok\n\n-- hex --\n{}\nCURSOR 0,2\nEXIT 3\n", hex)
+        );
+        let report = format_report(parser.screen(), 2, 2, &options, "IDLE_TIMEOUT");
+        assert!(report.ends_with("\nEXIT IDLE_TIMEOUT\n"), "{}", report);
+    }
+
+    #[test]
+    fn hex_attrs_byte_encodes_each_sgr_attribute() {
+        let data = b"\x1b[1ma\x1b[0;3ma\x1b[0;4ma\x1b[0;7ma\x1b[0;2ma\x1b[0;5ma\x1b[0;9ma\x1b[0;8ma\x1b[0m\x1b[2;9ma";
+        let mut parser = vt100::Parser::new(1, 10, 0);
+        parser.process(data);
+        let mut extra = crate::attrs::ExtraAttrs::new(1, 10);
+        extra.process(data);
+        let options = RenderOptions {
+            extra_attrs: Some(extra.screen().clone()),
+            ..Default::default()
+        };
+
+        let hex = format_hex_state(parser.screen(), 1, 10, &options);
+        let attrs: Vec<&str> = (0..10).map(|i| &hex[i * 22 + 20..i * 22 + 22]).collect();
+        // Hidden has no bit of its own; JSON and HTML show it
+        assert_eq!(
+            attrs,
+            ["01", "02", "04", "08", "10", "20", "40", "00", "50", "00"]
+        );
+
+        let json =
+            format_json_state(parser.screen(), 1, 10, &options, &JsonDetails::default()).unwrap();
+        assert!(json.contains(r#""inverse":false,"dim":true}"#), "{}", json);
+        assert!(
+            json.contains(r#""inverse":false,"blink":true}"#),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains(r#""inverse":false,"dim":true,"strikethrough":true}"#),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains(r#""inverse":false,"hidden":true}"#),
+            "{}",
+            json
+        );
+
+        let html = format_html_state(parser.screen(), 1, 10, &options);
+        assert!(
+            html.contains("background:#000000;opacity:0.5\">a</span>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(";text-decoration:blink\">a</span>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(";opacity:0.5;text-decoration:line-through\">a</span>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("\"color:#000000;background:#000000\">a</span>"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn palette_applies_to_every_format() {
+        let mut parser = vt100::Parser::new(1, 2, 0);
+        parser.process(b"\x1b[34;101mx");
+        let mut colors = Palette::default().0;
+        colors[4] = (0x12, 0x34, 0x56);
+        colors[9] = (0xab, 0xcd, 0xef);
+        let options = RenderOptions {
+            palette: Palette(colors),
+            ..Default::default()
+        };
+        let screen = parser.screen();
+
+        assert!(format_hex_state(screen, 1, 2, &options).starts_with("00000078123456ABCDEF00"));
+        let json = format_json_state(screen, 1, 2, &options, &JsonDetails::default()).unwrap();
+        assert!(
+            json.contains(r#""fg":[18,52,86],"bg":[171,205,239]"#),
+            "{}",
+            json
+        );
+        let html = format_html_state(screen, 1, 2, &options);
+        assert!(
+            html.contains("color:#123456;background:#abcdef"),
+            "{}",
+            html
+        );
+        let svg = format_svg_state(screen, 1, 2, &options, (8, 16));
+        assert!(svg.contains("fill=\"#abcdef\""), "{}", svg);
+        assert!(svg.contains("fill=\"#123456\">x"), "{}", svg);
+    }
+
+    #[test]
+    fn indexed_color_mode_keeps_what_the_program_set() {
+        let mut parser = vt100::Parser::new(1, 3, 0);
+        parser.process(b"\x1b[34ma\x1b[38;2;1;2;3;48;5;200mb\x1b[m");
+        let options = RenderOptions {
+            color_mode: ColorMode::Indexed,
+            masks: vec![Mask {
+                row: 0,
+                col: 2,
+                width: 1,
+                height: 1,
+            }],
+            ..Default::default()
+        };
+        let screen = parser.screen();
+
+        let hex = format_hex_state(screen, 1, 3, &options);
+        assert_eq!(
+            hex,
+            format!(
+                "{}\n{}{}{}",
+                HEX_INDEXED_HEADER,
+                "000000610100000400000000",
+                "00",
+                "0000006202010203010000C800000000000000000000000000FF"
+            )
+        );
+        let cells = crate::compare::parse_hex_cells(&hex).unwrap();
+        assert_eq!(cells.len(), 3);
+        // The palette plays no part
+        let recolored = RenderOptions {
+            palette: Palette::load("xterm").unwrap(),
+            ..options.clone()
+        };
+        assert_eq!(format_hex_state(screen, 1, 3, &recolored), hex);
+
+        let json = format_json_state(screen, 1, 3, &options, &JsonDetails::default()).unwrap();
+        assert!(
+            json.contains(
+                r#""char":"a","fg":{"type":"indexed","value":4},"bg":{"type":"default"}"#
+            ),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains(
+                r#""fg":{"type":"rgb","value":[1,2,3]},"bg":{"type":"indexed","value":200}"#
+            ),
+            "{}",
+            json
+        );
+        assert!(json.contains(r#""fg":{"type":"default"},"bg":{"type":"default"},"attrs""#));
+    }
+
+    #[test]
+    fn grid_larger_than_the_screen_renders_blanks() {
+        let mut parser = vt100::Parser::new(2, 3, 0);
+        parser.process(b"abc\r\nde");
+        let screen = parser.screen();
+        let options = RenderOptions::default();
+
+        let hex = format_hex_state(screen, 3, 5, &options);
+        let cells = crate::compare::parse_hex_cells(&hex).unwrap();
+        let texts: String = cells.iter().map(|cell| cell.text()).collect();
+        assert_eq!(texts, "abc  de        ");
+        assert_eq!(format_text_state(screen, 3, 5, &options), "abc\nde\n\n");
+        let json = format_json_state(screen, 3, 5, &options, &JsonDetails::default()).unwrap();
+        assert_eq!(json.matches(r#""char""#).count(), 15);
+        let html = format_html_state(screen, 3, 5, &options);
+        assert!(html.contains(">de   </span>\n<span"), "{}", html);
+        assert!(html.contains(">     </span>\n</pre>"), "{}", html);
+        assert!(format_svg_state(screen, 3, 5, &options, (8, 16)).contains("height=\"48\""));
+    }
+
+    #[test]
+    fn captures_without_extra_attributes_are_unchanged() {
+        // Everything a program could style before dim, blink and
+        // strikethrough were tracked
+        let data = b"\x1b[1mb\x1b[3mi\x1b[4mu\x1b[7mr\x1b[0;31;42mc\x1b[38;5;200mx\x1b[0m\r\nplain";
+        let mut parser = vt100::Parser::new(2, 8, 0);
+        parser.process(data);
+        let mut extra = crate::attrs::ExtraAttrs::new(2, 8);
+        extra.process(data);
+        let without = RenderOptions::default();
+        let with = RenderOptions {
+            extra_attrs: Some(extra.screen().clone()),
+            ..Default::default()
+        };
+
+        let screen = parser.screen();
+        assert_eq!(
+            format_hex_state(screen, 2, 8, &with),
+            format_hex_state(screen, 2, 8, &without)
+        );
+        assert_eq!(
+            format_html_state(screen, 2, 8, &with),
+            format_html_state(screen, 2, 8, &without)
+        );
+        assert_eq!(
+            format_svg_state(screen, 2, 8, &with, (8, 16)),
+            format_svg_state(screen, 2, 8, &without, (8, 16))
+        );
+        let details = JsonDetails::default();
+        assert_eq!(
+            format_json_state(screen, 2, 8, &with, &details).unwrap(),
+            format_json_state(screen, 2, 8, &without, &details).unwrap()
+        );
+    }
+}
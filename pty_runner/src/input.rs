@@ -0,0 +1,503 @@
+//! Keyboard input: key tokens and keyboard scripts
+
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// A single step of a timed keyboard script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptStep {
+    /// Write these bytes to the PTY
+    Send(Vec<u8>),
+    /// Pause for this many milliseconds
+    Sleep(u64),
+    /// Block until this text appears on the screen
+    Expect(String),
+}
+
+/// Decode backslash escapes (\x1b, \e, \r, \n, \t, \\) into raw bytes
+pub fn decode_escapes(text: &str) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(text.len());
+    let bytes = text.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            result.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let escape = bytes
+            .get(i + 1)
+            .ok_or_else(|| anyhow::anyhow!("Trailing backslash"))?;
+        match escape {
+            b'r' => result.push(b'\r'),
+            b'n' => result.push(b'\n'),
+            b't' => result.push(b'\t'),
+            b'e' => result.push(0x1b),
+            b'\\' => result.push(b'\\'),
+            b'x' => {
+                // Exactly two hex digits, e.g. \x1b
+                let hex = text
+                    .get(i + 2..i + 4)
+                    .ok_or_else(|| anyhow::anyhow!("Truncated \\x escape"))?;
+                let value = u8::from_str_radix(hex, 16)
+                    .with_context(|| format!("Invalid \\x escape: \\x{}", hex))?;
+                result.push(value);
+                i += 2;
+            }
+            other => anyhow::bail!("Unknown escape: \\{}", *other as char),
+        }
+        i += 2;
+    }
+
+    Ok(result)
+}
+
+/// Escape bytes for a script `send` line, undone by `decode_escapes`:
+/// printable ASCII stays as it is, except a backslash and a space at the end
+/// (which editors tend to strip)
+pub fn encode_escapes(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        match byte {
+            b'\r' => text.push_str("\\r"),
+            b'\n' => text.push_str("\\n"),
+            b'\t' => text.push_str("\\t"),
+            0x1b => text.push_str("\\e"),
+            b'\\' => text.push_str("\\\\"),
+            b' ' if i + 1 == data.len() => text.push_str("\\x20"),
+            0x20..=0x7e => text.push(byte as char),
+            _ => {
+                let _ = write!(text, "\\x{:02x}", byte);
+            }
+        }
+    }
+    text
+}
+
+/// Write typed input as a keyboard script of `send` lines, one per chunk
+/// read from the keyboard; with `timing`, a `sleep` line before each keeps
+/// the pauses (the first one counts from `keys`' zero time)
+pub fn format_keyboard_script(keys: &[(Duration, Vec<u8>)], timing: bool) -> String {
+    let mut script = String::new();
+    let mut last = Duration::ZERO;
+    for (at, data) in keys {
+        let pause = at.saturating_sub(last).as_millis();
+        if timing && pause > 0 {
+            let _ = writeln!(script, "sleep {}", pause);
+        }
+        let _ = writeln!(script, "send {}", encode_escapes(data));
+        last = *at;
+    }
+    script
+}
+
+/// Decode hex bytes such as "1b 5b 41" or "1b5b41" (whitespace is ignored
+/// between tokens, each token must have an even number of hex digits)
+pub fn decode_hex_bytes(text: &str) -> Result<Vec<u8>> {
+    let mut result = Vec::new();
+    for token in text.split_whitespace() {
+        if token.len() % 2 != 0 {
+            anyhow::bail!("Odd number of hex digits in {:?}", token);
+        }
+        for i in (0..token.len()).step_by(2) {
+            let pair = token
+                .get(i..i + 2)
+                .ok_or_else(|| anyhow::anyhow!("Invalid hex token {:?}", token))?;
+            let byte = u8::from_str_radix(pair, 16)
+                .map_err(|_| anyhow::anyhow!("Invalid hex byte {:?} in {:?}", pair, token))?;
+            result.push(byte);
+        }
+    }
+    Ok(result)
+}
+
+/// Parse a keyboard script, one command per line:
+/// - `send <escaped bytes>` writes text, decoding \x1b-style escapes
+/// - `send-hex <hex bytes>` writes raw bytes given as hex
+/// - `key <names>` writes named keys, e.g. `key Down Down Enter` or `key C-c`
+/// - `sleep <ms>` (or `wait <ms>`) pauses
+/// - `expect <text>` blocks until the text is visible on screen
+///
+/// Blank lines and lines starting with '#' are ignored
+pub fn parse_keyboard_script(text: &str) -> Result<Vec<ScriptStep>> {
+    let mut steps = Vec::new();
+
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let step = match command {
+            "send" => ScriptStep::Send(
+                decode_escapes(rest).with_context(|| format!("Script line {}", line_no + 1))?,
+            ),
+            "send-hex" => ScriptStep::Send(
+                decode_hex_bytes(rest).with_context(|| format!("Script line {}", line_no + 1))?,
+            ),
+            "key" => ScriptStep::Send(
+                decode_key_names(rest).with_context(|| format!("Script line {}", line_no + 1))?,
+            ),
+            "sleep" | "wait" => {
+                ScriptStep::Sleep(rest.trim().parse().with_context(|| {
+                    format!("Script line {}: invalid sleep duration", line_no + 1)
+                })?)
+            }
+            "expect" => ScriptStep::Expect(rest.to_string()),
+            other => anyhow::bail!("Script line {}: unknown command {:?}", line_no + 1, other),
+        };
+        steps.push(step);
+    }
+
+    Ok(steps)
+}
+
+/// Concatenate the bytes of whitespace-separated key names (`key_bytes`
+/// names or `C-x` chords)
+fn decode_key_names(text: &str) -> Result<Vec<u8>> {
+    let mut result = Vec::new();
+    for name in text.split_whitespace() {
+        if let Some(bytes) = key_bytes(name) {
+            result.extend_from_slice(bytes);
+        } else if let Some(byte) = control_byte(name) {
+            result.push(byte);
+        } else {
+            anyhow::bail!("Unknown key name {:?}", name);
+        }
+    }
+    Ok(result)
+}
+
+/// Look up the xterm byte sequence for a named key (case-insensitive)
+/// Sequences match the TERM=xterm environment given to the child
+pub fn key_bytes(name: &str) -> Option<&'static [u8]> {
+    let bytes: &'static [u8] = match name.to_ascii_lowercase().as_str() {
+        "up" => b"\x1b[A",
+        "down" => b"\x1b[B",
+        "right" => b"\x1b[C",
+        "left" => b"\x1b[D",
+        "home" => b"\x1b[H",
+        "end" => b"\x1b[F",
+        "insert" => b"\x1b[2~",
+        "delete" => b"\x1b[3~",
+        "pageup" => b"\x1b[5~",
+        "pagedown" => b"\x1b[6~",
+        "enter" => b"\r",
+        "tab" => b"\t",
+        "esc" => b"\x1b",
+        "backspace" => b"\x7f",
+        "space" => b" ",
+        "lt" => b"<",
+        "f1" => b"\x1bOP",
+        "f2" => b"\x1bOQ",
+        "f3" => b"\x1bOR",
+        "f4" => b"\x1bOS",
+        "f5" => b"\x1b[15~",
+        "f6" => b"\x1b[17~",
+        "f7" => b"\x1b[18~",
+        "f8" => b"\x1b[19~",
+        "f9" => b"\x1b[20~",
+        "f10" => b"\x1b[21~",
+        "f11" => b"\x1b[23~",
+        "f12" => b"\x1b[24~",
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+/// Resolve a `C-x` control chord to its control byte (e.g. C-c -> 0x03)
+pub fn control_byte(name: &str) -> Option<u8> {
+    let key = name
+        .strip_prefix("C-")
+        .or_else(|| name.strip_prefix("c-"))?;
+    match key.as_bytes() {
+        [c @ (b'a'..=b'z' | b'A'..=b'Z')] => Some(c.to_ascii_lowercase() & 0x1f),
+        [c @ (b'@' | b'[' | b'\\' | b']' | b'^' | b'_')] => Some(c & 0x1f),
+        _ => None,
+    }
+}
+
+/// Expand `<Name>` key tokens in keyboard input to their byte sequences
+/// A literal '<' is written as `<lt>` or `\<`; everything else passes through
+pub fn expand_key_tokens(data: &[u8]) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i] == b'\\' && data.get(i + 1) == Some(&b'<') {
+            result.push(b'<');
+            i += 2;
+        } else if data[i] == b'<' {
+            i = expand_key_token(data, i, &mut result)?;
+        } else {
+            result.push(data[i]);
+            i += 1;
+        }
+    }
+
+    Ok(result)
+}
+
+/// Expand the `<Name>` token starting at `data[start]` into `result`,
+/// returning the index just past it
+fn expand_key_token(data: &[u8], start: usize, result: &mut Vec<u8>) -> Result<usize> {
+    let end = data[start..]
+        .iter()
+        .position(|&b| b == b'>')
+        .map(|offset| start + offset)
+        .ok_or_else(|| anyhow::anyhow!("Unterminated key token at byte {}", start))?;
+    let name = std::str::from_utf8(&data[start + 1..end])
+        .with_context(|| format!("Invalid key token at byte {}", start))?;
+
+    if let Some(bytes) = key_bytes(name) {
+        result.extend_from_slice(bytes);
+    } else if let Some(byte) = control_byte(name) {
+        result.push(byte);
+    } else {
+        anyhow::bail!("Unknown key token <{}> at byte {}", name, start);
+    }
+    Ok(end + 1)
+}
+
+/// Split keyboard input into the bytes of each key, for typing them one at
+/// a time: an escape sequence (CSI, SS3 or Alt+key), a UTF-8 character,
+/// CR LF, or else a single byte
+/// A CSI sequence cut off by the end of `data` is kept whole as the last key
+pub fn split_keys(data: &[u8]) -> Vec<&[u8]> {
+    let mut keys = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (key, after) = rest.split_at(key_len(rest).min(rest.len()));
+        keys.push(key);
+        rest = after;
+    }
+    keys
+}
+
+/// Length of the key `data` starts with (see `split_keys`), possibly past
+/// its end
+fn key_len(data: &[u8]) -> usize {
+    match data {
+        // CSI: parameters and intermediates up to a final byte
+        [0x1b, b'[', rest @ ..] => rest
+            .iter()
+            .position(|b| (0x40..=0x7e).contains(b))
+            .map_or(data.len(), |end| end + 3),
+        // SS3, e.g. ESC O P for F1
+        [0x1b, b'O', _, ..] => 3,
+        // Alt+key
+        [0x1b, 0x20..=0x7e, ..] => 2,
+        [b'\r', b'\n', ..] => 2,
+        [0xc0..=0xdf, ..] => 2,
+        [0xe0..=0xef, ..] => 3,
+        [0xf0..=0xf7, ..] => 4,
+        _ => 1,
+    }
+}
+
+/// Translate escaped keyboard text into bytes: backslash escapes as in
+/// `decode_escapes` (\e, \x1b, \r, \n, \t, \\) plus `<Name>` key tokens
+/// as in `expand_key_tokens`, with `\<` or `<lt>` for a literal '<'
+pub fn parse_key_sequence(text: &str) -> Result<Vec<u8>> {
+    let data = text.as_bytes();
+    let mut result = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        match (data[i], data.get(i + 1)) {
+            (b'\\', Some(b'<')) => {
+                result.push(b'<');
+                i += 2;
+            }
+            (b'\\', escape) => {
+                // \x takes two hex digits, every other escape one character
+                let len = if escape == Some(&b'x') { 4 } else { 2 };
+                let end = (i + len).min(data.len());
+                let escaped = text
+                    .get(i..end)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid escape at byte {}", i))?;
+                result.extend(decode_escapes(escaped).with_context(|| format!("At byte {}", i))?);
+                i = end;
+            }
+            (b'<', _) => i = expand_key_token(data, i, &mut result)?,
+            (byte, _) => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_bytes_decode() {
+        assert_eq!(decode_hex_bytes("1b 5b 41").unwrap(), b"\x1b[A");
+        assert_eq!(decode_hex_bytes("1b5b41\n0d").unwrap(), b"\x1b[A\r");
+        assert_eq!(decode_hex_bytes(" \n").unwrap(), b"");
+        let err = decode_hex_bytes("1b 5").unwrap_err();
+        assert_eq!(err.to_string(), "Odd number of hex digits in \"5\"");
+        let err = decode_hex_bytes("1b zz").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid hex byte \"zz\" in \"zz\"");
+    }
+
+    #[test]
+    fn keys_keep_escape_sequences_whole() {
+        let keys = split_keys(b"a\x1b[A\x1b[1;5C\x1bOP\x1bx\x1b\x1b\r\n\xc3\xa9\x1b[2");
+        let expected: [&[u8]; 10] = [
+            b"a",
+            b"\x1b[A",
+            b"\x1b[1;5C",
+            b"\x1bOP",
+            b"\x1bx",
+            b"\x1b",
+            b"\x1b",
+            b"\r\n",
+            "\u{e9}".as_bytes(),
+            b"\x1b[2",
+        ];
+        assert_eq!(keys, expected);
+        assert!(split_keys(b"").is_empty());
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        let data: Vec<u8> = (0..=255).chain(*b"a \\ ").collect();
+        let text = encode_escapes(&data);
+        assert!(!text.ends_with(' '));
+        assert_eq!(decode_escapes(&text).unwrap(), data);
+        assert_eq!(encode_escapes(b"ls -l\r\x1b[A"), "ls -l\\r\\e[A");
+    }
+
+    #[test]
+    fn typed_keys_become_a_script() {
+        let keys = vec![
+            (Duration::from_millis(250), b"ls\r".to_vec()),
+            (Duration::from_millis(250), b"\x1b[A".to_vec()),
+            (Duration::from_millis(1300), b"q".to_vec()),
+        ];
+        let script = format_keyboard_script(&keys, true);
+        assert_eq!(
+            script,
+            "sleep 250\nsend ls\\r\nsend \\e[A\nsleep 1050\nsend q\n"
+        );
+        let steps = parse_keyboard_script(&script).unwrap();
+        assert_eq!(steps[1], ScriptStep::Send(b"ls\r".to_vec()));
+        assert_eq!(steps[4], ScriptStep::Send(b"q".to_vec()));
+
+        let untimed = format_keyboard_script(&keys, false);
+        assert_eq!(untimed, "send ls\\r\nsend \\e[A\nsend q\n");
+    }
+
+    #[test]
+    fn keyboard_script_commands() {
+        let steps = parse_keyboard_script(
+            "send a\\r\nsend-hex 1b5b41\nwait 5\nsleep 6\nexpect > \nkey Down enter C-c",
+        )
+        .unwrap();
+        assert!(matches!(&steps[0], ScriptStep::Send(data) if data == b"a\r"));
+        assert!(matches!(&steps[1], ScriptStep::Send(data) if data == b"\x1b[A"));
+        assert!(matches!(steps[2], ScriptStep::Sleep(5)));
+        assert!(matches!(steps[3], ScriptStep::Sleep(6)));
+        assert!(matches!(&steps[4], ScriptStep::Expect(text) if text == "> "));
+        assert_eq!(steps[5], ScriptStep::Send(b"\x1b[B\r\x03".to_vec()));
+        assert!(parse_keyboard_script("bogus 1").is_err());
+        assert!(parse_keyboard_script("key Nope").is_err());
+    }
+
+    #[test]
+    fn key_bytes_table() {
+        let cases: &[(&str, &[u8])] = &[
+            ("Up", b"\x1b[A"),
+            ("Down", b"\x1b[B"),
+            ("Right", b"\x1b[C"),
+            ("Left", b"\x1b[D"),
+            ("Home", b"\x1b[H"),
+            ("End", b"\x1b[F"),
+            ("PageUp", b"\x1b[5~"),
+            ("PageDown", b"\x1b[6~"),
+            ("Enter", b"\r"),
+            ("Tab", b"\t"),
+            ("Esc", b"\x1b"),
+            ("Backspace", b"\x7f"),
+            ("F1", b"\x1bOP"),
+            ("F4", b"\x1bOS"),
+            ("F5", b"\x1b[15~"),
+            ("F10", b"\x1b[21~"),
+            ("F11", b"\x1b[23~"),
+            ("F12", b"\x1b[24~"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(key_bytes(name), Some(*expected), "<{}>", name);
+        }
+        assert_eq!(key_bytes("pageup"), key_bytes("PageUp"));
+        assert_eq!(key_bytes("F13"), None);
+    }
+
+    #[test]
+    fn control_chords() {
+        assert_eq!(control_byte("C-c"), Some(0x03));
+        assert_eq!(control_byte("C-d"), Some(0x04));
+        assert_eq!(control_byte("C-A"), Some(0x01));
+        assert_eq!(control_byte("C-["), Some(0x1b));
+        assert_eq!(control_byte("C-1"), None);
+        assert_eq!(control_byte("c"), None);
+    }
+
+    #[test]
+    fn expand_tokens_in_text() {
+        assert_eq!(
+            expand_key_tokens(b"ab<Down><Enter>q<C-c>").unwrap(),
+            b"ab\x1b[B\rq\x03"
+        );
+        assert_eq!(expand_key_tokens(b"1 <lt> 2 \\< 3").unwrap(), b"1 < 2 < 3");
+        assert_eq!(expand_key_tokens(b"no tokens\n").unwrap(), b"no tokens\n");
+    }
+
+    #[test]
+    fn key_sequence_escapes() {
+        let cases: &[(&str, &[u8])] = &[
+            ("\\e", b"\x1b"),
+            ("\\x1b", b"\x1b"),
+            ("\\x41", b"A"),
+            ("\\r", b"\r"),
+            ("\\n", b"\n"),
+            ("\\t", b"\t"),
+            ("\\\\", b"\\"),
+            ("\\<", b"<"),
+            ("<Up>", b"\x1b[A"),
+            ("<Down>", b"\x1b[B"),
+            ("<Enter>", b"\r"),
+            ("<lt>", b"<"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_key_sequence(text).unwrap(), *expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn key_sequence_mixes_text_escapes_and_tokens() {
+        assert_eq!(
+            parse_key_sequence("ab\\e[A<Down>\\x0d<C-c>\n").unwrap(),
+            b"ab\x1b[A\x1b[B\r\x03\n"
+        );
+        assert!(parse_key_sequence("\\x1").is_err());
+        assert!(parse_key_sequence("\\q").is_err());
+        assert!(parse_key_sequence("trailing \\").is_err());
+        assert!(parse_key_sequence("<Nope>").is_err());
+    }
+
+    #[test]
+    fn expand_tokens_rejects_bad_input() {
+        assert!(expand_key_tokens(b"<Nope>").is_err());
+        assert!(expand_key_tokens(b"a < b").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! Stopping runs cleanly when the runner itself is interrupted
+//!
+//! Once `forward_interrupts` has been called, SIGINT and SIGTERM (a console
+//! control event on Windows) no longer end the runner on the spot, which
+//! would leave the program running in its PTY. They are recorded instead:
+//! every run in progress then kills its program, together with the processes
+//! it started, and returns, and runs not yet started fail at once. A second
+//! interrupt exits immediately.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code used for an interrupted runner, as a shell reports SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// True once an interrupt has arrived
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Record an interrupt; returns false if one was already recorded
+fn record_interrupt() -> bool {
+    !INTERRUPTED.swap(true, Ordering::SeqCst)
+}
+
+/// Record SIGINT and SIGTERM instead of exiting at once
+#[cfg(unix)]
+pub fn forward_interrupts() -> io::Result<()> {
+    extern "C" fn on_signal(_signal: libc::c_int) {
+        // Only async-signal-safe calls here: an atomic swap and _exit
+        if !record_interrupt() {
+            // SAFETY: _exit is async-signal-safe and never returns
+            unsafe { libc::_exit(EXIT_INTERRUPTED) }
+        }
+    }
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the action is fully initialized before use, and the
+        // handler only touches an atomic
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // Restart interrupted reads so the PTY reader keeps going
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Record Ctrl-C, Ctrl-Break and console close events instead of exiting at
+/// once
+#[cfg(windows)]
+pub fn forward_interrupts() -> io::Result<()> {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> i32>,
+            add: i32,
+        ) -> i32;
+        fn ExitProcess(exit_code: u32) -> !;
+    }
+
+    unsafe extern "system" fn on_event(_event: u32) -> i32 {
+        if !record_interrupt() {
+            ExitProcess(EXIT_INTERRUPTED as u32);
+        }
+        // Handled: the runner stops its programs and exits by itself
+        1
+    }
+
+    // SAFETY: the handler is a plain function that only touches an atomic
+    if unsafe { SetConsoleCtrlHandler(Some(on_event), 1) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
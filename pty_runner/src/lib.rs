@@ -0,0 +1,24 @@
+//! PTY Runner - Cross-platform terminal state capture
+//!
+//! Runs a program in a PTY with [`run`] (or a reusable [`PtyRunner`]), then renders the captured screen
+//! ([`format`]) or compares it against golden output ([`compare`]).
+
+pub mod attrs;
+pub mod batch;
+pub mod compare;
+pub mod filter;
+pub mod format;
+pub mod input;
+pub mod interrupt;
+pub mod palette;
+mod process_tree;
+pub mod query;
+pub mod record;
+mod runner;
+mod sha256;
+pub mod terminal;
+
+pub use runner::{
+    parse_resize, replay, run, CaptureResult, Frame, InputOrder, LiveOutput, PtyRunner, Resize,
+    RunConfig, RunResult, ScreenPattern, SpawnError, TimeoutKind, SCROLL_COUNT_LIMIT,
+};
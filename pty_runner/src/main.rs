@@ -2,15 +2,47 @@
 //!
 //! Runs a program in a PTY, captures output, and produces hex terminal state.
 //! Uses portable-pty for cross-platform PTY and vt100 for terminal emulation.
+//! This is the command-line front end; the capture logic lives in the library.
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+use pty_runner::batch::{
+    format_junit, format_summary, parse_manifest, run_cases, update_expect_file, write_atomically,
+    ExpectMode,
+};
+use pty_runner::compare::{
+    compare_output, format_ansi_cells, format_cell_diff, parse_hex_capture, parse_hex_cells,
+    parse_mask, render_diff, split_hex_header, HexCell, IgnoreFields, Mask,
+};
+use pty_runner::filter::{parse_newline_mode, NewlineMode};
+use pty_runner::format::{
+    format_hash_state, format_hex_cursor, format_hex_scrollback, format_hex_state,
+    format_hex_title, format_html_state, format_json_state, format_report, format_svg_state,
+    format_text_cursor, format_text_history, format_text_scrollback, format_text_state,
+    format_text_title, parse_rgb, screen_formatter, ColorMode, DefaultColors, HexFormatter,
+    HexVersion, JsonDetails, RenderOptions, RunMeta, ScreenFormatter, REPORT_NO_EXIT,
+};
+use pty_runner::input::{
+    decode_hex_bytes, expand_key_tokens, format_keyboard_script, parse_key_sequence,
+    parse_keyboard_script,
+};
+use pty_runner::interrupt::{forward_interrupts, interrupted, EXIT_INTERRUPTED};
+use pty_runner::palette::Palette;
+use pty_runner::record::{
+    decode_base64, encode_base64, format_asciicast, format_timing_csv, parse_raw_log,
+};
+use pty_runner::terminal::RawMode;
+use pty_runner::{
+    parse_resize, replay, run, CaptureResult, Frame, InputOrder, LiveOutput, Resize, RunConfig,
+    ScreenPattern, SpawnError, TimeoutKind,
+};
+use regex::Regex;
+use std::ffi::OsString;
 use std::fs;
-use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::sync::mpsc;
-use std::thread;
+#[cfg(unix)]
+use std::io::IsTerminal;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 /// Set console code page to UTF-8 on Windows
@@ -38,155 +70,154 @@ fn set_utf8_console() {
     // No-op on non-Windows platforms
 }
 
-/// Normalize line endings: ensure all lines end with \r\n (CRLF) for Windows ConPTY
-/// Only used on Windows platforms
-#[cfg(windows)]
-fn normalize_line_endings(data: &[u8]) -> Vec<u8> {
-    let mut result = Vec::new();
-    let mut i = 0;
-    while i < data.len() {
-        if data[i] == b'\r' && i + 1 < data.len() && data[i + 1] == b'\n' {
-            // Already CRLF, keep as is
-            result.push(b'\r');
-            result.push(b'\n');
-            i += 2;
-        } else if data[i] == b'\n' {
-            // LF only, convert to CRLF
-            result.push(b'\r');
-            result.push(b'\n');
-            i += 1;
-        } else if data[i] == b'\r' {
-            // CR only (rare), keep as is
-            result.push(b'\r');
-            i += 1;
-        } else {
-            // Normal character
-            result.push(data[i]);
-            i += 1;
-        }
-    }
-    result
+/// Runner exit code when the child was killed by the timeout
+const EXIT_TIMEOUT: i32 = 124;
+
+/// Runner exit code when the child was killed by the idle timeout
+const EXIT_IDLE_TIMEOUT: i32 = 122;
+
+/// Runner exit code when output does not match the --expect file
+const EXIT_MISMATCH: i32 = 1;
+
+/// Runner exit code when a --wait-for / expect step timed out
+const EXIT_WAIT_TIMEOUT: i32 = 123;
+
+/// Runner exit code when the child wrote more than --max-output-bytes
+const EXIT_OUTPUT_LIMIT: i32 = 121;
+
+/// Runner exit code with --fail-on-unconsumed-input when part of the input
+/// never reached the child
+const EXIT_UNCONSUMED_INPUT: i32 = 120;
+
+/// `pty_runner diff` exit code when the files cannot be compared (as for
+/// diff(1))
+const EXIT_DIFF_TROUBLE: i32 = 2;
+
+/// Runner exit code when the child could not be spawned
+const EXIT_SPAWN_FAILED: i32 = 125;
+
+/// Run every case in a TOML manifest (`pty_runner batch --manifest FILE`)
+#[derive(Parser, Debug)]
+#[command(name = "pty_runner batch")]
+struct BatchArgs {
+    /// Manifest listing the cases as `[[case]]` tables
+    #[arg(long)]
+    manifest: PathBuf,
+
+    /// Also write a JUnit XML report of the cases to this file
+    #[arg(long, value_name = "PATH")]
+    report_junit: Option<PathBuf>,
+
+    /// Rewrite each case's expect file with its new capture instead of
+    /// comparing against it
+    #[arg(long)]
+    update_expect: bool,
+
+    /// With --update-expect, also update after timeouts and crashes
+    #[arg(long, requires = "update_expect")]
+    update_expect_force: bool,
+
+    /// Number of cases to run at once
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
 }
 
-/// Filter out OSC (Operating System Command) sequences
-/// OSC sequences start with ESC ] and end with BEL (0x07) or ESC \
-/// These are often used for window titles and can differ between platforms
-fn filter_osc_sequences(data: &[u8]) -> Vec<u8> {
-    let mut result = Vec::new();
-    let mut i = 0;
-    
-    while i < data.len() {
-        // Check for OSC start: ESC ]
-        if i + 1 < data.len() && data[i] == 0x1b && data[i + 1] == b']' {
-            // Skip until we find BEL (0x07) or ESC \ (0x1b 0x5c)
-            i += 2;
-            while i < data.len() {
-                if data[i] == 0x07 {
-                    // Found BEL terminator
-                    i += 1;
-                    break;
-                } else if i + 1 < data.len() && data[i] == 0x1b && data[i + 1] == b'\\' {
-                    // Found ESC \ terminator
-                    i += 2;
-                    break;
-                }
-                i += 1;
-            }
-        } else {
-            // Normal character, keep it
-            result.push(data[i]);
-            i += 1;
-        }
-    }
-    
-    result
+/// Turn `--output base64` back into the captured bytes
+/// (`pty_runner decode-base64 [FILE]`)
+#[derive(Parser, Debug)]
+#[command(name = "pty_runner decode-base64")]
+struct DecodeArgs {
+    /// File holding the base64 text (standard input if omitted)
+    input: Option<PathBuf>,
 }
 
-/// Normalize erase sequences to use default colors
-/// Windows ConPTY's ESC[K (erase to end of line) uses current colors
-/// Linux PTY doesn't send ESC[K, just ends the line
-/// Insert reset before ESC[K to ensure spaces are written with default colors
-fn normalize_erase_sequences(data: &[u8]) -> Vec<u8> {
-    let mut result = Vec::with_capacity(data.len());
-    let mut i = 0;
-
-    while i < data.len() {
-        // Look for ESC[K (erase to end of line)
-        if i + 2 < data.len() && data[i] == 0x1b && data[i + 1] == b'[' && data[i + 2] == b'K' {
-            // Insert explicit reset before the erase
-            // This ensures spaces are written with default colors on Windows ConPTY
-            result.extend_from_slice(b"\x1b[0;39;49m");
-            // Then add the erase sequence
-            result.extend_from_slice(&data[i..i + 3]);
-            i += 3;
-        } else {
-            result.push(data[i]);
-            i += 1;
-        }
-    }
+/// Emulate saved raw output again without running the program
+/// (`pty_runner replay --input FILE`)
+#[derive(Parser, Debug)]
+#[command(name = "pty_runner replay")]
+struct ReplayArgs {
+    /// Raw output to replay (from --raw-out, --raw-log or --output raw), or
+    /// "-" for standard input
+    #[arg(long)]
+    input: PathBuf,
+
+    /// The input is a --raw-log written with --raw-log-timestamps
+    #[arg(long)]
+    timestamps: bool,
 
-    result
+    /// Terminal rows
+    #[arg(long, default_value = "25")]
+    rows: u16,
+
+    /// Terminal columns
+    #[arg(long, default_value = "80")]
+    cols: u16,
+
+    /// Output format: "hex", "text", "json", "html", "svg", "hash" or "both";
+    /// there is no program, so no exit status is reported ("both" ends in
+    /// `EXIT NONE`)
+    #[arg(short, long, default_value = "hex",
+          value_parser = ["hex", "text", "json", "html", "svg", "hash", "both"])]
+    output: String,
 }
 
-/// Normalize ANSI reset sequence: ESC [m or ESC [0m should always reset to default
-/// On Windows ConPTY, sometimes the foreground color persists after reset
-/// This function ensures reset sequences are followed by explicit default color codes
-fn normalize_reset_sequences(data: &[u8]) -> Vec<u8> {
-    let mut result = Vec::new();
-    let mut i = 0;
-    
-    while i < data.len() {
-        // Check for ESC [ ... m sequence
-        if i + 2 < data.len() && data[i] == 0x1b && data[i + 1] == b'[' {
-            // Find the end of the CSI sequence (ends with 'm')
-            let start = i;
-            i += 2;
-            let mut params = Vec::new();
-            let mut current_num = String::new();
-            
-            while i < data.len() {
-                if data[i] == b';' {
-                    if !current_num.is_empty() {
-                        params.push(current_num.clone());
-                        current_num.clear();
-                    }
-                    i += 1;
-                } else if data[i] == b'm' {
-                    if !current_num.is_empty() {
-                        params.push(current_num.clone());
-                    }
-                    
-                    // Check if this is a reset sequence (no params or param 0)
-                    let is_reset = params.is_empty() || (params.len() == 1 && params[0] == "0");
-                    
-                    if is_reset {
-                        // Replace with explicit reset + set default colors
-                        // ESC[0;39;49m = reset + default foreground + default background
-                        result.extend_from_slice(b"\x1b[0;39;49m");
-                    } else {
-                        // Keep original sequence
-                        result.extend_from_slice(&data[start..=i]);
-                    }
-                    
-                    i += 1;
-                    break;
-                } else if data[i].is_ascii_digit() {
-                    current_num.push(data[i] as char);
-                    i += 1;
-                } else {
-                    // Not a valid SGR sequence, keep original
-                    result.extend_from_slice(&data[start..=i]);
-                    i += 1;
-                    break;
-                }
-            }
-        } else {
-            result.push(data[i]);
-            i += 1;
-        }
-    }
-    
-    result
+/// Compare two hex captures cell by cell
+/// (`pty_runner diff A.hex B.hex`); exits 0 when they match, 1 when they
+/// differ and 2 when they cannot be compared (including a capture with a
+/// truncated cell or text other than the cursor, title and scrollback
+/// trailers)
+#[derive(Parser, Debug)]
+#[command(name = "pty_runner diff")]
+struct DiffArgs {
+    /// First (expected) capture
+    a: PathBuf,
+
+    /// Second (actual) capture
+    b: PathBuf,
+
+    /// Terminal rows both captures were made with
+    #[arg(long, default_value = "25")]
+    rows: u16,
+
+    /// Terminal columns both captures were made with
+    #[arg(long, default_value = "80")]
+    cols: u16,
+
+    /// Compare without the foreground and background colors
+    #[arg(long)]
+    ignore_colors: bool,
+
+    /// Compare without the attributes (bold, underline, ...)
+    #[arg(long)]
+    ignore_attrs: bool,
+
+    /// Maximum number of differing cells listed in detail
+    #[arg(long, default_value = "20")]
+    diff_limit: usize,
+}
+
+/// Render a hex capture in a readable form
+/// (`pty_runner render --input FILE --to text|html|ansi`)
+#[derive(Parser, Debug)]
+#[command(name = "pty_runner render")]
+struct RenderArgs {
+    /// Hex capture to render, or "-" for standard input; a truncated cell or
+    /// text other than the cursor, title and scrollback trailers is an error
+    #[arg(long)]
+    input: PathBuf,
+
+    /// Terminal rows the capture was made with
+    #[arg(long, default_value = "25")]
+    rows: u16,
+
+    /// Terminal columns the capture was made with
+    #[arg(long, default_value = "80")]
+    cols: u16,
+
+    /// "text", "html", or "ansi" (escape sequences that redraw the screen;
+    /// `pty_runner replay` turns them back into the same hex)
+    #[arg(long, default_value = "text", value_parser = ["text", "html", "ansi"])]
+    to: String,
 }
 
 /// PTY Runner for terminal state testing
@@ -197,14 +228,99 @@ struct Args {
     #[arg(short, long)]
     executable: PathBuf,
 
-    /// Path to keyboard input file (escape sequences sent to PTY)
+    /// Path to keyboard input file (escape sequences sent to PTY); repeat to
+    /// send several files in order
     #[arg(short, long)]
-    keyboard_input: Option<PathBuf>,
+    keyboard_input: Vec<PathBuf>,
+
+    /// Milliseconds to wait between repeated --keyboard-input files
+    #[arg(long, default_value = "0", value_name = "MS")]
+    keyboard_delay: u64,
+
+    /// Delay in milliseconds between keyboard input bytes (0 sends line by line)
+    #[arg(long, default_value = "0")]
+    send_delay: u64,
+
+    /// Type keyboard input and script `send` steps at this many keys per
+    /// second, one byte, character or escape sequence at a time
+    #[arg(long, value_name = "CPS", value_parser = clap::value_parser!(u32).range(1..))]
+    type_rate: Option<u32>,
+
+    /// Before sending keyboard input, and between its bytes or lines, wait
+    /// until the program has written nothing for this many milliseconds
+    /// (instead of --startup-delay and the fixed pause between lines)
+    #[arg(long, value_name = "MS")]
+    settle: Option<u64>,
+
+    /// Keyboard input format: "raw" (bytes sent as-is), "tokens" (expand <Up>, <C-c>, ...)
+    /// or "escaped" (tokens plus \e, \x1b, \r, \n, \t escapes)
+    #[arg(long, default_value = "raw")]
+    keyboard_format: String,
+
+    /// After sending --stdin-file, keyboard input and script, signal end of
+    /// input (Ctrl-D at the start of a line; on Windows the PTY input is
+    /// closed) so programs reading until EOF finish
+    #[arg(long)]
+    send_eof: bool,
+
+    /// As --send-eof, but no earlier than this many milliseconds after the
+    /// program started
+    #[arg(long, value_name = "MS")]
+    close_stdin_after: Option<u64>,
+
+    /// Read --keyboard-input and --stdin-file as whitespace-separated hex
+    /// bytes (e.g. "1b 5b 41"), sending the decoded bytes
+    #[arg(long, conflicts_with = "keyboard_format")]
+    input_hex: bool,
+
+    /// Path to keyboard script (`send`, `send-hex`, `key`, `sleep`/`wait`, `expect` lines)
+    #[arg(long, visible_alias = "script", conflicts_with = "keyboard_input")]
+    keyboard_script: Option<PathBuf>,
+
+    /// Wait until this text appears on screen before sending keyboard input
+    #[arg(long)]
+    wait_for: Option<String>,
+
+    /// Treat --wait-for as a regular expression
+    #[arg(long, requires = "wait_for")]
+    wait_regex: bool,
+
+    /// Timeout in milliseconds for each --wait-for / expect step
+    #[arg(long, default_value = "2000")]
+    wait_timeout: u64,
 
     /// Path to stdin file (piped to program's stdin)
     #[arg(short, long)]
     stdin_file: Option<PathBuf>,
 
+    /// Which goes first: "stdin-first" (--stdin-file right after spawning)
+    /// or "keyboard-first" (keyboard input and script, then --stdin-file)
+    #[arg(long, default_value = "stdin-first", value_parser = ["stdin-first", "keyboard-first"])]
+    input_order: String,
+
+    /// Milliseconds to wait after spawning before keyboard input is sent
+    #[arg(long, default_value = "100", value_name = "MS")]
+    startup_delay: u64,
+
+    /// Rewrite newlines in --stdin-file and --keyboard-input: "crlf", "lf",
+    /// "cr" or "raw" (unchanged; default on Unix, "crlf" on Windows)
+    #[arg(long, value_name = "MODE", value_parser = parse_newline_mode)]
+    input_newlines: Option<NewlineMode>,
+
+    /// Send --stdin-file and --keyboard-input bytes verbatim (the same as
+    /// --input-newlines raw)
+    #[arg(long, conflicts_with = "input_newlines")]
+    no_crlf_normalize: bool,
+
+    /// Working directory for the child process (defaults to the runner's cwd)
+    #[arg(long)]
+    working_dir: Option<PathBuf>,
+
+    /// Write the program's stderr to FILE instead of the terminal, so
+    /// diagnostics do not end up on the captured screen (Unix only)
+    #[arg(long, value_name = "FILE")]
+    separate_stderr: Option<PathBuf>,
+
     /// Terminal width
     #[arg(long, default_value = "80")]
     cols: u16,
@@ -213,211 +329,527 @@ struct Args {
     #[arg(long, default_value = "25")]
     rows: u16,
 
-    /// Output format: "hex", "text", or "raw"
+    /// Keep up to N rows that scroll off the top and include them in the
+    /// output (a scrollback section in hex/JSON, lines above a marker in text)
+    /// The hex screen itself stays exactly rows*cols cells; scrollback goes in
+    /// a separate SCROLLBACK line after it, so golden files keep their layout
+    #[arg(long, default_value = "0", value_name = "N")]
+    scrollback: usize,
+
+    /// Print the full history (kept scrollback, then the screen) as text on
+    /// stderr, whatever the output format
+    #[arg(long, default_value = "false")]
+    dump_scrollback: bool,
+
+    /// RGB (RRGGBB) reported for cells using the default foreground color
+    #[arg(long, default_value = "F0F0F0", value_name = "RRGGBB", value_parser = parse_rgb)]
+    default_fg: (u8, u8, u8),
+
+    /// RGB (RRGGBB) reported for cells using the default background color
+    #[arg(long, default_value = "000000", value_name = "RRGGBB", value_parser = parse_rgb)]
+    default_bg: (u8, u8, u8),
+
+    /// RGB of ANSI colors 0-15: "vscode" (default), "xterm", "vga",
+    /// "windows-terminal", or a palette file as for --palette-file; the
+    /// color cube and grayscale ramp (16-255) are standard in every preset
+    #[arg(long, value_name = "NAME|FILE")]
+    palette: Option<String>,
+
+    /// Palette file: 16 (colors 0-15) or 256 (every color) RRGGBB lines, or
+    /// a JSON array of them
+    #[arg(long, value_name = "PATH", conflicts_with = "palette")]
+    palette_file: Option<PathBuf>,
+
+    /// Output format: "hex", "text", "json", "html", "svg", "hash" (SHA-256
+    /// of the hex screen), "raw", "base64"
+    /// (the raw bytes on one line; `pty_runner decode-base64` restores them)
+    /// "both" (text grid, hex dump, cursor and exit code in one report) or
+    /// "diff" (cell report against --expect)
     #[arg(short, long, default_value = "hex")]
     output: String,
 
-    /// Timeout in milliseconds
+    /// Screen to capture: "final" (as the program left it), "alt-last" (the
+    /// alternate screen as last shown, e.g. a full-screen program just
+    /// before it restored the main screen), "main-last" (the main screen as
+    /// last shown) or "both" (alt-last, then main-last; hex or text output
+    /// only, without --expect)
+    #[arg(long, default_value = "final",
+          value_parser = ["final", "alt-last", "main-last", "both"])]
+    capture: String,
+
+    /// Write the output to this file instead of stdout, creating its
+    /// directory; the file is replaced in one step, so a killed run never
+    /// leaves part of a capture behind
+    #[arg(long, value_name = "FILE")]
+    output_file: Option<PathBuf>,
+
+    /// Mirror the program's output as it arrives, to watch it run: to stdout
+    /// with --output-file; otherwise to /dev/tty if stdout is not a terminal,
+    /// or else to stdout, leaving the capture out
+    #[arg(long)]
+    live: bool,
+
+    /// Once all input is sent, hand this terminal to the program: what you
+    /// type goes to it and its output is mirrored as with --live, until
+    /// Ctrl-] ends the session (Ctrl-C, then a kill after --kill-grace or
+    /// 1 s) or the program exits; the timeouts do not apply (Unix only)
+    #[arg(long)]
+    interactive: bool,
+
+    /// Write what was typed during --interactive to this file as a
+    /// --keyboard-script of `send` lines
+    #[arg(long, value_name = "FILE", requires = "interactive")]
+    record_input: Option<PathBuf>,
+
+    /// Keep the pauses between typed keys as `sleep` lines in --record-input
+    #[arg(long, requires = "record_input")]
+    record_timing: bool,
+
+    /// Kill the program after this many milliseconds without output (the
+    /// --timeout still applies as a hard cap)
+    #[arg(long, value_name = "MS")]
+    idle_timeout: Option<u64>,
+
+    /// Resize the terminal to COLSxROWS this many milliseconds after spawn
+    /// (repeatable); the output has the last size set
+    #[arg(long = "resize-at", value_name = "MS:COLSxROWS", value_parser = parse_resize)]
+    resizes: Vec<Resize>,
+
+    /// On timeout, send Ctrl-C and wait this many milliseconds for the
+    /// program to exit before killing it (0 kills at once; so does a closed
+    /// input after --send-eof on Windows, as Ctrl-C cannot be sent then)
+    #[arg(long, default_value = "0", value_name = "MS")]
+    kill_grace: u64,
+
+    /// On timeout, kill only the program itself, not the processes it started
+    #[arg(long, default_value = "false")]
+    no_kill_tree: bool,
+
+    /// Treat going quiet for --idle-timeout as a complete capture: the run
+    /// exits 0 instead of 122 (for programs that wait for input forever)
+    #[arg(long, default_value = "false", requires = "idle_timeout")]
+    exit_on_output_idle: bool,
+
+    /// Width in pixels of one cell in SVG output
+    #[arg(long, default_value = "8")]
+    cell_width: u32,
+
+    /// Height in pixels of one cell in SVG output
+    #[arg(long, default_value = "16")]
+    cell_height: u32,
+
+    /// Timeout in milliseconds; a program still running then is killed, and
+    /// once the capture is written the runner exits 124 (122 for
+    /// --idle-timeout), so a hang never passes for a clean run
     #[arg(short, long, default_value = "5000")]
     timeout: u64,
 
+    /// After the program exits, how long to keep reading while waiting for
+    /// the end of its output, in milliseconds
+    #[arg(long, default_value = "1000")]
+    drain_timeout: u64,
+
+    /// Also record the whole session as an asciicast v2 file (for asciinema)
+    #[arg(long, value_name = "FILE")]
+    record_asciicast: Option<PathBuf>,
+
+    /// Write every chunk of output to FILE as it is read, before any
+    /// filtering, so a run that hangs or crashes still leaves its bytes
+    #[arg(long, value_name = "FILE")]
+    raw_log: Option<PathBuf>,
+
+    /// Put a 12-byte header before each chunk in the --raw-log: microseconds
+    /// since spawn (u64) and the chunk length (u32), little-endian
+    #[arg(long, requires = "raw_log")]
+    raw_log_timestamps: bool,
+
+    /// Keep at most N bytes of output; a program writing more is killed and
+    /// the runner exits with code 121
+    #[arg(long, value_name = "N")]
+    max_output_bytes: Option<usize>,
+
+    /// Exit with code 120 when part of the input never reached the program,
+    /// e.g. because it exited before reading it (a warning with the byte
+    /// offset reached is printed either way)
+    #[arg(long)]
+    fail_on_unconsumed_input: bool,
+
+    /// Write when each chunk of output arrived (ms since spawn) and its size
+    /// to FILE as CSV, for tuning --send-delay and --idle-timeout
+    #[arg(long, value_name = "FILE")]
+    timing_log: Option<PathBuf>,
+
+    /// Write the screen after every chunk of output to numbered files
+    /// (frame-0001.hex, ...) in this directory, in the --output format if it
+    /// is hex, text, html or svg and in hex otherwise
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+
+    /// Snapshot the screen every MS milliseconds while the program runs, in
+    /// the same formats as --record (needs --snapshot-dir)
+    #[arg(long, value_name = "MS", requires = "snapshot_dir")]
+    snapshot_interval: Option<u64>,
+
+    /// Directory for --snapshot-interval snapshots (snapshot-0001.hex, ...)
+    /// and --snapshot-on snapshots (match-01.hex, ... in pattern order)
+    #[arg(long, value_name = "DIR")]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Snapshot the screen the first time PATTERN appears on it (repeatable;
+    /// needs --snapshot-dir)
+    #[arg(long, value_name = "PATTERN", requires = "snapshot_dir")]
+    snapshot_on: Vec<String>,
+
+    /// Treat --snapshot-on patterns as regular expressions
+    #[arg(long, requires = "snapshot_on")]
+    snapshot_regex: bool,
+
+    /// Also save the unfiltered captured bytes to this file, whatever the
+    /// --output format
+    #[arg(long, value_name = "FILE")]
+    raw_out: Option<PathBuf>,
+
     /// Debug: print raw bytes to stderr
     #[arg(long, default_value = "false")]
     debug_raw: bool,
+
+    /// Append the cursor position and visibility to hex/text output
+    #[arg(long, default_value = "false")]
+    cursor: bool,
+
+    /// Set an environment variable for the child (repeatable, overrides TERM)
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_pair)]
+    env_vars: Vec<(String, String)>,
+
+    /// Remove an inherited environment variable from the child (repeatable)
+    #[arg(long, value_name = "KEY")]
+    env_remove: Vec<String>,
+
+    /// TERM for the child (default xterm); programs may pick different escape
+    /// sequences per terminal type, so changing it can change the captured bytes
+    #[arg(long, default_value = "xterm", value_name = "VALUE")]
+    term: String,
+
+    /// Start the child from an empty environment; only TERM, --env variables
+    /// and the SHELL that portable-pty always adds are set (without PATH, the
+    /// executable needs a full path)
+    #[arg(long, default_value = "false")]
+    clear_env: bool,
+
+    /// Report the last window title set with OSC 0/1/2, the icon name of
+    /// OSC 1 counting as a title (a TITLE trailer in
+    /// hex/text, a "title" field in JSON, and a TITLE line on stderr)
+    #[arg(long, default_value = "false")]
+    capture_title: bool,
+
+    /// Pass OSC sequences to the terminal emulator instead of removing them
+    #[arg(long, default_value = "false")]
+    keep_osc: bool,
+
+    /// Do not strip string sequences before emulation: OSC (titles, OSC 8
+    /// hyperlinks, ...), DCS, SOS, PM and APC all reach the terminal emulator
+    #[arg(long, default_value = "false")]
+    no_osc_filter: bool,
+
+    /// Do not answer terminal queries (DA1, DA2, cursor position reports);
+    /// programs waiting for a reply then block until the timeout
+    #[arg(long, default_value = "false")]
+    no_query_replies: bool,
+
+    /// Write run metadata (duration, exit status, byte counts) as JSON to this
+    /// file, even if the program could not be started
+    #[arg(long, value_name = "PATH")]
+    meta_file: Option<PathBuf>,
+
+    /// Compare the output against this file and exit 1 on mismatch
+    #[arg(long)]
+    expect: Option<PathBuf>,
+
+    /// Write the output to the --expect file instead of comparing against it
+    #[arg(long, requires = "expect")]
+    update_expect: bool,
+
+    /// With --update-expect, also update after a timeout or crash
+    #[arg(long, requires = "update_expect")]
+    update_expect_force: bool,
+
+    /// Maximum number of differing cells listed in detail
+    #[arg(long, default_value = "20")]
+    diff_limit: usize,
+
+    /// Ignore a ROW,COL,WIDTH,HEIGHT rectangle in --expect comparisons (repeatable)
+    #[arg(long = "mask", value_name = "ROW,COL,WIDTH,HEIGHT", value_parser = parse_mask)]
+    masks: Vec<Mask>,
+
+    /// Render masked cells as a fixed sentinel in hex/JSON output
+    #[arg(long, default_value = "false")]
+    mask_output: bool,
+
+    /// Colors in hex and JSON output: "rgb" (default, resolved through the
+    /// palette) or "indexed" (the default color, palette index or literal RGB,
+    /// as the program set them; hex output then starts with a header line
+    /// and writes each color as 00000000, 010000II or 02RRGGBB)
+    #[arg(long, default_value = "rgb", value_parser = ["rgb", "indexed"])]
+    color_mode: String,
+
+    /// Hex cell encoding: 1 (the default, so existing goldens keep matching)
+    /// writes the first codepoint of each cell and the column a wide
+    /// character covers as a space; 2 keeps every codepoint of a cell and
+    /// marks both columns of a wide character with attrs bit 80, the second
+    /// with codepoint 00000000
+    #[arg(long, default_value = "1", value_parser = ["1", "2"])]
+    hex_version: String,
+
+    /// Draw this character at the cursor position in text output
+    #[arg(long, value_name = "CHAR")]
+    cursor_marker: Option<char>,
+
+    /// Write the child's exit code to this file ("TIMEOUT", "IDLE_TIMEOUT",
+    /// "OUTPUT_LIMIT" or "INTERRUPTED" if it was killed)
+    #[arg(long)]
+    exit_file: Option<PathBuf>,
+
+    /// Arguments passed to the executable (after `--`)
+    #[arg(last = true)]
+    program_args: Vec<OsString>,
 }
 
 fn main() -> Result<()> {
     // Set UTF-8 console code page on Windows for proper Unicode support
     set_utf8_console();
     
+    // A subcommand, if any, comes first; without one the arguments describe a run
+    let subcommand = std::env::args_os().nth(1);
+    let subcommand_args = || std::env::args_os().skip(1);
+    match subcommand.as_ref().and_then(|arg| arg.to_str()) {
+        Some("batch") => return run_batch(&BatchArgs::parse_from(subcommand_args())),
+        Some("decode-base64") => {
+            return decode_base64_file(&DecodeArgs::parse_from(subcommand_args()))
+        }
+        Some("diff") => return diff_hex_files(&DiffArgs::parse_from(subcommand_args())),
+        Some("render") => return render_hex_file(&RenderArgs::parse_from(subcommand_args())),
+        Some("replay") => return replay_file(&ReplayArgs::parse_from(subcommand_args())),
+        _ => {}
+    }
     let args = Args::parse();
+    stop_runs_on_interrupt();
+    if args.output == "diff" && args.expect.is_none() {
+        anyhow::bail!("--output diff requires --expect");
+    }
+    if args.capture == "both"
+        && (args.expect.is_some() || !matches!(args.output.as_str(), "hex" | "text"))
+    {
+        anyhow::bail!("--capture both requires --output hex or text, without --expect");
+    }
 
     eprintln!("Starting PTY runner...");
     eprintln!("Executable: {:?}", args.executable);
-
-    // Create PTY system
-    let pty_system = native_pty_system();
-
-    // Create PTY pair with specified size
-    let pair = pty_system
-        .openpty(PtySize {
-            rows: args.rows,
-            cols: args.cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        })
-        .context("Failed to open PTY")?;
-
-    eprintln!("PTY opened successfully");
-
-    // Build command with consistent TERM environment
-    let mut cmd = CommandBuilder::new(&args.executable);
-    cmd.env("TERM", "xterm"); // Ensure consistent terminal type across platforms
-    
-    // On Windows, set environment variable to hint UTF-8 encoding
-    // Note: This may not fully solve ConPTY code page issues, but helps with some programs
-    #[cfg(windows)]
-    {
-        cmd.env("CHCP", "65001");
-        cmd.env("LANG", "en_US.UTF-8");
-        cmd.env("LC_ALL", "en_US.UTF-8");
+    if !args.program_args.is_empty() {
+        eprintln!("Arguments: {:?}", args.program_args);
     }
 
-    // Spawn child process in PTY
-    let mut child = pair
-        .slave
-        .spawn_command(cmd)
-        .context("Failed to spawn command")?;
-
-    eprintln!("Child process spawned");
-
-    // Get master for I/O
-    let master = pair.master;
-
-    // Note: Both Windows ConPTY and Unix PTY echo stdin by default
-    // This is the expected behavior - students see what they type
-    // Golden files should include echoed stdin for realistic testing
+    // Read every keyboard input file up front
+    let keyboard_input = args
+        .keyboard_input
+        .iter()
+        .map(|kb_path| {
+            let data = read_input_file(kb_path, "keyboard input", args.input_hex)?;
+            match args.keyboard_format.as_str() {
+                "raw" => Ok(data),
+                "tokens" => expand_key_tokens(&data)
+                    .with_context(|| format!("Invalid keyboard input: {:?}", kb_path)),
+                "escaped" => {
+                    let text = String::from_utf8(data)
+                        .with_context(|| format!("Keyboard input is not UTF-8: {:?}", kb_path))?;
+                    parse_key_sequence(&text)
+                        .with_context(|| format!("Invalid keyboard input: {:?}", kb_path))
+                }
+                other => anyhow::bail!("Unknown keyboard format: {:?}", other),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    // Read keyboard input if provided
-    let keyboard_input = if let Some(kb_path) = &args.keyboard_input {
+    // Parse keyboard script up front so syntax errors are reported before running
+    let keyboard_script = if let Some(script_path) = &args.keyboard_script {
+        let text = fs::read_to_string(script_path)
+            .with_context(|| format!("Failed to read keyboard script: {:?}", script_path))?;
         Some(
-            fs::read(kb_path)
-                .with_context(|| format!("Failed to read keyboard input: {:?}", kb_path))?,
+            parse_keyboard_script(&text)
+                .with_context(|| format!("Invalid keyboard script: {:?}", script_path))?,
         )
     } else {
         None
     };
 
-    // Create vt100 parser for terminal emulation
-    let mut parser = vt100::Parser::new(args.rows, args.cols, 0);
-
-    // Clone reader for output capture thread
-    let mut reader = master
-        .try_clone_reader()
-        .context("Failed to clone PTY reader")?;
-
-    // Get writer for sending input
-    let mut writer = master
-        .take_writer()
-        .context("Failed to get PTY writer")?;
-
-    // Use a channel to communicate output chunks from the reader thread
-    let (tx, rx) = mpsc::channel::<Vec<u8>>();
-
-    // Spawn thread to read output (this thread may block indefinitely on Windows)
-    let _output_handle = thread::spawn(move || {
-        let mut buf = [0u8; 4096];
-        loop {
-            match reader.read(&mut buf) {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    if tx.send(buf[..n].to_vec()).is_err() {
-                        break; // Receiver dropped
-                    }
-                }
-                Err(_) => break, // Error, likely PTY closed
-            }
-        }
-    });
-
-    // Send stdin content if provided
-    if let Some(stdin_path) = &args.stdin_file {
-        let stdin_content = fs::read(stdin_path)?;
-        // On Windows, normalize LF to CRLF for scanf compatibility
-        // On Unix, keep LF as-is (Unix terminals expect LF)
-        #[cfg(windows)]
-        let data_to_send = normalize_line_endings(&stdin_content);
-        #[cfg(not(windows))]
-        let data_to_send = stdin_content;
-        writer.write_all(&data_to_send)?;
-    }
-
-    // Small delay to let program start
-    thread::sleep(Duration::from_millis(100));
-
-    // Send keyboard input if provided, line by line with delays
-    // This ensures proper echo timing across platforms
-    if let Some(kb_data) = keyboard_input {
-        // Split by newlines and send each line separately with a delay
-        // This gives the program time to process each input and echo before next input
-        let mut start_idx = 0;
-        for (i, &byte) in kb_data.iter().enumerate() {
-            if byte == b'\n' {
-                // Include the newline in this chunk
-                let line = &kb_data[start_idx..=i];
-                
-                #[cfg(windows)]
-                let data_to_send = normalize_line_endings(line);
-                #[cfg(not(windows))]
-                let data_to_send = line.to_vec();
-                
-                writer.write_all(&data_to_send)?;
-                writer.flush()?;
-                
-                // Delay between lines to allow program to process and echo
-                thread::sleep(Duration::from_millis(50));
-                
-                start_idx = i + 1;
-            }
-        }
-        // Send any remaining data after the last newline
-        if start_idx < kb_data.len() {
-            let remaining = &kb_data[start_idx..];
-            
-            #[cfg(windows)]
-            let data_to_send = normalize_line_endings(remaining);
-            #[cfg(not(windows))]
-            let data_to_send = remaining.to_vec();
-            
-            writer.write_all(&data_to_send)?;
-        }
-    }
+    let palette = match (&args.palette, &args.palette_file) {
+        (_, Some(path)) => Palette::from_file(path)?,
+        (Some(name), None) => Palette::load(name)?,
+        (None, None) => Palette::default(),
+    };
 
-    // Wait for child with timeout
-    let timeout = Duration::from_millis(args.timeout);
-    let start = std::time::Instant::now();
+    let wait_for = match &args.wait_for {
+        Some(pattern) if args.wait_regex => Some(ScreenPattern::Regex(
+            Regex::new(pattern)
+                .with_context(|| format!("Invalid --wait-for regex: {:?}", pattern))?,
+        )),
+        Some(text) => Some(ScreenPattern::Text(text.clone())),
+        None => None,
+    };
+    let snapshot_patterns = args
+        .snapshot_on
+        .iter()
+        .map(|pattern| {
+            Ok(if args.snapshot_regex {
+                ScreenPattern::Regex(
+                    Regex::new(pattern)
+                        .with_context(|| format!("Invalid --snapshot-on regex: {:?}", pattern))?,
+                )
+            } else {
+                ScreenPattern::Text(pattern.clone())
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    loop {
-        match child.try_wait() {
-            Ok(Some(_status)) => {
-                eprintln!("Child process exited");
-                break; // Process exited
-            }
-            Ok(None) => {
-                if start.elapsed() > timeout {
-                    eprintln!("Timeout reached, killing process");
-                    // Kill the process
-                    let _ = child.kill();
-                    break;
-                }
-                thread::sleep(Duration::from_millis(50));
-            }
-            Err(_) => break,
-        }
-    }
+    let (live, live_on_stdout) = live_output(&args);
+    let stdin = match &args.stdin_file {
+        Some(stdin_path) => Some(read_input_file(stdin_path, "stdin file", args.input_hex)?),
+        None => None,
+    };
 
-    // Give more time for any final output and to drain the channel
-    thread::sleep(Duration::from_millis(200));
+    let config = RunConfig {
+        executable: args.executable.clone(),
+        args: args.program_args.clone(),
+        working_dir: args.working_dir.clone(),
+        term: args.term.clone(),
+        clear_env: args.clear_env,
+        env: args.env_vars.clone(),
+        env_remove: args.env_remove.clone(),
+        cols: args.cols,
+        rows: args.rows,
+        scrollback: args.scrollback,
+        stdin,
+        input_newlines: match args.input_newlines {
+            _ if args.no_crlf_normalize => NewlineMode::Raw,
+            Some(mode) => mode,
+            None => NewlineMode::platform_default(),
+        },
+        keyboard_input,
+        keyboard_delay: Duration::from_millis(args.keyboard_delay),
+        send_delay: Duration::from_millis(args.send_delay),
+        type_interval: args
+            .type_rate
+            .map(|rate| Duration::from_secs_f64(1.0 / rate as f64)),
+        settle: args.settle.map(Duration::from_millis),
+        input_order: if args.input_order == "keyboard-first" {
+            InputOrder::KeyboardFirst
+        } else {
+            InputOrder::StdinFirst
+        },
+        startup_delay: Duration::from_millis(args.startup_delay),
+        keyboard_script,
+        send_eof: match args.close_stdin_after {
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => args.send_eof.then_some(Duration::ZERO),
+        },
+        wait_for,
+        wait_timeout: Duration::from_millis(args.wait_timeout),
+        timeout: Duration::from_millis(args.timeout),
+        idle_timeout: args.idle_timeout.map(Duration::from_millis),
+        kill_grace: Duration::from_millis(args.kill_grace),
+        kill_tree: !args.no_kill_tree,
+        drain_timeout: Duration::from_millis(args.drain_timeout),
+        keep_osc: args.keep_osc,
+        osc_filter: !args.no_osc_filter,
+        record_frames: args.record.is_some(),
+        snapshot_interval: args.snapshot_interval.map(Duration::from_millis),
+        snapshot_patterns,
+        query_replies: !args.no_query_replies,
+        stderr_file: args.separate_stderr.clone(),
+        log_prefix: String::new(),
+        resizes: args.resizes.clone(),
+        raw_log: args.raw_log.clone(),
+        raw_log_timestamps: args.raw_log_timestamps,
+        live,
+        max_output_bytes: args.max_output_bytes,
+        interactive: args.interactive,
+    };
 
-    // Collect all output received so far (with a timeout per chunk)
-    let mut output = Vec::new();
-    let collect_deadline = std::time::Instant::now() + Duration::from_millis(300);
-    while std::time::Instant::now() < collect_deadline {
-        match rx.try_recv() {
-            Ok(chunk) => output.extend(chunk),
-            Err(mpsc::TryRecvError::Empty) => {
-                // No data yet, wait a bit
-                thread::sleep(Duration::from_millis(10));
-            }
-            Err(mpsc::TryRecvError::Disconnected) => break,
+    let mut meta = RunMeta {
+        executable: args.executable.display().to_string(),
+        args: args
+            .program_args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        rows: args.rows,
+        cols: args.cols,
+        ..Default::default()
+    };
+    // Raw mode lasts for the run only, and ends before any exit below
+    let raw_mode = if args.interactive {
+        eprintln!("Interactive session: press Ctrl-] to end it");
+        Some(RawMode::enable().context("--interactive needs a terminal on standard input")?)
+    } else {
+        None
+    };
+    let result = run(&config);
+    drop(raw_mode);
+    let result = match result {
+        Ok(result) => result,
+        Err(e) if e.is::<SpawnError>() => {
+            eprintln!("Error: {:#}", e);
+            meta.spawn_error = Some(format!("{:#}", e));
+            write_meta_file(args.meta_file.as_ref(), &meta)?;
+            std::process::exit(EXIT_SPAWN_FAILED);
         }
+        Err(e) => return Err(e),
+    };
+    meta.duration_ms = Some(result.duration.as_millis() as u64);
+    meta.run_time_ms = Some(result.run_time.as_millis() as u64);
+    meta.timed_out = result
+        .timed_out
+        .is_some_and(|kind| kind != TimeoutKind::Ended);
+    meta.output_truncated = result.truncated;
+    meta.alternate_screen = result.screen.alternate_screen();
+    meta.alternate_screen_used = result.alternate_screen_used;
+    // Machine-readable, with a flag when only the kill ended the run
+    eprintln!(
+        "DURATION_MS {}{}",
+        result.run_time.as_millis(),
+        if meta.timed_out { " TIMED_OUT" } else { "" }
+    );
+    // Which of the main and alternate screens the capture shows
+    eprintln!("ALT_SCREEN {}", meta.alternate_screen);
+    meta.exit_code = result.exit_status.as_ref().map(|status| status.exit_code());
+    meta.signal = result.signal();
+    meta.bytes_captured = Some(result.output.len());
+    meta.bytes_filtered = Some(result.filtered_len);
+    meta.input_bytes_sent = Some(result.input_sent);
+    meta.input_bytes_written = Some(result.input_written);
+    meta.input_error = result.input_error.clone();
+    write_meta_file(args.meta_file.as_ref(), &meta)?;
+    if let Some(path) = &args.record_input {
+        fs::write(
+            path,
+            format_keyboard_script(&result.typed, args.record_timing),
+        )
+        .with_context(|| format!("Failed to write recorded input: {:?}", path))?;
+        let typed: usize = result.typed.iter().map(|(_, keys)| keys.len()).sum();
+        eprintln!("Recorded {} bytes of input to {:?}", typed, path);
     }
-
-    // Drop writer and master (but don't wait for reader thread - it may hang on Windows)
-    drop(writer);
-    drop(master);
-
-    eprintln!("Captured {} bytes of output", output.len());
+    let output = &result.output;
+    let captures = captured_screens(&args.capture, &result);
+    let (screen, extra_attrs) = captures[0];
+    // The size last set with --resize-at, if any
+    let (rows, cols) = screen.size();
+    let exit_status = &result.exit_status;
+    // Only emitted when requested so default output stays unchanged
+    let scrollback = Some(result.scrollback.as_slice()).filter(|_| args.scrollback > 0);
+    let title = result.title.as_deref().filter(|_| args.capture_title);
+    if let Some(title) = title {
+        eprint!("{}", format_text_title(title));
+    }
+    let wait_ok = !result.wait_timed_out;
 
     // Debug: print raw bytes if requested
     if args.debug_raw {
@@ -431,140 +863,658 @@ fn main() -> Result<()> {
         eprintln!();
     }
 
-    // Filter out OS-specific sequences (e.g., window title OSC from Windows ConPTY)
-    let filtered = filter_osc_sequences(&output);
-    eprintln!("After filtering OSC: {} bytes", filtered.len());
+    if let Some(raw_path) = &args.raw_out {
+        write_atomically(raw_path, output)
+            .with_context(|| format!("Failed to write raw output: {:?}", raw_path))?;
+    }
 
-    // Normalize erase sequences to use default colors (Windows ConPTY issue)
-    let erase_normalized = normalize_erase_sequences(&filtered);
-    eprintln!("After normalizing erases: {} bytes", erase_normalized.len());
+    if let Some(cast_path) = &args.record_asciicast {
+        let cast = format_asciicast(
+            output,
+            &result.chunks,
+            args.cols,
+            args.rows,
+            result.started_at,
+        )?;
+        fs::write(cast_path, cast)
+            .with_context(|| format!("Failed to write asciicast: {:?}", cast_path))?;
+    }
 
-    // Normalize ANSI reset sequences for cross-platform consistency
-    let normalized = normalize_reset_sequences(&erase_normalized);
-    eprintln!("After normalizing resets: {} bytes", normalized.len());
+    if let Some(timing_path) = &args.timing_log {
+        fs::write(timing_path, format_timing_csv(&result.chunks))
+            .with_context(|| format!("Failed to write timing log: {:?}", timing_path))?;
+    }
 
-    // Process output through terminal emulator
-    parser.process(&normalized);
+    if args.dump_scrollback {
+        if args.scrollback == 0 {
+            eprintln!("Note: --dump-scrollback without --scrollback shows the screen only");
+        }
+        eprintln!("Scrollback history:");
+        eprint!("{}", format_text_history(&result.scrollback, screen, cols));
+    }
 
     // Generate output based on format
-    if args.output == "hex" {
-        print_hex_state(&parser, args.rows, args.cols);
+    let defaults = DefaultColors {
+        fg: args.default_fg,
+        bg: args.default_bg,
+    };
+    let color_mode = if args.color_mode == "indexed" {
+        ColorMode::Indexed
+    } else {
+        ColorMode::Rgb
+    };
+    let hex_version = if args.hex_version == "1" {
+        HexVersion::V1
+    } else {
+        HexVersion::V2
+    };
+    // Comparisons always see real cell contents; masks are applied by the comparison
+    let compare_options = RenderOptions {
+        defaults,
+        palette,
+        hex_version,
+        color_mode,
+        extra_attrs: Some(extra_attrs.clone()),
+        scrollback_extra_attrs: Some(result.scrollback_extra_attrs.clone()),
+        ..Default::default()
+    };
+    let options = RenderOptions {
+        defaults,
+        palette,
+        masks: if args.mask_output {
+            args.masks.clone()
+        } else {
+            Vec::new()
+        },
+        cursor_marker: args.cursor_marker,
+        extra_attrs: Some(extra_attrs.clone()),
+        scrollback_extra_attrs: Some(result.scrollback_extra_attrs.clone()),
+        hex_version,
+        color_mode,
+    };
+    // Frames use the output format when it renders a screen, hex otherwise
+    let frame_formatter = screen_formatter(&args.output, (args.cell_width, args.cell_height))
+        .unwrap_or_else(|| Box::new(HexFormatter));
+    if let Some(record_dir) = &args.record {
+        write_frames(
+            record_dir,
+            "frame",
+            &result.frames,
+            &*frame_formatter,
+            &options,
+        )?;
+        eprintln!(
+            "Recorded {} frames in {:?}",
+            result.frames.len(),
+            record_dir
+        );
+    }
+    if let Some(snapshot_dir) = &args.snapshot_dir {
+        write_frames(
+            snapshot_dir,
+            "snapshot",
+            &result.snapshots,
+            &*frame_formatter,
+            &options,
+        )?;
+        if args.snapshot_interval.is_some() {
+            eprintln!(
+                "Wrote {} snapshots to {:?}",
+                result.snapshots.len(),
+                snapshot_dir
+            );
+        }
+        let mut frame_options = options.clone();
+        for (i, (pattern, found)) in config
+            .snapshot_patterns
+            .iter()
+            .zip(&result.pattern_snapshots)
+            .enumerate()
+        {
+            let Some(frame) = found else {
+                eprintln!("Pattern {} {}: never appeared", i + 1, pattern);
+                continue;
+            };
+            let path = snapshot_dir.join(format!(
+                "match-{:02}.{}",
+                i + 1,
+                frame_formatter.extension()
+            ));
+            frame_options.extra_attrs = Some(frame.extra_attrs.clone());
+            let (frame_rows, frame_cols) = frame.screen.size();
+            fs::write(
+                &path,
+                frame_formatter.format(&frame.screen, frame_rows, frame_cols, &frame_options),
+            )
+            .with_context(|| format!("Failed to write snapshot: {:?}", path))?;
+            eprintln!(
+                "Pattern {} {}: appeared at {} ms, saved to {:?}",
+                i + 1,
+                pattern,
+                frame.elapsed.as_millis(),
+                path
+            );
+        }
+    }
+    // With --capture both, each screen in turn, formatted the same way
+    let mut capture_options = options.clone();
+    let mut format_captures = |format: &dyn Fn(&vt100::Screen, &RenderOptions) -> String| {
+        let mut out = String::new();
+        for &(screen, extra_attrs) in &captures {
+            capture_options.extra_attrs = Some(extra_attrs.clone());
+            out.push_str(&format(screen, &capture_options));
+        }
+        out
+    };
+    let formatted = if args.output == "hex" {
+        let mut out = format_captures(&|screen, options| {
+            let (rows, cols) = screen.size();
+            let mut out = format_hex_state(screen, rows, cols, options);
+            if args.cursor {
+                out.push_str(&format_hex_cursor(screen));
+            }
+            out
+        });
+        if let Some(rows) = scrollback {
+            out.push_str(&format_hex_scrollback(rows, &options));
+        }
+        if let Some(title) = title {
+            out.push_str(&format_hex_title(title));
+        }
+        out.into_bytes()
     } else if args.output == "text" {
-        print_text_state(&parser, args.rows, args.cols);
+        let mut out = scrollback.map(format_text_scrollback).unwrap_or_default();
+        out.push_str(&format_captures(&|screen, options| {
+            let (rows, cols) = screen.size();
+            let mut out = format_text_state(screen, rows, cols, options);
+            if args.cursor {
+                out.push_str(&format_text_cursor(screen));
+            }
+            out
+        }));
+        if let Some(title) = title {
+            out.push_str(&format_text_title(title));
+        }
+        out.into_bytes()
+    } else if args.output == "json" {
+        format_json_state(
+            screen,
+            rows,
+            cols,
+            &options,
+            &JsonDetails {
+                exit_status: exit_status.as_ref(),
+                timed_out: exit_status.is_none(),
+                scrollback,
+                title,
+                meta: Some(&meta),
+            },
+        )?
+        .into_bytes()
+    } else if args.output == "html" {
+        format_html_state(screen, rows, cols, &options).into_bytes()
+    } else if args.output == "hash" {
+        format_hash_state(screen, rows, cols, &options).into_bytes()
+    } else if args.output == "both" {
+        format_report(screen, rows, cols, &options, &result.exit_label()).into_bytes()
+    } else if args.output == "svg" {
+        format_svg_state(
+            screen,
+            rows,
+            cols,
+            &options,
+            (args.cell_width, args.cell_height),
+        )
+        .into_bytes()
+    } else if args.output == "diff" {
+        // Cell-level report against the --expect file (required in this mode)
+        let expect_path = args
+            .expect
+            .as_ref()
+            .context("--output diff requires --expect")?;
+        let expected = fs::read_to_string(expect_path)
+            .with_context(|| format!("Failed to read expected output: {:?}", expect_path))?;
+        let expected_cells = parse_hex_capture(&expected)
+            .with_context(|| format!("Invalid hex in {:?}", expect_path))?;
+        let actual_cells =
+            parse_hex_cells(&format_hex_state(screen, rows, cols, &compare_options))?;
+        format_cell_diff(
+            &expected_cells,
+            &actual_cells,
+            rows,
+            cols,
+            &args.masks,
+            args.diff_limit,
+        )
+        .into_bytes()
     } else if args.output == "raw" {
         // Just output the raw bytes
-        std::io::stdout().write_all(&output)?;
+        output.clone()
+    } else if args.output == "base64" {
+        format!("{}\n", encode_base64(output)).into_bytes()
+    } else {
+        Vec::new()
+    };
+    if let Some(path) = &args.output_file {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory: {:?}", dir))?;
+        }
+        write_atomically(path, &formatted)
+            .with_context(|| format!("Failed to write output: {:?}", path))?;
+    } else if !live_on_stdout {
+        let mut stdout = BufWriter::new(std::io::stdout().lock());
+        stdout.write_all(&formatted)?;
+        stdout.flush()?;
     }
 
-    // Exit explicitly since the reader thread may still be blocking
-    std::process::exit(0);
-}
-
-/// Print terminal state as hex format
-/// Format: 22 chars per cell = 8 (codepoint) + 6 (fg RGB) + 6 (bg RGB) + 2 (attrs)
-fn print_hex_state(parser: &vt100::Parser, rows: u16, cols: u16) {
-    let screen = parser.screen();
+    // Compare against the expected output if requested
+    let mut matched = true;
+    if let (true, Some(expect_path)) = (args.update_expect, &args.expect) {
+        // The diff report is not a golden file; keep the hex it was built from
+        let data = if args.output == "diff" {
+            format_hex_state(screen, rows, cols, &compare_options).into_bytes()
+        } else {
+            formatted
+        };
+        if update_expect_file(expect_path, &data, &result, args.update_expect_force)? {
+            eprintln!("Updated {:?}", expect_path);
+        } else {
+            eprintln!("Already up to date: {:?}", expect_path);
+        }
+    } else if let Some(expect_path) = &args.expect {
+        let expected = fs::read(expect_path)
+            .with_context(|| format!("Failed to read expected output: {:?}", expect_path))?;
+        // The diff report itself is not comparable; compare the hex it was built from
+        let differences = if args.output == "diff" {
+            let hex = format_hex_state(screen, rows, cols, &compare_options);
+            compare_output("hex", &expected, hex.as_bytes(), cols, &args.masks)
+        } else {
+            compare_output(&args.output, &expected, &formatted, cols, &args.masks)
+        };
+        if differences.is_empty() {
+            eprintln!("Output matches {:?}", expect_path);
+        } else {
+            matched = false;
+            eprintln!("Output differs from {:?}:", expect_path);
+            for line in differences.iter().take(args.diff_limit) {
+                eprintln!("  {}", line);
+            }
+            if differences.len() > args.diff_limit {
+                eprintln!("  ... and {} more", differences.len() - args.diff_limit);
+            }
+            eprintln!("{} difference(s) in total", differences.len());
+            if args.output == "hex" || args.output == "diff" {
+                let hex = format_hex_state(screen, rows, cols, &compare_options);
+                let cells = (
+                    parse_hex_cells(&String::from_utf8_lossy(&expected)),
+                    parse_hex_cells(&hex),
+                );
+                if let (Ok(expected_cells), Ok(actual_cells)) = cells {
+                    eprint!(
+                        "{}",
+                        render_diff(&expected_cells, &actual_cells, rows, cols, &args.masks)
+                    );
+                }
+            }
+        }
+    }
 
-    for row in 0..rows {
-        for col in 0..cols {
-            let cell = screen.cell(row, col).unwrap();
+    // Write exit code for graders; "TIMEOUT" means we killed the child
+    if let Some(exit_path) = &args.exit_file {
+        fs::write(exit_path, format!("{}\n", result.exit_label()))
+            .with_context(|| format!("Failed to write exit file: {:?}", exit_path))?;
+    }
 
-            // Get character (first char of contents, or space if empty)
-            let ch = cell.contents().chars().next().unwrap_or(' ');
-            let codepoint = ch as u32;
+    // Exit explicitly since the reader thread may still be blocking,
+    // propagating the child's status so CI can tell crashes from clean runs
+    let exit_code = match &exit_status {
+        _ if interrupted() => EXIT_INTERRUPTED,
+        _ if result.truncated => EXIT_OUTPUT_LIMIT,
+        _ if !matched => EXIT_MISMATCH,
+        _ if !wait_ok => EXIT_WAIT_TIMEOUT,
+        _ if args.fail_on_unconsumed_input && result.input_unconsumed() => EXIT_UNCONSUMED_INPUT,
+        Some(status) => status.exit_code() as i32,
+        None if result.timed_out == Some(TimeoutKind::Idle) && args.exit_on_output_idle => 0,
+        None if result.timed_out == Some(TimeoutKind::Idle) => EXIT_IDLE_TIMEOUT,
+        None if result.timed_out == Some(TimeoutKind::Ended) => 0,
+        None => EXIT_TIMEOUT,
+    };
+    std::process::exit(exit_code);
+}
 
-            // Get foreground color
-            let (fg_r, fg_g, fg_b) = match cell.fgcolor() {
-                vt100::Color::Rgb(r, g, b) => (r, g, b),
-                vt100::Color::Idx(idx) => ansi_to_rgb(idx),
-                vt100::Color::Default => (240, 240, 240), // Default light gray
-            };
+/// Write the bytes encoded in the `DecodeArgs` input to standard output
+fn decode_base64_file(args: &DecodeArgs) -> Result<()> {
+    let text = match &args.input {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read base64 input: {:?}", path))?,
+        None => std::io::read_to_string(std::io::stdin())
+            .context("Failed to read base64 from standard input")?,
+    };
+    let data = decode_base64(&text).context("Invalid base64 input")?;
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&data)?;
+    stdout.flush()?;
+    Ok(())
+}
 
-            // Get background color
-            let (bg_r, bg_g, bg_b) = match cell.bgcolor() {
-                vt100::Color::Rgb(r, g, b) => (r, g, b),
-                vt100::Color::Idx(idx) => ansi_to_rgb(idx),
-                vt100::Color::Default => (0, 0, 0), // Default black
-            };
+/// Kill the programs being run when we are interrupted instead of leaving
+/// them in their PTYs (only for commands that run programs, so the others
+/// still stop at the first Ctrl-C)
+fn stop_runs_on_interrupt() {
+    if let Err(e) = forward_interrupts() {
+        eprintln!("WARN: failed to install interrupt handler: {}", e);
+    }
+}
 
-            // Get attributes as a byte
-            let attrs = {
-                let mut a = 0u8;
-                if cell.bold() {
-                    a |= 0x01;
-                }
-                if cell.italic() {
-                    a |= 0x02;
-                }
-                if cell.underline() {
-                    a |= 0x04;
-                }
-                if cell.inverse() {
-                    a |= 0x08;
-                }
-                a
-            };
+/// Compare the `DiffArgs` captures and print the cell report, exiting with
+/// the status documented on `DiffArgs`
+fn diff_hex_files(args: &DiffArgs) -> Result<()> {
+    match print_hex_diff(args) {
+        Ok(true) => Ok(()),
+        Ok(false) => std::process::exit(EXIT_MISMATCH),
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(EXIT_DIFF_TROUBLE);
+        }
+    }
+}
 
-            // Print in hex format: CCCCCCCC RRGGBB RRGGBB AA
-            print!(
-                "{:08X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
-                codepoint, fg_r, fg_g, fg_b, bg_r, bg_g, bg_b, attrs
+/// Print the cell report for the `DiffArgs` captures; returns true if they
+/// match
+fn print_hex_diff(args: &DiffArgs) -> Result<bool> {
+    let ignore = IgnoreFields {
+        colors: args.ignore_colors,
+        attrs: args.ignore_attrs,
+    };
+    let cell_count = args.rows as usize * args.cols as usize;
+    let read = |path: &PathBuf| -> Result<(ColorMode, Vec<HexCell>)> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read hex capture: {:?}", path))?;
+        let cells =
+            parse_hex_capture(&text).with_context(|| format!("Invalid hex in {:?}", path))?;
+        if cells.len() != cell_count {
+            anyhow::bail!(
+                "{:?} holds {} cells, but a {} rows x {} cols screen has {}",
+                path,
+                cells.len(),
+                args.rows,
+                args.cols,
+                cell_count
             );
         }
+        let cells = cells.iter().map(|cell| cell.without(ignore)).collect();
+        Ok((split_hex_header(&text).0, cells))
+    };
+    let (a_mode, a) = read(&args.a)?;
+    let (b_mode, b) = read(&args.b)?;
+    if a_mode != b_mode && !args.ignore_colors {
+        anyhow::bail!(
+            "{:?} has {:?} colors but {:?} has {:?}; compare them with --ignore-colors",
+            args.a,
+            a_mode,
+            args.b,
+            b_mode
+        );
     }
+
+    print!(
+        "{}",
+        format_cell_diff(&a, &b, args.rows, args.cols, &[], args.diff_limit)
+    );
+    Ok(a == b)
 }
 
-/// Print terminal state as text (just the characters)
-fn print_text_state(parser: &vt100::Parser, rows: u16, cols: u16) {
-    let screen = parser.screen();
+/// Decode the `RenderArgs` hex capture and write it in the requested form to
+/// standard output
+fn render_hex_file(args: &RenderArgs) -> Result<()> {
+    let text = read_input(&args.input, "hex capture")?;
+    let text = String::from_utf8(text)
+        .with_context(|| format!("Hex capture is not UTF-8: {:?}", args.input))?;
+    let cells =
+        parse_hex_capture(&text).with_context(|| format!("Invalid hex in {:?}", args.input))?;
+    let expected = args.rows as usize * args.cols as usize;
+    if cells.len() != expected {
+        anyhow::bail!(
+            "{:?} holds {} cells, but a {} rows x {} cols screen has {}",
+            args.input,
+            cells.len(),
+            args.rows,
+            args.cols,
+            expected
+        );
+    }
 
-    for row in 0..rows {
-        let mut line = String::new();
-        for col in 0..cols {
-            let cell = screen.cell(row, col).unwrap();
-            let ch = cell.contents().chars().next().unwrap_or(' ');
-            line.push(ch);
+    let ansi = format_ansi_cells(&cells, args.rows, args.cols);
+    let formatted = if args.to == "ansi" {
+        ansi
+    } else {
+        // Emulate the redraw so the screen formatters can be used as they are
+        let mut config = RunConfig::new(&args.input);
+        config.rows = args.rows;
+        config.cols = args.cols;
+        let result = replay(&config, &[(Duration::ZERO, ansi.as_bytes())]);
+        let options = RenderOptions {
+            extra_attrs: Some(result.extra_attrs.clone()),
+            ..Default::default()
+        };
+        screen_formatter(&args.to, (8, 16))
+            .with_context(|| format!("Unknown render format: {:?}", args.to))?
+            .format(&result.screen, args.rows, args.cols, &options)
+    };
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(formatted.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// The screens `--capture` asks for, each with its extra attributes; the
+/// final screen stands in for an alternate screen the program never used
+fn captured_screens<'a>(
+    capture: &str,
+    result: &'a CaptureResult,
+) -> Vec<(&'a vt100::Screen, &'a vt100::Screen)> {
+    let last_shown = |alternate: bool| {
+        let left = if alternate {
+            &result.alternate_left
+        } else {
+            &result.main_left
+        };
+        match left {
+            _ if result.screen.alternate_screen() == alternate => {
+                (&result.screen, &result.extra_attrs)
+            }
+            Some(frame) => (&frame.screen, &frame.extra_attrs),
+            None => {
+                eprintln!("WARN: the alternate screen was never used, capturing the final screen");
+                (&result.screen, &result.extra_attrs)
+            }
         }
-        // Trim trailing spaces
-        let trimmed = line.trim_end();
-        println!("{}", trimmed);
+    };
+    match capture {
+        "alt-last" => vec![last_shown(true)],
+        "main-last" => vec![last_shown(false)],
+        "both" => vec![last_shown(true), last_shown(false)],
+        _ => vec![(&result.screen, &result.extra_attrs)],
     }
 }
 
-/// Convert ANSI color index to RGB
-fn ansi_to_rgb(idx: u8) -> (u8, u8, u8) {
-    match idx {
-        // Standard colors
-        0 => (0, 0, 0),       // Black
-        1 => (205, 49, 49),   // Red
-        2 => (13, 188, 121),  // Green
-        3 => (229, 229, 16),  // Yellow
-        4 => (36, 114, 200),  // Blue
-        5 => (188, 63, 188),  // Magenta
-        6 => (17, 168, 205),  // Cyan
-        7 => (229, 229, 229), // White
-        // Bright colors
-        8 => (102, 102, 102),  // Bright Black
-        9 => (241, 76, 76),    // Bright Red
-        10 => (35, 209, 139),  // Bright Green
-        11 => (245, 245, 67),  // Bright Yellow
-        12 => (59, 142, 234),  // Bright Blue
-        13 => (214, 112, 214), // Bright Magenta
-        14 => (41, 184, 219),  // Bright Cyan
-        15 => (255, 255, 255), // Bright White
-        // 216 color cube (16-231)
-        16..=231 => {
-            let n = idx - 16;
-            let r = (n / 36) % 6;
-            let g = (n / 6) % 6;
-            let b = n % 6;
-            let to_val = |x: u8| if x == 0 { 0 } else { 55 + x * 40 };
-            (to_val(r), to_val(g), to_val(b))
-        }
-        // Grayscale (232-255)
-        232..=255 => {
-            let gray = 8 + (idx - 232) * 10;
-            (gray, gray, gray)
+/// Where `--live` (or `--interactive`) mirrors the output to, and whether that is stdout with no
+/// --output-file, so the capture is left out
+fn live_output(args: &Args) -> (Option<LiveOutput>, bool) {
+    if !args.live && !args.interactive {
+        return (None, false);
+    }
+    if args.output_file.is_some() {
+        return (Some(LiveOutput::Stdout), false);
+    }
+    #[cfg(unix)]
+    if !std::io::stdout().is_terminal()
+        && fs::OpenOptions::new().write(true).open("/dev/tty").is_ok()
+    {
+        return (Some(LiveOutput::File(PathBuf::from("/dev/tty"))), false);
+    }
+    eprintln!(
+        "WARN: --live mirrors the output to stdout, so the capture is not written \
+         (use --output-file to keep it)"
+    );
+    (Some(LiveOutput::Stdout), true)
+}
+
+/// Contents of a `--keyboard-input` or `--stdin-file` file, decoded from hex
+/// with `--input-hex`
+fn read_input_file(path: &Path, what: &str, hex: bool) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}: {:?}", what, path))?;
+    if !hex {
+        return Ok(data);
+    }
+    let text = String::from_utf8(data)
+        .with_context(|| format!("Hex {} is not UTF-8: {:?}", what, path))?;
+    decode_hex_bytes(&text).with_context(|| format!("Invalid hex {}: {:?}", what, path))
+}
+
+/// Contents of the file at `path`, or of standard input for "-"
+fn read_input(path: &Path, what: &str) -> Result<Vec<u8>> {
+    if path.as_os_str() == "-" {
+        let mut data = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to read {} from standard input", what))?;
+        Ok(data)
+    } else {
+        fs::read(path).with_context(|| format!("Failed to read {}: {:?}", what, path))
+    }
+}
+
+/// Emulate the `ReplayArgs` input and write it in the requested format to
+/// standard output
+fn replay_file(args: &ReplayArgs) -> Result<()> {
+    let data = read_input(&args.input, "raw output")?;
+    let chunks = if args.timestamps {
+        parse_raw_log(&data).with_context(|| format!("Invalid raw log: {:?}", args.input))?
+    } else {
+        vec![(Duration::ZERO, data.as_slice())]
+    };
+
+    let mut config = RunConfig::new(&args.input);
+    config.rows = args.rows;
+    config.cols = args.cols;
+    let result = replay(&config, &chunks);
+    let options = RenderOptions {
+        extra_attrs: Some(result.extra_attrs.clone()),
+        ..Default::default()
+    };
+    let screen = &result.screen;
+    let formatted = match args.output.as_str() {
+        "json" => format_json_state(
+            screen,
+            args.rows,
+            args.cols,
+            &options,
+            &JsonDetails::default(),
+        )?,
+        "both" => format_report(screen, args.rows, args.cols, &options, REPORT_NO_EXIT),
+        name => screen_formatter(name, (8, 16))
+            .with_context(|| format!("Unknown output format: {:?}", name))?
+            .format(screen, args.rows, args.cols, &options),
+    };
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(formatted.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Run the manifest cases on up to `--jobs` threads at once, print the
+/// summary in manifest order and exit non-zero if any case did not pass
+fn run_batch(args: &BatchArgs) -> Result<()> {
+    stop_runs_on_interrupt();
+    let text = fs::read_to_string(&args.manifest)
+        .with_context(|| format!("Failed to read manifest: {:?}", args.manifest))?;
+    let cases =
+        parse_manifest(&text).with_context(|| format!("Invalid manifest: {:?}", args.manifest))?;
+    let base_dir = args.manifest.parent().unwrap_or(Path::new(""));
+
+    let mode = if args.update_expect {
+        ExpectMode::Update {
+            force: args.update_expect_force,
         }
+    } else {
+        ExpectMode::Compare
+    };
+    let results = run_cases(&cases, base_dir, usize::from(args.jobs), mode);
+    print!("{}", format_summary(&results));
+
+    if let Some(path) = &args.report_junit {
+        let suite = args
+            .manifest
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        write_atomically(path, format_junit(&suite, &results).as_bytes())
+            .with_context(|| format!("Failed to write JUnit report: {:?}", path))?;
+    }
+
+    if interrupted() {
+        std::process::exit(EXIT_INTERRUPTED);
+    } else if results.iter().all(|result| result.outcome.passed()) {
+        Ok(())
+    } else {
+        std::process::exit(EXIT_MISMATCH);
+    }
+}
+
+/// Write `frames` to `dir` as numbered files (`<name>-0001.<ext>`, ...)
+fn write_frames(
+    dir: &Path,
+    name: &str,
+    frames: &[Frame],
+    formatter: &dyn ScreenFormatter,
+    options: &RenderOptions,
+) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    let mut options = options.clone();
+    for (i, frame) in frames.iter().enumerate() {
+        let path = dir.join(format!("{}-{:04}.{}", name, i + 1, formatter.extension()));
+        let (rows, cols) = frame.screen.size();
+        options.extra_attrs = Some(frame.extra_attrs.clone());
+        fs::write(&path, formatter.format(&frame.screen, rows, cols, &options))
+            .with_context(|| format!("Failed to write {}: {:?}", name, path))?;
+    }
+    Ok(())
+}
+
+/// Write `meta` as JSON to `path`, if one was given
+fn write_meta_file(path: Option<&PathBuf>, meta: &RunMeta) -> Result<()> {
+    if let Some(path) = path {
+        fs::write(path, serde_json::to_string(meta)? + "\n")
+            .with_context(|| format!("Failed to write meta file: {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Parse a KEY=VALUE environment assignment (the value may itself contain '=')
+fn parse_env_pair(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, val)) if !key.is_empty() => Ok((key.to_string(), val.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_pairs_keep_equals_in_value() {
+        assert_eq!(
+            parse_env_pair("OPTS=a=1,b=2"),
+            Ok(("OPTS".to_string(), "a=1,b=2".to_string()))
+        );
+        assert_eq!(
+            parse_env_pair("EMPTY="),
+            Ok(("EMPTY".to_string(), String::new()))
+        );
+        assert!(parse_env_pair("NOVALUE").is_err());
+        assert!(parse_env_pair("=x").is_err());
     }
 }
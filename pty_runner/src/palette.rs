@@ -0,0 +1,278 @@
+//! The table used to resolve indexed colors to RGB
+//!
+//! Only entries 0-15 differ between terminals; the 216-color cube (16-231)
+//! and the grayscale ramp (232-255) are computed the same way everywhere, so
+//! presets and 16-color files leave them standard. A 256-color file replaces
+//! them too.
+
+use crate::format::parse_rgb;
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// RGB of all 256 ANSI colors: 0-15 (normal then bright), the 216-color
+/// cube and the grayscale ramp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette(pub [(u8, u8, u8); 256]);
+
+/// Built-in palettes selectable by name with `--palette`
+pub const PRESETS: &[(&str, Palette)] = &[
+    ("vscode", VSCODE),
+    ("xterm", XTERM),
+    ("vga", VGA),
+    ("windows-terminal", WINDOWS_TERMINAL),
+];
+
+/// VS Code's integrated terminal (the default)
+const VSCODE: Palette = Palette::from_base([
+    (0, 0, 0),
+    (205, 49, 49),
+    (13, 188, 121),
+    (229, 229, 16),
+    (36, 114, 200),
+    (188, 63, 188),
+    (17, 168, 205),
+    (229, 229, 229),
+    (102, 102, 102),
+    (241, 76, 76),
+    (35, 209, 139),
+    (245, 245, 67),
+    (59, 142, 234),
+    (214, 112, 214),
+    (41, 184, 219),
+    (255, 255, 255),
+]);
+
+/// xterm's default resources
+const XTERM: Palette = Palette::from_base([
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+]);
+
+/// The VGA text mode colors (also the Linux console's)
+const VGA: Palette = Palette::from_base([
+    (0, 0, 0),
+    (170, 0, 0),
+    (0, 170, 0),
+    (170, 85, 0),
+    (0, 0, 170),
+    (170, 0, 170),
+    (0, 170, 170),
+    (170, 170, 170),
+    (85, 85, 85),
+    (255, 85, 85),
+    (85, 255, 85),
+    (255, 255, 85),
+    (85, 85, 255),
+    (255, 85, 255),
+    (85, 255, 255),
+    (255, 255, 255),
+]);
+
+/// Windows Terminal's default "Campbell" scheme
+const WINDOWS_TERMINAL: Palette = Palette::from_base([
+    (12, 12, 12),
+    (197, 15, 31),
+    (19, 161, 14),
+    (193, 156, 0),
+    (0, 55, 218),
+    (136, 23, 152),
+    (58, 150, 221),
+    (204, 204, 204),
+    (118, 118, 118),
+    (231, 72, 86),
+    (22, 198, 12),
+    (249, 241, 165),
+    (59, 120, 255),
+    (180, 0, 158),
+    (97, 214, 214),
+    (242, 242, 242),
+]);
+
+impl Default for Palette {
+    fn default() -> Self {
+        VSCODE
+    }
+}
+
+impl Palette {
+    /// The palette with `base` as colors 0-15 and the standard cube and ramp
+    pub const fn from_base(base: [(u8, u8, u8); 16]) -> Self {
+        let mut colors = [(0, 0, 0); 256];
+        let mut idx = 0;
+        while idx < 256 {
+            colors[idx] = match idx {
+                0..=15 => base[idx],
+                _ => standard_rgb(idx as u8),
+            };
+            idx += 1;
+        }
+        Self(colors)
+    }
+
+    /// RGB of ANSI color `idx`
+    pub fn rgb(&self, idx: u8) -> (u8, u8, u8) {
+        self.0[idx as usize]
+    }
+
+    /// The preset called `name`, or else the palette in the file at that path
+    pub fn load(name: &str) -> Result<Self> {
+        if let Some((_, palette)) = PRESETS.iter().find(|(preset, _)| *preset == name) {
+            return Ok(*palette);
+        }
+        let path = Path::new(name);
+        if !path.exists() {
+            let presets: Vec<&str> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+            bail!(
+                "Unknown palette {:?} (not a file or one of {})",
+                name,
+                presets.join(", ")
+            );
+        }
+        Self::from_file(path)
+    }
+
+    /// The palette in the file at `path` (see `parse`)
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read palette file: {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("Invalid palette file: {:?}", path))
+    }
+
+    /// Parse a palette file: a JSON array of "RRGGBB" strings, or lines of
+    /// RRGGBB (blank lines are skipped), giving either colors 0-15 or all 256
+    pub fn parse(text: &str) -> Result<Self> {
+        let is_json = text.trim_start().starts_with('[');
+        let entries: Vec<(usize, String)> = if is_json {
+            let colors: Vec<String> =
+                serde_json::from_str(text).context("expected a JSON array of RRGGBB strings")?;
+            colors
+                .into_iter()
+                .enumerate()
+                .map(|(i, color)| (i + 1, color))
+                .collect()
+        } else {
+            text.lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(i, line)| (i + 1, line.trim().to_string()))
+                .collect()
+        };
+        let place = |n: usize| {
+            if is_json {
+                format!("entry {}", n)
+            } else {
+                format!("line {}", n)
+            }
+        };
+
+        let mut palette = Self::default();
+        for (i, (n, entry)) in entries.iter().enumerate() {
+            if i == palette.0.len() {
+                bail!("{}: more than 256 colors", place(*n));
+            }
+            palette.0[i] = parse_rgb(entry).map_err(|e| anyhow::anyhow!("{}: {}", place(*n), e))?;
+        }
+        if entries.len() != 16 && entries.len() != palette.0.len() {
+            bail!("expected 16 or 256 colors, found {}", entries.len());
+        }
+        Ok(palette)
+    }
+}
+
+/// RGB every terminal gives color `idx` of the cube (16-231) or the ramp
+/// (232-255)
+const fn standard_rgb(idx: u8) -> (u8, u8, u8) {
+    match idx {
+        // 216 color cube (16-231)
+        16..=231 => {
+            let n = idx - 16;
+            (
+                cube_level(n / 36),
+                cube_level((n / 6) % 6),
+                cube_level(n % 6),
+            )
+        }
+        // Grayscale (232-255)
+        232..=255 => {
+            let gray = 8 + (idx - 232) * 10;
+            (gray, gray, gray)
+        }
+        _ => (0, 0, 0),
+    }
+}
+
+/// Intensity of step `x` (0-5) along one axis of the color cube
+const fn cube_level(x: u8) -> u8 {
+    if x == 0 {
+        0
+    } else {
+        55 + x * 40
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lines_and_json() {
+        let lines: Vec<String> = (0..16).map(|i| format!("{:02x}0000", i)).collect();
+        let palette = Palette::parse(&format!("{}\n\n", lines.join("\n"))).unwrap();
+        assert_eq!(palette.rgb(3), (3, 0, 0));
+        assert_eq!(palette.rgb(15), (15, 0, 0));
+        // The cube and ramp do not depend on the palette
+        assert_eq!(palette.rgb(196), Palette::default().rgb(196));
+        assert_eq!(palette.rgb(244), (128, 128, 128));
+
+        let json = format!("[\"#{}\"]", lines.join("\", \"#"));
+        assert_eq!(Palette::parse(&json).unwrap(), palette);
+        assert_eq!(Palette::load("xterm").unwrap().rgb(4), (0, 0, 238));
+        assert_eq!(Palette::load("vga").unwrap().rgb(3), (170, 85, 0));
+        assert!(Palette::load("no-such-palette").is_err());
+    }
+
+    #[test]
+    fn full_tables_replace_the_cube_and_ramp() {
+        let lines: Vec<String> = (0..=255).map(|i| format!("0000{:02x}", i)).collect();
+        let palette = Palette::parse(&lines.join("\n")).unwrap();
+        assert_eq!(palette.rgb(9), (0, 0, 9));
+        assert_eq!(palette.rgb(196), (0, 0, 196));
+        assert_eq!(palette.rgb(255), (0, 0, 255));
+
+        let err = Palette::parse(&lines[..100].join("\n")).unwrap_err();
+        assert_eq!(err.to_string(), "expected 16 or 256 colors, found 100");
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let mut lines: Vec<&str> = vec!["000000"; 16];
+        lines[4] = "12345";
+        let err = Palette::parse(&lines.join("\n")).unwrap_err();
+        assert!(
+            format!("{:#}", err).starts_with("line 5: expected RRGGBB"),
+            "{:#}",
+            err
+        );
+
+        let err = Palette::parse("000000\n").unwrap_err();
+        assert_eq!(err.to_string(), "expected 16 or 256 colors, found 1");
+        let err = Palette::parse(&"000000\n".repeat(257)).unwrap_err();
+        assert_eq!(err.to_string(), "line 257: more than 256 colors");
+        let err = Palette::parse(r#"["000000", "nope"]"#).unwrap_err();
+        assert!(err.to_string().starts_with("entry 2:"), "{}", err);
+    }
+}
@@ -0,0 +1,106 @@
+//! Killing a child together with everything it started
+//!
+//! On Unix portable-pty makes the child a session (and so process group)
+//! leader, so the whole group is signalled; on Windows the child is put in a
+//! job object, which its descendants inherit.
+
+use portable_pty::Child;
+
+/// Handle on a child's descendants, taken right after spawning
+pub(crate) struct ProcessTree {
+    #[cfg(unix)]
+    pgid: libc::pid_t,
+    #[cfg(windows)]
+    job: job::Job,
+}
+
+impl ProcessTree {
+    /// Track the processes of `child`; None if it has no process id
+    /// (or, on Windows, the job object could not be set up)
+    pub(crate) fn attach(child: &(dyn Child + Send + Sync)) -> Option<Self> {
+        let pid = child.process_id()?;
+        #[cfg(unix)]
+        {
+            Some(Self {
+                pgid: pid as libc::pid_t,
+            })
+        }
+        #[cfg(windows)]
+        {
+            job::Job::assign(pid).map(|job| Self { job })
+        }
+    }
+
+    /// Kill every process still in the tree
+    pub(crate) fn kill(&self) {
+        #[cfg(unix)]
+        // SAFETY: kill has no memory-safety preconditions; a negative pid
+        // addresses the process group
+        unsafe {
+            libc::kill(-self.pgid, libc::SIGKILL);
+        }
+        #[cfg(windows)]
+        self.job.terminate();
+    }
+}
+
+#[cfg(windows)]
+mod job {
+    use std::os::raw::c_void;
+    use std::ptr;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> *mut c_void;
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> *mut c_void;
+        fn AssignProcessToJobObject(job: *mut c_void, process: *mut c_void) -> i32;
+        fn TerminateJobObject(job: *mut c_void, exit_code: u32) -> i32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+
+    const PROCESS_TERMINATE: u32 = 0x0001;
+    const PROCESS_SET_QUOTA: u32 = 0x0100;
+
+    /// Job object owning the child; processes it creates join it too
+    pub(super) struct Job(*mut c_void);
+
+    impl Job {
+        pub(super) fn assign(pid: u32) -> Option<Self> {
+            // SAFETY: plain Win32 calls; every handle opened here is closed
+            // here or when the Job is dropped
+            unsafe {
+                let job = CreateJobObjectW(ptr::null_mut(), ptr::null());
+                if job.is_null() {
+                    return None;
+                }
+                let process = OpenProcess(PROCESS_TERMINATE | PROCESS_SET_QUOTA, 0, pid);
+                let assigned = !process.is_null() && AssignProcessToJobObject(job, process) != 0;
+                if !process.is_null() {
+                    CloseHandle(process);
+                }
+                if assigned {
+                    Some(Self(job))
+                } else {
+                    CloseHandle(job);
+                    None
+                }
+            }
+        }
+
+        pub(super) fn terminate(&self) {
+            // SAFETY: self.0 is a valid job handle until drop
+            unsafe {
+                TerminateJobObject(self.0, 1);
+            }
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            // SAFETY: the handle is owned by this Job and closed only once
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Answers to terminal queries found in the child's output
+//!
+//! Programs that ask the terminal about itself (device attributes, cursor
+//! position) block until they get a reply, so the runner answers the common
+//! requests the way xterm would.
+
+/// A terminal request the runner knows how to answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalQuery {
+    /// DA1, `ESC [ c` or `ESC [ 0 c`
+    PrimaryAttributes,
+    /// DA2, `ESC [ > c` or `ESC [ > 0 c`
+    SecondaryAttributes,
+    /// DSR cursor position report request, `ESC [ 6 n`
+    CursorPosition,
+}
+
+impl TerminalQuery {
+    /// Reply bytes, given the 0-based cursor (row, col) when the query was seen
+    pub fn reply(self, cursor: (u16, u16)) -> Vec<u8> {
+        match self {
+            // VT100 with advanced video option, as xterm reports by default
+            TerminalQuery::PrimaryAttributes => b"\x1b[?1;2c".to_vec(),
+            TerminalQuery::SecondaryAttributes => b"\x1b[>0;276;0c".to_vec(),
+            TerminalQuery::CursorPosition => {
+                format!("\x1b[{};{}R", cursor.0 + 1, cursor.1 + 1).into_bytes()
+            }
+        }
+    }
+}
+
+/// Longest CSI parameter string kept while scanning; longer sequences are
+/// never queries we answer
+const MAX_PARAMS: usize = 16;
+
+/// Position of a `QueryScanner` within the byte stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ScanState {
+    Normal,
+    Escape,
+    /// Inside a CSI sequence, with its parameter and intermediate bytes
+    Csi(Vec<u8>),
+}
+
+/// Streaming scanner for terminal queries; its state carries across `feed`
+/// calls, so a query split between chunks is still found
+pub struct QueryScanner {
+    state: ScanState,
+}
+
+impl QueryScanner {
+    pub fn new() -> Self {
+        Self {
+            state: ScanState::Normal,
+        }
+    }
+
+    /// Scan the next chunk, returning each query found with the offset just
+    /// past its last byte in `data`
+    pub fn feed(&mut self, data: &[u8]) -> Vec<(usize, TerminalQuery)> {
+        let mut queries = Vec::new();
+        for (i, &byte) in data.iter().enumerate() {
+            let state = std::mem::replace(&mut self.state, ScanState::Normal);
+            self.state = match (state, byte) {
+                (_, 0x1b) => ScanState::Escape,
+                (ScanState::Escape, b'[') => ScanState::Csi(Vec::new()),
+                (ScanState::Csi(mut params), 0x20..=0x3f) => {
+                    if params.len() < MAX_PARAMS {
+                        params.push(byte);
+                    }
+                    ScanState::Csi(params)
+                }
+                (ScanState::Csi(params), 0x40..=0x7e) => {
+                    let query = match (params.as_slice(), byte) {
+                        (b"" | b"0", b'c') => Some(TerminalQuery::PrimaryAttributes),
+                        (b">" | b">0", b'c') => Some(TerminalQuery::SecondaryAttributes),
+                        (b"6", b'n') => Some(TerminalQuery::CursorPosition),
+                        _ => None,
+                    };
+                    if let Some(query) = query {
+                        queries.push((i + 1, query));
+                    }
+                    ScanState::Normal
+                }
+                _ => ScanState::Normal,
+            };
+        }
+        queries
+    }
+}
+
+impl Default for QueryScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanner_finds_queries_with_their_end_offsets() {
+        let mut scanner = QueryScanner::new();
+        assert_eq!(
+            scanner.feed(b"a\x1b[c\x1b[>0cb\x1b[6n\x1b[31m\x1b[5n"),
+            [
+                (4, TerminalQuery::PrimaryAttributes),
+                (9, TerminalQuery::SecondaryAttributes),
+                (14, TerminalQuery::CursorPosition),
+            ]
+        );
+    }
+
+    #[test]
+    fn scanner_finds_query_split_across_chunks() {
+        let mut scanner = QueryScanner::new();
+        assert!(scanner.feed(b"x\x1b").is_empty());
+        assert!(scanner.feed(b"[6").is_empty());
+        assert_eq!(scanner.feed(b"n"), [(1, TerminalQuery::CursorPosition)]);
+    }
+
+    #[test]
+    fn cursor_position_reply_is_one_based() {
+        assert_eq!(TerminalQuery::CursorPosition.reply((0, 4)), b"\x1b[1;5R");
+        assert_eq!(
+            TerminalQuery::PrimaryAttributes.reply((0, 0)),
+            b"\x1b[?1;2c"
+        );
+    }
+}
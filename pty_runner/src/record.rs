@@ -0,0 +1,240 @@
+//! Recording the whole session for later replay
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header line of an asciicast v2 file
+#[derive(Serialize)]
+struct AsciicastHeader {
+    version: u32,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+}
+
+/// Format a captured session as asciicast v2 (asciinema) lines
+/// One "o" event is written per chunk; a UTF-8 sequence split between chunks
+/// is carried into the next event rather than replaced
+pub fn format_asciicast(
+    output: &[u8],
+    chunks: &[(Duration, usize)],
+    cols: u16,
+    rows: u16,
+    started_at: SystemTime,
+) -> serde_json::Result<String> {
+    let header = AsciicastHeader {
+        version: 2,
+        width: cols,
+        height: rows,
+        timestamp: started_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+    };
+    let mut out = serde_json::to_string(&header)?;
+    out.push('\n');
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut offset = 0;
+    for &(elapsed, len) in chunks {
+        pending.extend_from_slice(&output[offset..offset + len]);
+        offset += len;
+
+        // Hold back an incomplete sequence at the end for the next chunk
+        let valid = match std::str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => pending.len(),
+        };
+        if valid == 0 {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&pending[..valid]).into_owned();
+        pending.drain(..valid);
+
+        // Microsecond precision keeps the file stable across serializers
+        let seconds = (elapsed.as_secs_f64() * 1e6).round() / 1e6;
+        out.push_str(&serde_json::to_string(&(seconds, "o", text))?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Format chunk arrival times as CSV: an `elapsed_ms,bytes` header, then one
+/// row per chunk with the time since spawn (to the microsecond) and its length
+pub fn format_timing_csv(chunks: &[(Duration, usize)]) -> String {
+    let mut out = String::from("elapsed_ms,bytes\n");
+    for &(elapsed, len) in chunks {
+        out.push_str(&format!("{:.3},{}\n", elapsed.as_secs_f64() * 1e3, len));
+    }
+    out
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode bytes as standard (RFC 4648, padded) base64 on a single line
+pub fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode base64 from `encode_base64`; whitespace (line breaks from other
+/// tools) is ignored and padding is optional
+pub fn decode_base64(text: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let data = match digits.iter().position(|&b| b == b'=') {
+        Some(pad) if digits[pad..].iter().all(|&b| b == b'=') && digits.len().is_multiple_of(4) => {
+            &digits[..pad]
+        }
+        Some(pad) => bail!("misplaced padding at offset {}", pad),
+        None => &digits[..],
+    };
+    if data.len() % 4 == 1 {
+        bail!("truncated base64 ({} digits)", data.len());
+    }
+
+    let mut out = Vec::with_capacity(data.len() / 4 * 3 + 2);
+    for chunk in data.chunks(4) {
+        let mut group = 0u32;
+        for (i, &digit) in chunk.iter().enumerate() {
+            let Some(value) = BASE64_ALPHABET.iter().position(|&b| b == digit) else {
+                bail!("invalid base64 character {:?}", digit as char);
+            };
+            group |= (value as u32) << (18 - 6 * i);
+        }
+        out.extend(&group.to_be_bytes()[1..chunk.len()]);
+    }
+    Ok(out)
+}
+
+/// Length of the header written before each chunk of a timestamped raw log
+pub const RAW_LOG_HEADER_LEN: usize = 12;
+
+/// Header of a chunk in a timestamped raw log: microseconds since spawn as a
+/// u64, then the chunk length as a u32, both little-endian
+pub fn raw_log_header(elapsed: Duration, len: usize) -> [u8; RAW_LOG_HEADER_LEN] {
+    let mut header = [0; RAW_LOG_HEADER_LEN];
+    header[..8].copy_from_slice(&(elapsed.as_micros() as u64).to_le_bytes());
+    header[8..].copy_from_slice(&(len as u32).to_le_bytes());
+    header
+}
+
+/// Split a timestamped raw log into its chunks and when each arrived
+pub fn parse_raw_log(data: &[u8]) -> Result<Vec<(Duration, &[u8])>> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let Some(header) = data.get(offset..offset + RAW_LOG_HEADER_LEN) else {
+            bail!("truncated chunk header at offset {}", offset);
+        };
+        let micros = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        let start = offset + RAW_LOG_HEADER_LEN;
+        let Some(chunk) = data.get(start..start + len) else {
+            bail!(
+                "chunk at offset {} has {} of its {} bytes",
+                offset,
+                data.len() - start,
+                len
+            );
+        };
+        chunks.push((Duration::from_micros(micros), chunk));
+        offset = start + len;
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asciicast_has_header_and_one_event_per_chunk() {
+        // "é" split across the two chunks
+        let output = b"hi\xc3\xa9\r\n";
+        let chunks = [
+            (Duration::from_millis(5), 3),
+            (Duration::from_millis(1250), 3),
+        ];
+        let started_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let cast = format_asciicast(output, &chunks, 80, 25, started_at).unwrap();
+        let lines: Vec<&str> = cast.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"version":2,"width":80,"height":25,"timestamp":1700000000}"#,
+                r#"[0.005,"o","hi"]"#,
+                r#"[1.25,"o","é\r\n"]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn timing_csv_has_one_row_per_chunk() {
+        let chunks = [
+            (Duration::from_micros(5_250), 3),
+            (Duration::from_millis(1250), 4096),
+        ];
+        assert_eq!(
+            format_timing_csv(&chunks),
+            "elapsed_ms,bytes\n5.250,3\n1250.000,4096\n"
+        );
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        let cases: [(&[u8], &str); 5] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"\x1b[1mhi\r\n\xff", "G1sxbWhpDQr/"),
+        ];
+        for (data, encoded) in cases {
+            assert_eq!(encode_base64(data), encoded);
+            assert_eq!(decode_base64(encoded).unwrap(), data);
+        }
+        assert_eq!(decode_base64("Zm9v\nYmFy\n").unwrap(), b"foobar");
+        assert_eq!(decode_base64("Zm8").unwrap(), b"fo");
+        assert!(decode_base64("Zm9v!").is_err());
+        assert!(decode_base64("Z=m9").is_err());
+        assert!(decode_base64("Zm9vY").is_err());
+    }
+
+    #[test]
+    fn raw_log_chunks_round_trip() {
+        let mut log = Vec::new();
+        log.extend(raw_log_header(Duration::from_micros(1_500), 2));
+        log.extend(b"hi");
+        log.extend(raw_log_header(Duration::from_secs(3), 1));
+        log.extend(b"\xff");
+        assert_eq!(
+            parse_raw_log(&log).unwrap(),
+            [
+                (Duration::from_micros(1_500), &b"hi"[..]),
+                (Duration::from_secs(3), &b"\xff"[..]),
+            ]
+        );
+
+        let err = parse_raw_log(&log[..log.len() - 1]).unwrap_err();
+        assert_eq!(err.to_string(), "chunk at offset 14 has 0 of its 1 bytes");
+        let err = parse_raw_log(&log[..20]).unwrap_err();
+        assert_eq!(err.to_string(), "truncated chunk header at offset 14");
+    }
+}
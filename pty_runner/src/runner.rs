@@ -0,0 +1,1905 @@
+//! Running a program in a PTY and capturing its output
+
+use crate::attrs::{cell_attr_bits, ExtraAttrs};
+use crate::filter::{
+    convert_newlines, normalize_erase_sequences, normalize_reset_sequences, NewlineMode, OscFilter,
+};
+use crate::input::{split_keys, ScriptStep};
+use crate::interrupt::interrupted;
+use crate::process_tree::ProcessTree;
+use crate::query::QueryScanner;
+use crate::record::raw_log_header;
+use crate::terminal::END_SESSION_KEY;
+use anyhow::{Context, Result};
+use portable_pty::{native_pty_system, Child, CommandBuilder, ExitStatus, MasterPty, PtySize};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// `eprintln!` with a run's `log_prefix` in front
+macro_rules! log {
+    ($prefix:expr, $($arg:tt)*) => {
+        eprintln!("{}{}", $prefix, format_args!($($arg)*))
+    };
+}
+
+/// What to run and how to drive it
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    /// Program to run inside the PTY
+    pub executable: PathBuf,
+    /// Arguments passed to the program as-is
+    pub args: Vec<OsString>,
+    /// Directory the program is started in (must exist)
+    pub working_dir: Option<PathBuf>,
+    /// TERM given to the child; programs may emit different bytes per value
+    pub term: String,
+    /// Start the child from an empty environment (TERM is still set)
+    pub clear_env: bool,
+    /// Extra KEY=VALUE environment, applied after the defaults
+    pub env: Vec<(String, String)>,
+    /// Environment variables removed from the inherited environment
+    pub env_remove: Vec<String>,
+    /// Terminal width
+    pub cols: u16,
+    /// Terminal height
+    pub rows: u16,
+    /// Rows kept after scrolling off the top of the screen (0 keeps none)
+    pub scrollback: usize,
+    /// Bytes written to the PTY right after spawning (or, with
+    /// `InputOrder::KeyboardFirst`, after the keyboard input)
+    pub stdin: Option<Vec<u8>>,
+    /// Whether `stdin` or the keyboard input is sent first
+    pub input_order: InputOrder,
+    /// Pause after spawning (and after `stdin`, if it goes first) before
+    /// keyboard input is sent
+    pub startup_delay: Duration,
+    /// How newlines in `stdin` and `keyboard_input` are rewritten
+    pub input_newlines: NewlineMode,
+    /// Keyboard inputs (one per file), each sent line by line (or byte by
+    /// byte with `send_delay`), in order and `keyboard_delay` apart
+    pub keyboard_input: Vec<Vec<u8>>,
+    /// Pause between the `keyboard_input` entries
+    pub keyboard_delay: Duration,
+    /// Delay between keyboard bytes; zero sends line by line instead
+    pub send_delay: Duration,
+    /// Type the keyboard input and script `send` steps one key at a time
+    /// (see `input::split_keys`), this far apart; takes over from
+    /// `send_delay`
+    pub type_interval: Option<Duration>,
+    /// Before the keyboard input and each of its chunks (bytes or lines;
+    /// typed keys only wait `type_interval`), wait until no output has
+    /// arrived for this long, at most until the timeout; replaces
+    /// `startup_delay` and the pause between lines
+    pub settle: Option<Duration>,
+    /// Keyboard script run after the keyboard input
+    pub keyboard_script: Option<Vec<ScriptStep>>,
+    /// Once all input is sent and this long has passed since spawning, send
+    /// end of input (see `InputWriter::send_eof`)
+    pub send_eof: Option<Duration>,
+    /// Text or pattern to wait for on screen before sending keyboard input
+    pub wait_for: Option<ScreenPattern>,
+    /// How long `wait_for` and script `expect` steps may wait
+    pub wait_timeout: Duration,
+    /// How long the program may run before it is killed
+    pub timeout: Duration,
+    /// Kill the program once no output has arrived for this long
+    pub idle_timeout: Option<Duration>,
+    /// On timeout, send Ctrl-C and give the program this long to exit before
+    /// killing it (zero kills at once, as does input closed by `send_eof` on
+    /// Windows)
+    pub kill_grace: Duration,
+    /// On timeout, also kill every process the program started
+    pub kill_tree: bool,
+    /// How long to keep reading after exit while waiting for the PTY to
+    /// report end of output
+    pub drain_timeout: Duration,
+    /// Pass OSC sequences to the emulator instead of removing them
+    pub keep_osc: bool,
+    /// Remove OSC, DCS, SOS, PM and APC strings before emulation; when
+    /// false, every captured byte reaches the emulator
+    pub osc_filter: bool,
+    /// Snapshot the screen after every chunk of output (see
+    /// `CaptureResult::frames`)
+    pub record_frames: bool,
+    /// Also snapshot the screen at this interval while the program runs (see
+    /// `CaptureResult::snapshots`)
+    pub snapshot_interval: Option<Duration>,
+    /// Snapshot the screen the first time each of these appears on it (see
+    /// `CaptureResult::pattern_snapshots`)
+    pub snapshot_patterns: Vec<ScreenPattern>,
+    /// Answer device attribute and cursor position queries from the program
+    pub query_replies: bool,
+    /// Send the program's stderr to this file instead of the terminal
+    /// (Unix only)
+    pub stderr_file: Option<PathBuf>,
+    /// Prepended to every progress line written to stderr, so concurrent
+    /// runs can be told apart
+    pub log_prefix: String,
+    /// Terminal size changes made while the program runs, in time order
+    pub resizes: Vec<Resize>,
+    /// Write every chunk of output to this file as it arrives, before any
+    /// filtering
+    pub raw_log: Option<PathBuf>,
+    /// Put a `record::raw_log_header` before each chunk in `raw_log`
+    pub raw_log_timestamps: bool,
+    /// Mirror every chunk of output here as it arrives, unmodified, so the
+    /// program can be watched in a terminal
+    pub live: Option<LiveOutput>,
+    /// Keep at most this many bytes of output, killing the program once it
+    /// writes more (see `CaptureResult::truncated`)
+    pub max_output_bytes: Option<usize>,
+    /// Once all input is sent, forward whatever arrives on the runner's
+    /// standard input to the program (see `CaptureResult::typed`), until
+    /// `terminal::END_SESSION_KEY` or the program's exit; the timeouts do
+    /// not apply. Standard input should be a terminal in
+    /// `terminal::RawMode`
+    pub interactive: bool,
+}
+
+impl RunConfig {
+    /// Configuration with the CLI defaults: an 80x25 terminal, a 5 s timeout
+    /// and no input
+    pub fn new(executable: impl Into<PathBuf>) -> Self {
+        Self {
+            executable: executable.into(),
+            args: Vec::new(),
+            working_dir: None,
+            term: "xterm".to_string(),
+            clear_env: false,
+            env: Vec::new(),
+            env_remove: Vec::new(),
+            cols: 80,
+            rows: 25,
+            scrollback: 0,
+            stdin: None,
+            input_order: InputOrder::StdinFirst,
+            startup_delay: Duration::from_millis(100),
+            input_newlines: NewlineMode::platform_default(),
+            keyboard_input: Vec::new(),
+            keyboard_delay: Duration::ZERO,
+            send_delay: Duration::ZERO,
+            type_interval: None,
+            settle: None,
+            keyboard_script: None,
+            send_eof: None,
+            wait_for: None,
+            wait_timeout: Duration::from_millis(2000),
+            timeout: Duration::from_millis(5000),
+            idle_timeout: None,
+            kill_grace: Duration::ZERO,
+            kill_tree: true,
+            drain_timeout: Duration::from_millis(1000),
+            keep_osc: false,
+            osc_filter: true,
+            record_frames: false,
+            snapshot_interval: None,
+            snapshot_patterns: Vec::new(),
+            query_replies: true,
+            stderr_file: None,
+            log_prefix: String::new(),
+            resizes: Vec::new(),
+            raw_log: None,
+            raw_log_timestamps: false,
+            live: None,
+            max_output_bytes: None,
+            interactive: false,
+        }
+    }
+}
+
+/// Order in which `RunConfig::stdin` and the keyboard input are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputOrder {
+    /// Stdin right after spawning, keyboard input after the startup delay
+    StdinFirst,
+    /// Keyboard input (and script) after the startup delay, then stdin
+    KeyboardFirst,
+}
+
+/// Where `RunConfig::live` mirrors the output to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LiveOutput {
+    /// The runner's standard output
+    Stdout,
+    /// A terminal device such as /dev/tty, or a file appended to
+    File(PathBuf),
+}
+
+/// Resize of the terminal `at` a time since spawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resize {
+    pub at: Duration,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Parse a MS:COLSxROWS resize, e.g. "500:100x30"
+pub fn parse_resize(value: &str) -> Result<Resize, String> {
+    let error = || format!("expected MS:COLSxROWS, got {:?}", value);
+    let (at, size) = value.split_once(':').ok_or_else(error)?;
+    let (cols, rows) = size.split_once(['x', 'X']).ok_or_else(error)?;
+    let at = at.trim().parse::<u64>().map_err(|_| error())?;
+    let cols = cols.trim().parse::<u16>().map_err(|_| error())?;
+    let rows = rows.trim().parse::<u16>().map_err(|_| error())?;
+    if cols == 0 || rows == 0 {
+        return Err(format!(
+            "terminal size must be at least 1x1, got {:?}",
+            value
+        ));
+    }
+    Ok(Resize {
+        at: Duration::from_millis(at),
+        cols,
+        rows,
+    })
+}
+
+/// Text looked for on the screen
+#[derive(Debug, Clone)]
+pub enum ScreenPattern {
+    /// A plain substring
+    Text(String),
+    Regex(regex::Regex),
+}
+
+impl ScreenPattern {
+    /// Whether the pattern occurs in `contents` (rows joined by newlines)
+    pub fn is_found_in(&self, contents: &str) -> bool {
+        match self {
+            ScreenPattern::Text(text) => contents.contains(text.as_str()),
+            ScreenPattern::Regex(regex) => regex.is_match(contents),
+        }
+    }
+}
+
+impl fmt::Display for ScreenPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScreenPattern::Text(text) => write!(f, "{:?}", text),
+            ScreenPattern::Regex(regex) => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+/// Screen snapshot taken after one chunk of output
+#[derive(Clone)]
+pub struct Frame {
+    /// Time since spawn the chunk was read
+    pub elapsed: Duration,
+    pub screen: vt100::Screen,
+    /// Extra attributes of the screen's cells (see `CaptureResult::extra_attrs`)
+    pub extra_attrs: vt100::Screen,
+}
+
+/// Everything captured from one run
+pub struct CaptureResult {
+    /// Raw bytes read from the PTY, before any filtering
+    pub output: Vec<u8>,
+    /// When the program was spawned
+    pub started_at: SystemTime,
+    /// Wall-clock time from spawn until the output was fully read
+    pub duration: Duration,
+    /// Wall-clock time from spawn until the program exited or was stopped
+    pub run_time: Duration,
+    /// Length of the output after OSC filtering
+    pub filtered_len: usize,
+    /// Time since spawn and length of each chunk read from the PTY, in
+    /// order; the lengths add up to `output.len()`
+    pub chunks: Vec<(Duration, usize)>,
+    /// Final terminal screen, built from the filtered and normalized output;
+    /// its size is the last one set by `RunConfig::resizes`
+    pub screen: vt100::Screen,
+    /// Rows that scrolled off the top of the screen, oldest first (at most
+    /// `RunConfig::scrollback` of them)
+    pub scrollback: Vec<Vec<vt100::Cell>>,
+    /// Extra attribute bits (`attrs::ATTR_*`) of each `scrollback` cell
+    pub scrollback_extra_attrs: Vec<Vec<u8>>,
+    /// Total rows that scrolled off the top, kept or not (counted up to
+    /// `SCROLL_COUNT_LIMIT`)
+    pub scrolled_off: usize,
+    /// Last window title set with OSC 0, 1 or 2 (OSC 1 being the icon name), if any
+    pub title: Option<String>,
+    /// Extra attributes (dim, blink, ...) of the screen's cells, read with
+    /// `attrs::extra_attr_bits`
+    pub extra_attrs: vt100::Screen,
+    /// The program switched to the alternate screen at some point
+    pub alternate_screen_used: bool,
+    /// The main screen just before the program last switched to the
+    /// alternate screen, if it ever did
+    pub main_left: Option<Frame>,
+    /// The alternate screen just before the program last left it, if it
+    /// ever did
+    pub alternate_left: Option<Frame>,
+    /// Screen after each chunk of output (empty unless
+    /// `RunConfig::record_frames` is set)
+    pub frames: Vec<Frame>,
+    /// Screen at every multiple of `RunConfig::snapshot_interval`, until the
+    /// output was fully read (empty unless the interval is set)
+    pub snapshots: Vec<Frame>,
+    /// First screen showing each of `RunConfig::snapshot_patterns`, in the
+    /// same order; None for patterns that never appeared
+    pub pattern_snapshots: Vec<Option<Frame>>,
+    /// The output went past `RunConfig::max_output_bytes`; `output` holds
+    /// only the bytes up to the limit
+    pub truncated: bool,
+    /// Exit status of the program, or None if it was killed on timeout
+    pub exit_status: Option<ExitStatus>,
+    /// Which timeout killed the program, if any
+    pub timed_out: Option<TimeoutKind>,
+    /// True if `wait_for` or a script `expect` step timed out
+    pub wait_timed_out: bool,
+    /// Bytes forwarded from standard input with `RunConfig::interactive`,
+    /// as read, with the time since spawn they were sent at
+    pub typed: Vec<(Duration, Vec<u8>)>,
+    /// Bytes sent to the program: stdin, keyboard input and script steps,
+    /// and replies to its queries
+    pub input_sent: usize,
+    /// How many of `input_sent` were written while the program's side of the
+    /// PTY was still open; the rest never reached it (see
+    /// `CaptureResult::input_unconsumed`)
+    pub input_written: usize,
+    /// The write error that stopped the input, if one did
+    pub input_error: Option<String>,
+}
+
+impl CaptureResult {
+    /// How the run ended in one word: the exit code, or else TIMEOUT,
+    /// IDLE_TIMEOUT, INTERRUPTED, OUTPUT_LIMIT or ENDED for the
+    /// `TimeoutKind` that stopped the program
+    pub fn exit_label(&self) -> String {
+        match (&self.exit_status, self.timed_out) {
+            (Some(status), _) => status.exit_code().to_string(),
+            (None, Some(TimeoutKind::Idle)) => "IDLE_TIMEOUT".to_string(),
+            (None, Some(TimeoutKind::Interrupted)) => "INTERRUPTED".to_string(),
+            (None, Some(TimeoutKind::OutputLimit)) => "OUTPUT_LIMIT".to_string(),
+            (None, Some(TimeoutKind::Ended)) => "ENDED".to_string(),
+            (None, Some(TimeoutKind::Absolute) | None) => "TIMEOUT".to_string(),
+        }
+    }
+
+    /// True if part of the input never reached the program, typically
+    /// because it exited before reading it; `input_written` is the offset
+    /// the input got to
+    pub fn input_unconsumed(&self) -> bool {
+        self.input_written < self.input_sent
+    }
+
+    /// Description of the signal that terminated the program, if one did
+    pub fn signal(&self) -> Option<String> {
+        // portable-pty only exposes the signal through its Display output
+        self.exit_status.as_ref().and_then(|status| {
+            status
+                .to_string()
+                .strip_prefix("Terminated by ")
+                .map(str::to_string)
+        })
+    }
+}
+
+/// Why a program was killed before it exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// The absolute `RunConfig::timeout` elapsed
+    Absolute,
+    /// No output arrived for `RunConfig::idle_timeout`
+    Idle,
+    /// The runner was interrupted (see `interrupt::forward_interrupts`)
+    Interrupted,
+    /// The program wrote more than `RunConfig::max_output_bytes`
+    OutputLimit,
+    /// `terminal::END_SESSION_KEY` ended an interactive session
+    Ended,
+}
+
+/// Rows of scrollback tracked internally to count how far the output
+/// scrolled, even when no scrollback was requested
+pub const SCROLL_COUNT_LIMIT: usize = 10_000;
+
+/// The program could not be started (as opposed to failing once running)
+#[derive(Debug)]
+pub struct SpawnError(pub anyhow::Error);
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to spawn command: {:#}", self.0)
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+/// Result of `PtyRunner::run`
+pub type RunResult = CaptureResult;
+
+/// Runs captures with one configuration, for in-process callers that run
+/// the same program repeatedly (each `run` starts a fresh child)
+#[derive(Debug, Clone)]
+pub struct PtyRunner {
+    config: RunConfig,
+}
+
+impl PtyRunner {
+    pub fn new(config: RunConfig) -> Self {
+        Self { config }
+    }
+
+    /// The configuration every run uses
+    pub fn config(&self) -> &RunConfig {
+        &self.config
+    }
+
+    /// Run the program once; see `run`
+    pub fn run(&self) -> Result<RunResult> {
+        run(&self.config)
+    }
+}
+
+/// Run a program in a PTY as described by `config` and capture its screen
+/// Progress is reported on stderr; a failed spawn is returned as `SpawnError`
+pub fn run(config: &RunConfig) -> Result<CaptureResult> {
+    if interrupted() {
+        anyhow::bail!("Interrupted before the program was started");
+    }
+
+    // Create PTY system
+    let pty_system = native_pty_system();
+
+    // Create PTY pair with specified size
+    let pair = pty_system
+        .openpty(PtySize {
+            rows: config.rows,
+            cols: config.cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .context("Failed to open PTY")?;
+
+    log!(config.log_prefix, "PTY opened successfully");
+
+    // Build command with consistent TERM environment
+    let mut cmd = match &config.stderr_file {
+        Some(path) => stderr_redirect_command(config, path)?,
+        None => CommandBuilder::new(&config.executable),
+    };
+    // Forward program arguments as-is (no String round-trip, quoting is handled per platform)
+    cmd.args(&config.args);
+    if config.clear_env {
+        cmd.env_clear();
+    }
+    cmd.env("TERM", &config.term); // Ensure consistent terminal type across platforms
+
+    // Run the child in the requested directory, failing early if it is missing
+    if let Some(dir) = &config.working_dir {
+        if !dir.is_dir() {
+            anyhow::bail!("Working directory does not exist: {:?}", dir);
+        }
+        cmd.cwd(dir);
+    }
+    
+    // On Windows, set environment variable to hint UTF-8 encoding
+    // Note: This may not fully solve ConPTY code page issues, but helps with some programs
+    #[cfg(windows)]
+    {
+        cmd.env("CHCP", "65001");
+        cmd.env("LANG", "en_US.UTF-8");
+        cmd.env("LC_ALL", "en_US.UTF-8");
+    }
+
+    // Apply user environment last so it can override the defaults above
+    for key in &config.env_remove {
+        cmd.env_remove(key);
+    }
+    for (key, value) in &config.env {
+        cmd.env(key, value);
+    }
+
+    // Open the raw log before spawning so a bad path fails the run early
+    let raw_log = match &config.raw_log {
+        Some(path) => Some(RawLog {
+            file: fs::File::create(path)
+                .with_context(|| format!("Failed to create raw log: {:?}", path))?,
+            timestamps: config.raw_log_timestamps,
+        }),
+        None => None,
+    };
+
+    let mirror: Option<Box<dyn Write>> = match &config.live {
+        Some(LiveOutput::Stdout) => Some(Box::new(std::io::stdout())),
+        Some(LiveOutput::File(path)) => Some(Box::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open live output: {:?}", path))?,
+        )),
+        None => None,
+    };
+
+    // Spawn child process in PTY
+    let mut child = pair.slave.spawn_command(cmd).map_err(SpawnError)?;
+    let tree = if config.kill_tree {
+        ProcessTree::attach(child.as_ref())
+    } else {
+        None
+    };
+
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+    log!(config.log_prefix, "Child process spawned");
+
+    // Get master for I/O (kept by the capture to resize the PTY); our copy of
+    // the slave is closed so the reader sees EOF as soon as the child (and
+    // anything it spawned) has exited
+    let master = pair.master;
+    drop(pair.slave);
+
+    // Note: Both Windows ConPTY and Unix PTY echo stdin by default
+    // This is the expected behavior - students see what they type
+    // Golden files should include echoed stdin for realistic testing
+
+    // Clone reader for output capture thread
+    let mut reader = master
+        .try_clone_reader()
+        .context("Failed to clone PTY reader")?;
+
+    // Get writer for sending input, which writes from its own thread
+    let mut writer = InputWriter::spawn(
+        master.take_writer().context("Failed to get PTY writer")?,
+        config.log_prefix.clone(),
+    );
+
+    let input = writer.progress();
+
+    // Use a channel to communicate output chunks from the reader thread,
+    // stamped with the time they were read
+    let (tx, rx) = mpsc::channel::<(Instant, Vec<u8>)>();
+    let mut capture = OutputCapture::new(rx, started, config);
+    if records_frames(config) {
+        capture.frames = Some(FrameRecorder::new(config));
+    }
+    if config.query_replies {
+        capture.queries = Some(QueryScanner::new());
+    }
+    capture.log_prefix = config.log_prefix.clone();
+    capture.raw_log = raw_log;
+    capture.mirror = mirror;
+    let mut resizes = config.resizes.clone();
+    resizes.sort_by_key(|resize| resize.at);
+    capture.resizer = Some(Resizer {
+        master,
+        pending: resizes.into(),
+    });
+
+    // Spawn thread to read output (this thread may block indefinitely on Windows)
+    let terminal_input = Arc::clone(&input);
+    let output_handle = thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break, // EOF
+                Ok(n) => {
+                    if tx.send((Instant::now(), buf[..n].to_vec())).is_err() {
+                        break; // Receiver dropped
+                    }
+                }
+                Err(_) => break, // Error, likely PTY closed
+            }
+        }
+        // Nothing reads input written from now on
+        terminal_input.terminal_closed.store(true, Ordering::SeqCst);
+    });
+
+    if config.input_order == InputOrder::StdinFirst {
+        write_stdin(config, &mut writer)?;
+    }
+
+    // Let the program start: for a fixed delay, or until its output settles
+    let settle_deadline = started + config.timeout;
+    match config.settle {
+        Some(quiet) => capture.settle(quiet, settle_deadline, &mut writer),
+        None => thread::sleep(config.startup_delay),
+    }
+
+    // Optionally wait for the program to draw its prompt; on timeout, no
+    // keyboard input is sent at all
+    let mut wait_ok = true;
+    if let Some(pattern) = &config.wait_for {
+        wait_ok = capture.wait_for(pattern, config.wait_timeout, &mut writer);
+    }
+
+    // Send each keyboard input, `keyboard_delay` apart
+    for (i, kb_data) in config.keyboard_input.iter().enumerate().filter(|_| wait_ok) {
+        if i > 0 {
+            capture.pump_for(config.keyboard_delay, &mut writer);
+        }
+        if interrupted() {
+            break;
+        }
+        send_keyboard_input(config, kb_data, &mut writer, &mut capture, settle_deadline)?;
+    }
+
+    // Run keyboard script if provided, honoring its sleep directives
+    if let Some(steps) = config.keyboard_script.as_ref().filter(|_| wait_ok) {
+        wait_ok = run_keyboard_script(
+            steps,
+            &mut writer,
+            child.as_mut(),
+            &mut capture,
+            config.wait_timeout,
+            config.type_interval,
+        )?;
+    }
+
+    if config.input_order == InputOrder::KeyboardFirst {
+        write_stdin(config, &mut writer)?;
+    }
+
+    // Wait for child with timeout, receiving output meanwhile so the idle
+    // timeout can be measured from the most recent chunk
+    let timeout = config.timeout;
+    let start = Instant::now();
+    let mut exit_status = None;
+    let mut timed_out = None;
+    let mut run_time = None;
+    let mut eof_due = config.send_eof.map(|after| started + after);
+    let keyboard = config.interactive.then(read_keyboard);
+    let mut typed = Vec::new();
+    let mut session_ended = false;
+
+    loop {
+        if eof_due.is_some_and(|due| Instant::now() >= due) {
+            log!(
+                config.log_prefix,
+                "Sending EOF at {} ms",
+                started.elapsed().as_millis()
+            );
+            writer.send_eof();
+            eof_due = None;
+        }
+        while let Some(Ok(mut keys)) = keyboard.as_ref().map(mpsc::Receiver::try_recv) {
+            if let Some(end) = keys.iter().position(|&byte| byte == END_SESSION_KEY) {
+                keys.truncate(end);
+                session_ended = true;
+            }
+            if !keys.is_empty() {
+                writer.write_all(&keys)?;
+                typed.push((started.elapsed(), keys));
+            }
+            if session_ended {
+                break;
+            }
+        }
+        capture.pump(&mut writer);
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                run_time = Some(started.elapsed());
+                log!(config.log_prefix, "Child process exited: {}", status);
+                exit_status = Some(status);
+                break; // Process exited
+            }
+            Ok(None) => {
+                let idle = config
+                    .idle_timeout
+                    .filter(|&idle| capture.last_received.elapsed() > idle);
+                if interrupted() {
+                    log!(config.log_prefix, "Interrupted, stopping process");
+                    timed_out = Some(TimeoutKind::Interrupted);
+                } else if capture.truncated {
+                    log!(config.log_prefix, "Output limit reached, stopping process");
+                    timed_out = Some(TimeoutKind::OutputLimit);
+                } else if session_ended {
+                    log!(config.log_prefix, "Session ended, stopping process");
+                    timed_out = Some(TimeoutKind::Ended);
+                } else if config.interactive {
+                    // The person at the keyboard decides when to stop
+                } else if start.elapsed() > timeout {
+                    log!(config.log_prefix, "Timeout reached, stopping process");
+                    timed_out = Some(TimeoutKind::Absolute);
+                } else if let Some(idle) = idle {
+                    log!(
+                        config.log_prefix,
+                        "Idle timeout reached (no output for {} ms), stopping process",
+                        idle.as_millis()
+                    );
+                    timed_out = Some(TimeoutKind::Idle);
+                }
+                if let Some(kind) = timed_out {
+                    run_time = Some(started.elapsed());
+                    // An ended session always gives the program a chance to
+                    // exit on its own
+                    let grace = match kind {
+                        TimeoutKind::Ended => config.kill_grace.max(ENDED_SESSION_GRACE),
+                        _ => config.kill_grace,
+                    };
+                    // Stop the process (exit_status stays None to mark the timeout)
+                    stop_child(child.as_mut(), &mut writer, grace, &config.log_prefix);
+                    // Forked helpers could otherwise keep the PTY open
+                    if let Some(tree) = &tree {
+                        tree.kill();
+                    }
+                    break;
+                }
+                // Poll faster while someone is typing, so echoes show promptly
+                thread::sleep(Duration::from_millis(if config.interactive {
+                    10
+                } else {
+                    50
+                }));
+            }
+            Err(_) => break,
+        }
+    }
+
+    // Drop writer and master (ConPTY only ends the output once the console
+    // closes; the input thread lets go of the PTY writer once its write in
+    // progress returns), then read until the reader thread reports EOF, with the drain
+    // timeout as a safety net in case it blocks (as it may on Windows)
+    drop(writer);
+    drop(capture.resizer.take());
+    if capture.drain(Instant::now() + config.drain_timeout) {
+        // The reader has hit EOF and dropped its sender, so the join returns
+        // at once; a reader still blocked after the timeout is left detached
+        let _ = output_handle.join();
+    } else {
+        log!(
+            config.log_prefix,
+            "Drain timeout: no end of output within {} ms, output may be incomplete",
+            config.drain_timeout.as_millis()
+        );
+    }
+    let duration = started.elapsed();
+    let run_time = run_time.unwrap_or(duration);
+    let input_sent = input.sent.load(Ordering::SeqCst);
+    let input_written = input.written.load(Ordering::SeqCst);
+    let input_error = input.error.lock().unwrap().clone();
+    if input_written < input_sent {
+        log!(
+            config.log_prefix,
+            "WARN: the program never got {} of {} input bytes (input stopped at byte offset {}{})",
+            input_sent - input_written,
+            input_sent,
+            input_written,
+            match &input_error {
+                Some(e) => format!(": {}", e),
+                None if input.terminal_closed.load(Ordering::SeqCst) => {
+                    ", after its terminal closed".to_string()
+                }
+                None => ", when the run ended".to_string(),
+            }
+        );
+    }
+    let output = capture.output;
+    let chunks = capture.chunks;
+    let capture_truncated = capture.truncated;
+    let recorded = capture
+        .frames
+        .map(FrameRecorder::finish)
+        .unwrap_or_default();
+
+    log!(
+        config.log_prefix,
+        "Captured {} bytes of output",
+        output.len()
+    );
+
+    // Each chunk went through the emulator as it arrived; only what the
+    // filter still holds is left
+    let emulated = finish_emulation(config, capture.emulator);
+
+    Ok(CaptureResult {
+        output,
+        started_at,
+        duration,
+        run_time,
+        filtered_len: emulated.filtered_len,
+        chunks,
+        screen: emulated.screen,
+        scrollback: emulated.scrollback,
+        scrollback_extra_attrs: emulated.scrollback_extra_attrs,
+        scrolled_off: emulated.scrolled_off,
+        title: emulated.title,
+        extra_attrs: emulated.extra_attrs,
+        alternate_screen_used: emulated.alternate_used,
+        main_left: emulated.main_left,
+        alternate_left: emulated.alternate_left,
+        frames: recorded.frames,
+        snapshots: recorded.snapshots,
+        pattern_snapshots: recorded.matches,
+        truncated: capture_truncated,
+        exit_status,
+        timed_out,
+        wait_timed_out: !wait_ok,
+        typed,
+        input_sent,
+        input_written,
+        input_error,
+    })
+}
+
+/// Emulate output captured earlier, without running anything: each chunk
+/// goes through the same filtering, normalization and snapshots as in `run`,
+/// at the time since spawn it was read
+/// Nothing exits, so the result has no exit status; `started_at` is now
+pub fn replay(config: &RunConfig, chunks: &[(Duration, &[u8])]) -> CaptureResult {
+    let mut emulator = Emulator::new(config, config.scrollback.max(SCROLL_COUNT_LIMIT));
+    let mut frames = records_frames(config).then(|| FrameRecorder::new(config));
+    let mut output = Vec::new();
+    let mut lengths = Vec::new();
+    for &(elapsed, chunk) in chunks {
+        emulator.feed(elapsed, chunk);
+        if let Some(frames) = &mut frames {
+            frames.record(elapsed, chunk);
+        }
+        lengths.push((elapsed, chunk.len()));
+        output.extend_from_slice(chunk);
+    }
+    let recorded = frames.map(FrameRecorder::finish).unwrap_or_default();
+    let duration = chunks
+        .last()
+        .map_or(Duration::ZERO, |(elapsed, _)| *elapsed);
+    let emulated = finish_emulation(config, emulator);
+
+    CaptureResult {
+        output,
+        started_at: SystemTime::now(),
+        duration,
+        run_time: duration,
+        filtered_len: emulated.filtered_len,
+        chunks: lengths,
+        screen: emulated.screen,
+        scrollback: emulated.scrollback,
+        scrollback_extra_attrs: emulated.scrollback_extra_attrs,
+        scrolled_off: emulated.scrolled_off,
+        title: emulated.title,
+        extra_attrs: emulated.extra_attrs,
+        alternate_screen_used: emulated.alternate_used,
+        main_left: emulated.main_left,
+        alternate_left: emulated.alternate_left,
+        frames: recorded.frames,
+        snapshots: recorded.snapshots,
+        pattern_snapshots: recorded.matches,
+        truncated: false,
+        exit_status: None,
+        timed_out: None,
+        wait_timed_out: false,
+        typed: Vec::new(),
+        input_sent: 0,
+        input_written: 0,
+        input_error: None,
+    }
+}
+
+/// True if `config` asks for any per-chunk or interval screen snapshots
+fn records_frames(config: &RunConfig) -> bool {
+    config.record_frames
+        || config.snapshot_interval.is_some()
+        || !config.snapshot_patterns.is_empty()
+}
+
+/// The final screen and what was read off the emulator with it
+struct Emulated {
+    screen: vt100::Screen,
+    scrollback: Vec<Vec<vt100::Cell>>,
+    scrollback_extra_attrs: Vec<Vec<u8>>,
+    scrolled_off: usize,
+    title: Option<String>,
+    extra_attrs: vt100::Screen,
+    filtered_len: usize,
+    alternate_used: bool,
+    main_left: Option<Frame>,
+    alternate_left: Option<Frame>,
+}
+
+/// Flush the emulator, then take the screen and up to `config.scrollback`
+/// rows of scrollback from it
+fn finish_emulation(config: &RunConfig, mut emulator: Emulator) -> Emulated {
+    emulator.finish();
+    let title = emulator.filter.title().map(str::to_string);
+    let filtered_len = emulator.filtered_len;
+    log!(
+        config.log_prefix,
+        "After filtering OSC: {} bytes",
+        filtered_len
+    );
+    let mut parser = emulator.parser;
+    let mut extra_attrs = emulator.extra_attrs;
+    let extra_screen = extra_attrs.screen().clone();
+    let (rows, cols) = parser.screen().size();
+
+    let screen = parser.screen().clone();
+    parser.set_scrollback(usize::MAX);
+    let scrolled_off = parser.screen().scrollback();
+    parser.set_scrollback(0);
+    let scrollback = take_scrollback(&mut parser, rows, cols, config.scrollback);
+    // The shadow parser keeps the same rows, so its cells line up with these
+    let scrollback_extra_attrs =
+        take_scrollback(extra_attrs.parser_mut(), rows, cols, config.scrollback)
+            .iter()
+            .map(|row| row.iter().map(cell_attr_bits).collect())
+            .collect();
+    if scrolled_off > scrollback.len() {
+        let at_least = if scrolled_off >= SCROLL_COUNT_LIMIT {
+            "at least "
+        } else {
+            ""
+        };
+        let kept = if scrollback.is_empty() {
+            String::new()
+        } else {
+            format!(" ({} kept in scrollback)", scrollback.len())
+        };
+        log!(
+            config.log_prefix,
+            "WARN: {}{} lines scrolled off; screen only shows last {}{}",
+            at_least,
+            scrolled_off,
+            rows,
+            kept
+        );
+    } else if config.scrollback > 0 {
+        log!(
+            config.log_prefix,
+            "Scrollback: {} rows scrolled off",
+            scrollback.len()
+        );
+    }
+
+    Emulated {
+        screen,
+        scrollback,
+        scrollback_extra_attrs,
+        scrolled_off,
+        title,
+        extra_attrs: extra_screen,
+        filtered_len,
+        alternate_used: emulator.alternate_used,
+        main_left: emulator.main_left,
+        alternate_left: emulator.alternate_left,
+    }
+}
+
+/// Command that runs the program with stderr sent to `path`
+/// portable-pty attaches all three standard streams to the PTY and closes
+/// every other inherited descriptor, so the redirect is made by a shell that
+/// then execs the program (keeping its pid); the file is created here first
+/// so that a bad path is reported before spawning
+#[cfg(unix)]
+fn stderr_redirect_command(config: &RunConfig, path: &std::path::Path) -> Result<CommandBuilder> {
+    // The child may run elsewhere, so hand it an absolute path
+    let path = std::env::current_dir()?.join(path);
+    std::fs::File::create(&path)
+        .with_context(|| format!("Failed to create stderr file: {:?}", path))?;
+    let mut cmd = CommandBuilder::new("/bin/sh");
+    cmd.arg("-c");
+    cmd.arg(r#"f=$1; shift; exec "$@" 2>"$f""#);
+    cmd.arg("sh");
+    cmd.arg(path);
+    cmd.arg(&config.executable);
+    Ok(cmd)
+}
+
+#[cfg(not(unix))]
+fn stderr_redirect_command(_config: &RunConfig, _path: &std::path::Path) -> Result<CommandBuilder> {
+    anyhow::bail!("Separating stderr is only supported on Unix")
+}
+
+/// OSC filter as configured; titles are recorded even when nothing is removed
+fn osc_filter_for(config: &RunConfig) -> OscFilter {
+    if !config.osc_filter {
+        OscFilter::passthrough()
+    } else if config.keep_osc {
+        OscFilter::keeping_osc()
+    } else {
+        OscFilter::new()
+    }
+}
+
+/// Copy out the newest `limit` rows of the parser's scrollback, oldest first
+/// vt100 cannot scroll the view back further than the screen height, so the
+/// screen is grown to fit first; call this only after copying the screen
+fn take_scrollback(
+    parser: &mut vt100::Parser,
+    rows: u16,
+    cols: u16,
+    limit: usize,
+) -> Vec<Vec<vt100::Cell>> {
+    parser.set_scrollback(limit);
+    let available = parser.screen().scrollback();
+    parser.set_scrollback(0);
+    if available == 0 {
+        return Vec::new();
+    }
+
+    let height = (usize::from(rows) + available).min(usize::from(u16::MAX)) as u16;
+    let kept = available.min(usize::from(height - rows));
+    parser.set_size(height, cols);
+    parser.set_scrollback(kept);
+    (0..kept as u16)
+        .map(|row| {
+            (0..cols)
+                .filter_map(|col| parser.screen().cell(row, col).cloned())
+                .collect()
+        })
+        .collect()
+}
+
+/// Send `RunConfig::stdin`, if any
+fn write_stdin(config: &RunConfig, writer: &mut dyn Write) -> Result<()> {
+    if let Some(stdin_content) = &config.stdin {
+        // Windows programs need CRLF for scanf, so that is its default mode;
+        // on Unix input is sent as-is unless another mode is requested
+        let data_to_send = convert_newlines(stdin_content, config.input_newlines);
+        writer.write_all(&data_to_send)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Run a keyboard script against the PTY writer
+/// Sleeps are cut short (and the script abandoned) once the child has exited
+/// Returns false if an `expect` step timed out, in which case the rest of the
+/// script is skipped
+fn run_keyboard_script(
+    steps: &[ScriptStep],
+    writer: &mut dyn Write,
+    child: &mut dyn portable_pty::Child,
+    capture: &mut OutputCapture,
+    wait_timeout: Duration,
+    type_interval: Option<Duration>,
+) -> Result<bool> {
+    for step in steps {
+        match step {
+            ScriptStep::Send(data) => match type_interval {
+                Some(interval) => type_keys(data, interval, writer, capture)?,
+                None => {
+                    writer.write_all(data)?;
+                    writer.flush()?;
+                }
+            },
+            ScriptStep::Sleep(ms) => {
+                let deadline = Instant::now() + Duration::from_millis(*ms);
+                while Instant::now() < deadline && !interrupted() {
+                    if let Ok(Some(_)) = child.try_wait() {
+                        log!(
+                            capture.log_prefix,
+                            "Child exited during keyboard script, stopping script"
+                        );
+                        return Ok(true);
+                    }
+                    capture.pump(writer);
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+            ScriptStep::Expect(text) => {
+                let pattern = ScreenPattern::Text(text.clone());
+                if !capture.wait_for(&pattern, wait_timeout, writer) {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Send one keyboard input: typed, byte by byte or line by line with delays
+/// This ensures proper echo timing across platforms
+fn send_keyboard_input(
+    config: &RunConfig,
+    kb_data: &[u8],
+    writer: &mut dyn Write,
+    capture: &mut OutputCapture,
+    settle_deadline: Instant,
+) -> Result<()> {
+    if let Some(interval) = config.type_interval {
+        // Key by key, for programs that treat a paste differently
+        let data_to_send = convert_newlines(kb_data, config.input_newlines);
+        type_keys(&data_to_send, interval, writer, capture)?;
+    } else if !config.send_delay.is_zero() {
+        // Byte-by-byte mode for programs that react to each keypress
+        let data_to_send = convert_newlines(kb_data, config.input_newlines);
+
+        for (i, byte) in data_to_send.into_iter().enumerate() {
+            if let Some(quiet) = config.settle.filter(|_| i > 0) {
+                capture.settle(quiet, settle_deadline, writer);
+            }
+            writer.write_all(&[byte])?;
+            writer.flush()?;
+            thread::sleep(config.send_delay);
+            capture.pump(writer);
+        }
+    } else {
+        // Split by newlines and send each line separately with a delay
+        // This gives the program time to process each input and echo before next input
+        let mut start_idx = 0;
+        for (i, &byte) in kb_data.iter().enumerate() {
+            if byte == b'\n' {
+                // Include the newline in this chunk
+                let line = &kb_data[start_idx..=i];
+                
+                let data_to_send = convert_newlines(line, config.input_newlines);
+                
+                writer.write_all(&data_to_send)?;
+                writer.flush()?;
+                
+                // Delay between lines to allow program to process and echo
+                match config.settle {
+                    Some(quiet) => capture.settle(quiet, settle_deadline, writer),
+                    None => thread::sleep(Duration::from_millis(50)),
+                }
+                capture.pump(writer);
+                
+                start_idx = i + 1;
+            }
+        }
+        // Send any remaining data after the last newline
+        if start_idx < kb_data.len() {
+            let remaining = &kb_data[start_idx..];
+            
+            let data_to_send = convert_newlines(remaining, config.input_newlines);
+            
+            writer.write_all(&data_to_send)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `data` one key at a time, `interval` apart, receiving output
+/// meanwhile; an interrupt drops the keys not yet typed
+fn type_keys(
+    data: &[u8],
+    interval: Duration,
+    writer: &mut dyn Write,
+    capture: &mut OutputCapture,
+) -> Result<()> {
+    for (i, key) in split_keys(data).into_iter().enumerate() {
+        if i > 0 {
+            capture.pump_for(interval, writer);
+        }
+        if interrupted() {
+            return Ok(());
+        }
+        writer.write_all(key)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// How long a program may take to exit after Ctrl-C when an interactive
+/// session ends, unless `RunConfig::kill_grace` is longer
+const ENDED_SESSION_GRACE: Duration = Duration::from_secs(1);
+
+/// Read the runner's standard input on a thread of its own, passing on
+/// each chunk as it arrives
+/// The thread stays blocked in its read after the run is over; the runner
+/// exits without waiting for it
+fn read_keyboard() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = [0u8; 1024];
+        while let Ok(n @ 1..) = stdin.read(&mut buf) {
+            if tx.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Stop a timed-out child: send Ctrl-C (a SIGINT to the foreground process
+/// group on Unix, a console Ctrl-C on Windows), then kill it if it is still
+/// running once `grace` has passed
+/// With the input already closed (`InputWriter::send_eof` on Windows) no
+/// Ctrl-C can reach the program, so it is killed without waiting
+fn stop_child(
+    child: &mut (dyn Child + Send + Sync),
+    writer: &mut InputWriter,
+    grace: Duration,
+    log_prefix: &str,
+) {
+    if !grace.is_zero() && writer.is_closed() {
+        log!(
+            log_prefix,
+            "Input already closed, so Ctrl-C cannot be sent; killing without the {} ms grace",
+            grace.as_millis()
+        );
+    } else if !grace.is_zero() {
+        let _ = writer.write_all(&[0x03]).and_then(|()| writer.flush());
+        log!(
+            log_prefix,
+            "Sent Ctrl-C, waiting up to {} ms for the program to exit",
+            grace.as_millis()
+        );
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            if let Ok(Some(status)) = child.try_wait() {
+                log!(log_prefix, "Child process exited after Ctrl-C: {}", status);
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        log!(
+            log_prefix,
+            "Child process still running {} ms after Ctrl-C",
+            grace.as_millis()
+        );
+    }
+    let _ = child.kill();
+    log!(log_prefix, "Child process killed");
+}
+
+/// Output received from the reader thread so far
+/// Chunks are fed to the emulator that builds the final screen as they
+/// arrive, and to a live parser of the unfiltered output that query replies
+/// and waits look at while the child is still running
+struct OutputCapture {
+    rx: mpsc::Receiver<(Instant, Vec<u8>)>,
+    started: Instant,
+    output: Vec<u8>,
+    chunks: Vec<(Duration, usize)>,
+    /// When the latest chunk was read (the spawn time until then)
+    last_received: Instant,
+    live: vt100::Parser,
+    /// Builds the final screen
+    emulator: Emulator,
+    /// Per-chunk screen snapshots, when requested
+    frames: Option<FrameRecorder>,
+    /// Finds terminal queries to answer, when enabled
+    queries: Option<QueryScanner>,
+    /// Replies to queries seen but not yet written to the PTY
+    replies: Vec<u8>,
+    /// Resizes still to make, with the PTY to make them on
+    resizer: Option<Resizer>,
+    raw_log: Option<RawLog>,
+    /// Mirror of the output (`RunConfig::live`)
+    mirror: Option<Box<dyn Write>>,
+    /// Most output to keep (`RunConfig::max_output_bytes`)
+    limit: Option<usize>,
+    /// Output past `limit` arrived and was dropped
+    truncated: bool,
+    log_prefix: String,
+}
+
+/// The PTY master and the resizes not yet made on it
+struct Resizer {
+    master: Box<dyn MasterPty + Send>,
+    pending: VecDeque<Resize>,
+}
+
+impl OutputCapture {
+    fn new(rx: mpsc::Receiver<(Instant, Vec<u8>)>, started: Instant, config: &RunConfig) -> Self {
+        Self {
+            rx,
+            started,
+            output: Vec::new(),
+            chunks: Vec::new(),
+            last_received: started,
+            live: vt100::Parser::new(config.rows, config.cols, 0),
+            // Scrollback only affects rows that have left the screen, so
+            // tracking more than requested leaves the screen untouched and
+            // lets us count them
+            emulator: Emulator::new(config, config.scrollback.max(SCROLL_COUNT_LIMIT)),
+            frames: None,
+            queries: None,
+            replies: Vec::new(),
+            resizer: None,
+            raw_log: None,
+            mirror: None,
+            limit: config.max_output_bytes,
+            truncated: false,
+            log_prefix: String::new(),
+        }
+    }
+
+    /// Take all chunks currently available without blocking, then write the
+    /// replies to any queries in them
+    /// Returns false once the reader thread has gone away
+    fn pump(&mut self, writer: &mut dyn Write) -> bool {
+        let connected = loop {
+            match self.rx.try_recv() {
+                Ok((received, chunk)) => self.record(received, chunk),
+                Err(mpsc::TryRecvError::Empty) => break true,
+                Err(mpsc::TryRecvError::Disconnected) => break false,
+            }
+        };
+        self.snapshot_due(Instant::now());
+        self.resize_due();
+        if !self.replies.is_empty() {
+            let _ = writer
+                .write_all(&self.replies)
+                .and_then(|()| writer.flush());
+            self.replies.clear();
+        }
+        connected
+    }
+
+    /// Keep receiving chunks for `duration`, or until an interrupt
+    fn pump_for(&mut self, duration: Duration, writer: &mut dyn Write) {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.pump(writer);
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || interrupted() {
+                return;
+            }
+            thread::sleep(left.min(Duration::from_millis(10)));
+        }
+    }
+
+    /// Receive chunks until the reader thread goes away (end of output)
+    /// Returns false if `deadline` passed first
+    fn drain(&mut self, deadline: Instant) -> bool {
+        loop {
+            let now = Instant::now();
+            self.snapshot_due(now);
+            // Wake up for snapshots that fall due while no output arrives
+            let mut wait = deadline.saturating_duration_since(now);
+            if let Some(due) = self.frames.as_ref().and_then(FrameRecorder::next_snapshot) {
+                wait = wait.min((self.started + due).saturating_duration_since(now));
+            }
+            match self.rx.recv_timeout(wait) {
+                Ok((received, chunk)) => self.record(received, chunk),
+                Err(mpsc::RecvTimeoutError::Timeout) if Instant::now() >= deadline => return false,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return true,
+            }
+        }
+    }
+
+    /// Make the resizes that have fallen due; output received so far is
+    /// emulated at the old size
+    fn resize_due(&mut self) {
+        let Some(resizer) = &mut self.resizer else {
+            return;
+        };
+        let elapsed = self.started.elapsed();
+        while let Some(resize) = resizer.pending.front().filter(|r| r.at <= elapsed) {
+            let size = PtySize {
+                rows: resize.rows,
+                cols: resize.cols,
+                pixel_width: 0,
+                pixel_height: 0,
+            };
+            match resizer.master.resize(size) {
+                Ok(()) => {
+                    log!(
+                        self.log_prefix,
+                        "Resized terminal to {}x{} at {} ms",
+                        resize.cols,
+                        resize.rows,
+                        elapsed.as_millis()
+                    );
+                    self.live.set_size(resize.rows, resize.cols);
+                    self.emulator.set_size(resize.rows, resize.cols);
+                    if let Some(frames) = &mut self.frames {
+                        frames.set_size(resize.rows, resize.cols);
+                    }
+                }
+                Err(e) => log!(self.log_prefix, "WARN: failed to resize terminal: {}", e),
+            }
+            resizer.pending.pop_front();
+        }
+    }
+
+    /// Take the interval snapshots due by `now`
+    fn snapshot_due(&mut self, now: Instant) {
+        if let Some(frames) = &mut self.frames {
+            frames.snapshot_until(now.duration_since(self.started));
+        }
+    }
+
+    fn record(&mut self, received: Instant, mut chunk: Vec<u8>) {
+        self.last_received = received;
+        // Past the limit, output is dropped before it reaches any buffer
+        if let Some(limit) = self.limit {
+            let room = limit.saturating_sub(self.output.len());
+            if chunk.len() > room {
+                if !self.truncated {
+                    log!(
+                        self.log_prefix,
+                        "WARN: output exceeded {} bytes, the rest is dropped",
+                        limit
+                    );
+                }
+                self.truncated = true;
+                chunk.truncate(room);
+            }
+            if chunk.is_empty() {
+                return;
+            }
+        }
+        let elapsed = received.duration_since(self.started);
+        if let Some(raw_log) = &mut self.raw_log {
+            if let Err(e) = raw_log.write(elapsed, &chunk) {
+                log!(
+                    self.log_prefix,
+                    "WARN: failed to write raw log, stopping it: {}",
+                    e
+                );
+                self.raw_log = None;
+            }
+        }
+        if let Some(mirror) = &mut self.mirror {
+            if let Err(e) = mirror.write_all(&chunk).and_then(|()| mirror.flush()) {
+                log!(
+                    self.log_prefix,
+                    "WARN: failed to mirror output, stopping it: {}",
+                    e
+                );
+                self.mirror = None;
+            }
+        }
+        // Process up to each query so its reply sees the cursor at that point
+        let mut processed = 0;
+        if let Some(queries) = &mut self.queries {
+            for (end, query) in queries.feed(&chunk) {
+                self.live.process(&chunk[processed..end]);
+                processed = end;
+                self.replies
+                    .extend(query.reply(self.live.screen().cursor_position()));
+            }
+        }
+        self.live.process(&chunk[processed..]);
+        self.emulator.feed(elapsed, &chunk);
+        if let Some(frames) = &mut self.frames {
+            frames.record(elapsed, &chunk);
+        }
+        self.chunks.push((elapsed, chunk.len()));
+        self.output.extend(chunk);
+    }
+
+    /// Receive output until none has arrived for `quiet`, or until `deadline`
+    /// (or an interrupt), logging how long it took
+    fn settle(&mut self, quiet: Duration, deadline: Instant, writer: &mut dyn Write) {
+        let begun = Instant::now();
+        loop {
+            let connected = self.pump(writer);
+            let now = Instant::now();
+            // Output from before the wait began may not answer the input
+            // just sent yet
+            let quiet_for = now.saturating_duration_since(self.last_received.max(begun));
+            if quiet_for >= quiet || !connected {
+                log!(
+                    self.log_prefix,
+                    "Output settled after {} ms",
+                    begun.elapsed().as_millis()
+                );
+                return;
+            }
+            if now >= deadline || interrupted() {
+                log!(
+                    self.log_prefix,
+                    "Output still arriving after {} ms, sending input anyway",
+                    begun.elapsed().as_millis()
+                );
+                return;
+            }
+            thread::sleep((quiet - quiet_for).min(Duration::from_millis(10)));
+        }
+    }
+
+    /// Block until `pattern` is visible on the live screen or `timeout` elapses
+    fn wait_for(
+        &mut self,
+        pattern: &ScreenPattern,
+        timeout: Duration,
+        writer: &mut dyn Write,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let connected = self.pump(writer);
+            if pattern.is_found_in(&self.live.screen().contents()) {
+                log!(self.log_prefix, "Found {} on screen", pattern);
+                return true;
+            }
+            if interrupted() {
+                log!(self.log_prefix, "Interrupted while waiting for {}", pattern);
+                return false;
+            }
+            if !connected || Instant::now() >= deadline {
+                log!(
+                    self.log_prefix,
+                    "Wait timeout: {} did not appear within {} ms",
+                    pattern,
+                    timeout.as_millis()
+                );
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// File the output is logged to as it arrives
+struct RawLog {
+    file: fs::File,
+    timestamps: bool,
+}
+
+impl RawLog {
+    /// Append `chunk`, flushed so the log survives a hung or killed run
+    fn write(&mut self, elapsed: Duration, chunk: &[u8]) -> std::io::Result<()> {
+        if self.timestamps {
+            self.file.write_all(&raw_log_header(elapsed, chunk.len()))?;
+        }
+        self.file.write_all(chunk)?;
+        self.file.flush()
+    }
+}
+
+/// Largest piece of input written to the PTY at once, so that a long paste
+/// stops soon after the run is over
+const INPUT_PIECE_LEN: usize = 4096;
+
+/// Writes input to the PTY from a thread of its own, so that a program that
+/// stops reading (filling the PTY's input buffer) cannot block the run, which
+/// must keep reading output, answering queries and watching the timeout
+/// Writes return at once and are made in order; after a failed write, or
+/// once the program's side of the PTY has closed, the rest of the input is
+/// dropped, as is whatever is still queued when this is dropped
+struct InputWriter {
+    /// None once the input is closed
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    closed: Arc<AtomicBool>,
+    progress: Arc<InputProgress>,
+    /// The last byte written ended a line (or nothing was written yet)
+    #[cfg(unix)]
+    at_line_start: bool,
+}
+
+impl InputWriter {
+    fn spawn(mut writer: Box<dyn Write + Send>, log_prefix: String) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let closed = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&closed);
+        let progress = Arc::new(InputProgress::default());
+        let written = Arc::clone(&progress);
+        // Returning drops the PTY writer, which ConPTY needs to end the output
+        thread::spawn(move || {
+            for data in rx {
+                for piece in data.chunks(INPUT_PIECE_LEN) {
+                    // A closed terminal still accepts writes on Linux, but
+                    // nothing reads them any more
+                    if stop.load(Ordering::SeqCst) || written.terminal_closed.load(Ordering::SeqCst)
+                    {
+                        return;
+                    }
+                    if let Err(e) = writer.write_all(piece).and_then(|()| writer.flush()) {
+                        log!(
+                            log_prefix,
+                            "WARN: failed to write input, dropping the rest: {}",
+                            e
+                        );
+                        *written.error.lock().unwrap() = Some(e.to_string());
+                        return;
+                    }
+                    written.written.fetch_add(piece.len(), Ordering::SeqCst);
+                }
+            }
+        });
+        Self {
+            tx: Some(tx),
+            closed,
+            progress,
+            #[cfg(unix)]
+            at_line_start: true,
+        }
+    }
+
+    /// Signal end of input: on Unix the VEOF character (Ctrl-D) at the start
+    /// of a line, with one more first to end a partial line; on Windows,
+    /// where ConPTY has no such character, the PTY's input is closed once
+    /// the queued input is written, dropping later writes (and with them
+    /// the Ctrl-C `stop_child` would send)
+    fn send_eof(&mut self) {
+        #[cfg(unix)]
+        {
+            let eof: &[u8] = if self.at_line_start {
+                &[0x04]
+            } else {
+                &[0x04, 0x04]
+            };
+            let _ = self.write_all(eof);
+        }
+        #[cfg(windows)]
+        {
+            self.tx = None;
+        }
+    }
+
+    /// Whether the input has been closed, so that writes are dropped
+    fn is_closed(&self) -> bool {
+        self.tx.is_none()
+    }
+
+    /// How far the input has got, kept up to date by the thread
+    fn progress(&self) -> Arc<InputProgress> {
+        Arc::clone(&self.progress)
+    }
+}
+
+/// How far the input written with an `InputWriter` got
+#[derive(Default)]
+struct InputProgress {
+    /// Bytes queued for writing
+    sent: AtomicUsize,
+    /// Bytes written to the PTY
+    written: AtomicUsize,
+    /// Set once the output has ended, i.e. the program's side of the PTY has
+    /// closed
+    terminal_closed: AtomicBool,
+    /// The write error that stopped the thread, if one did
+    error: Mutex<Option<String>>,
+}
+
+impl Write for InputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        if let Some(&last) = buf.last() {
+            self.at_line_start = matches!(last, b'\n' | b'\r' | 0x04);
+        }
+        // A stopped thread has already logged why
+        if let Some(tx) = &self.tx {
+            self.progress.sent.fetch_add(buf.len(), Ordering::SeqCst);
+            let _ = tx.send(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for InputWriter {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Longest unfinished CSI sequence held back between chunks
+const MAX_PENDING_CSI: usize = 64;
+
+/// The filtering, normalization and emulation applied to the output, fed
+/// chunk by chunk; an escape sequence split between chunks is held back until
+/// its end arrives, so the result is the same as for the output in one piece
+struct Emulator {
+    filter: OscFilter,
+    /// Filtered bytes of an unfinished CSI sequence
+    pending: Vec<u8>,
+    parser: vt100::Parser,
+    extra_attrs: ExtraAttrs,
+    /// Bytes that came out of the OSC filter so far
+    filtered_len: usize,
+    /// Time since spawn of the chunk being emulated
+    elapsed: Duration,
+    /// The alternate screen was switched to at some point
+    alternate_used: bool,
+    /// The main screen just before the alternate screen was last entered
+    main_left: Option<Frame>,
+    /// The alternate screen just before it was last left
+    alternate_left: Option<Frame>,
+}
+
+impl Emulator {
+    fn new(config: &RunConfig, scrollback: usize) -> Self {
+        Self {
+            filter: osc_filter_for(config),
+            pending: Vec::new(),
+            parser: vt100::Parser::new(config.rows, config.cols, scrollback),
+            extra_attrs: ExtraAttrs::with_scrollback(config.rows, config.cols, config.scrollback),
+            filtered_len: 0,
+            elapsed: Duration::ZERO,
+            alternate_used: false,
+            main_left: None,
+            alternate_left: None,
+        }
+    }
+
+    /// Emulate a chunk read `elapsed` after spawn
+    fn feed(&mut self, elapsed: Duration, chunk: &[u8]) {
+        self.elapsed = elapsed;
+        // Filter out OS-specific sequences (e.g., window title OSC from Windows ConPTY)
+        let filtered = self.filter.feed(chunk);
+        self.filtered_len += filtered.len();
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend(filtered);
+        if let Some(start) = unfinished_csi(&data) {
+            self.pending = data.split_off(start);
+        }
+        self.emulate(&data);
+    }
+
+    /// Emulate everything still held back; returns false if there was nothing
+    fn finish(&mut self) -> bool {
+        let rest = self.filter.finish();
+        self.filtered_len += rest.len();
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend(rest);
+        self.emulate(&data);
+        !data.is_empty()
+    }
+
+    fn emulate(&mut self, data: &[u8]) {
+        // Normalize erase sequences to use default colors (Windows ConPTY
+        // issue), then ANSI resets for cross-platform consistency
+        let normalized = normalize_reset_sequences(&normalize_erase_sequences(data));
+        // Each switch between the main and alternate screens is processed on
+        // its own, so the screen being left can be kept as it was last shown
+        let mut rest = &normalized[..];
+        while let Some((start, end)) =
+            find_screen_switch(rest, self.parser.screen().alternate_screen())
+        {
+            self.process(&rest[..start]);
+            let left = self.snapshot(self.elapsed);
+            self.process(&rest[start..end]);
+            rest = &rest[end..];
+            // Only modes vt100 acts on switch (it ignores 1047)
+            if self.parser.screen().alternate_screen() == left.screen.alternate_screen() {
+                continue;
+            }
+            if left.screen.alternate_screen() {
+                self.alternate_left = Some(left);
+            } else {
+                self.main_left = Some(left);
+                self.alternate_used = true;
+            }
+        }
+        self.process(rest);
+    }
+
+    fn process(&mut self, data: &[u8]) {
+        self.parser.process(data);
+        self.extra_attrs.process(data);
+    }
+
+    fn set_size(&mut self, rows: u16, cols: u16) {
+        self.parser.set_size(rows, cols);
+        self.extra_attrs.set_size(rows, cols);
+    }
+
+    /// Frame holding the current screen
+    fn snapshot(&self, elapsed: Duration) -> Frame {
+        Frame {
+            elapsed,
+            screen: self.parser.screen().clone(),
+            extra_attrs: self.extra_attrs.screen().clone(),
+        }
+    }
+}
+
+/// Start and end of the first sequence in `data` that may switch screens: a
+/// DEC private mode set (`h`, from the main screen) or reset (`l`, from the
+/// alternate one) with 47, 1047 or 1049 anywhere in its parameters, such as
+/// `ESC[?1049h` or `ESC[?25;1049h`
+fn find_screen_switch(data: &[u8], alternate: bool) -> Option<(usize, usize)> {
+    let last = if alternate { b'l' } else { b'h' };
+    let mut from = 0;
+    while let Some(offset) = data[from..].windows(3).position(|w| w == b"\x1b[?") {
+        let start = from + offset;
+        let params = &data[start + 3..];
+        let len = params
+            .iter()
+            .position(|&b| !(b.is_ascii_digit() || b == b';'))
+            .unwrap_or(params.len());
+        let switches = params[..len]
+            .split(|&b| b == b';')
+            .any(|mode| matches!(mode, b"47" | b"1047" | b"1049"));
+        if switches && params.get(len) == Some(&last) {
+            return Some((start, start + 3 + len + 1));
+        }
+        from = start + 1;
+    }
+    None
+}
+
+/// Start of a CSI sequence (or lone ESC) at the end of `data` that its final
+/// byte has not reached yet
+fn unfinished_csi(data: &[u8]) -> Option<usize> {
+    let start = data.iter().rposition(|&b| b == 0x1b)?;
+    let rest = &data[start + 1..];
+    let unfinished = match rest.split_first() {
+        None => true,
+        Some((b'[', params)) => params.iter().all(|b| (0x20..=0x3F).contains(b)),
+        Some(_) => false,
+    };
+    (unfinished && data.len() - start <= MAX_PENDING_CSI).then_some(start)
+}
+
+/// Screens after each chunk and at each snapshot interval, built with the
+/// same filtering as the final screen but applied chunk by chunk as output
+/// arrives
+struct FrameRecorder {
+    /// Kept apart from the capture's emulator, without scrollback, so that
+    /// snapshots stay cheap to clone
+    emulator: Emulator,
+    /// Per-chunk frames, if requested
+    frames: Option<Vec<Frame>>,
+    /// Snapshot interval and when the next snapshot is due (since spawn)
+    interval: Option<(Duration, Duration)>,
+    snapshots: Vec<Frame>,
+    /// Patterns to snapshot on, with the screen each first appeared on
+    patterns: Vec<(ScreenPattern, Option<Frame>)>,
+}
+
+/// What a `FrameRecorder` kept
+#[derive(Default)]
+struct Recorded {
+    frames: Vec<Frame>,
+    snapshots: Vec<Frame>,
+    matches: Vec<Option<Frame>>,
+}
+
+impl FrameRecorder {
+    fn new(config: &RunConfig) -> Self {
+        Self {
+            emulator: Emulator::new(config, 0),
+            frames: config.record_frames.then(Vec::new),
+            interval: config
+                .snapshot_interval
+                .filter(|interval| !interval.is_zero())
+                .map(|interval| (interval, interval)),
+            snapshots: Vec::new(),
+            patterns: config
+                .snapshot_patterns
+                .iter()
+                .map(|pattern| (pattern.clone(), None))
+                .collect(),
+        }
+    }
+
+    fn record(&mut self, elapsed: Duration, chunk: &[u8]) {
+        // Snapshots due before this chunk was read show the screen without it
+        self.snapshot_until(elapsed);
+        self.emulator.feed(elapsed, chunk);
+        if let Some(frames) = &mut self.frames {
+            frames.push(self.emulator.snapshot(elapsed));
+        }
+        if self.patterns.iter().any(|(_, found)| found.is_none()) {
+            let contents = self.emulator.parser.screen().contents();
+            for (pattern, found) in &mut self.patterns {
+                if found.is_none() && pattern.is_found_in(&contents) {
+                    *found = Some(self.emulator.snapshot(elapsed));
+                }
+            }
+        }
+    }
+
+    /// Resize the screens frames are taken from
+    fn set_size(&mut self, rows: u16, cols: u16) {
+        self.emulator.set_size(rows, cols);
+    }
+
+    /// When the next interval snapshot is due, as time since spawn
+    fn next_snapshot(&self) -> Option<Duration> {
+        self.interval.map(|(_, due)| due)
+    }
+
+    /// Take every interval snapshot due by `elapsed`
+    fn snapshot_until(&mut self, elapsed: Duration) {
+        while let Some((interval, due)) = self.interval.filter(|&(_, due)| due <= elapsed) {
+            self.snapshots.push(self.emulator.snapshot(due));
+            self.interval = Some((interval, due + interval));
+        }
+    }
+
+    /// Apply whatever the emulator still holds to the last frame
+    fn finish(mut self) -> Recorded {
+        let mut frames = self.frames.take().unwrap_or_default();
+        if let (true, Some(last)) = (self.emulator.finish(), frames.last_mut()) {
+            *last = self.emulator.snapshot(last.elapsed);
+        }
+        Recorded {
+            frames,
+            snapshots: self.snapshots,
+            matches: self.patterns.into_iter().map(|(_, found)| found).collect(),
+        }
+    }
+}
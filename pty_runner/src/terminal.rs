@@ -0,0 +1,64 @@
+//! Handing the runner's own terminal over to the program
+//!
+//! With `RunConfig::interactive`, what is typed into the runner's terminal
+//! goes to the program as it is typed. The terminal has to be in raw mode
+//! for that, so keys are neither echoed nor held back until Enter, and
+//! Ctrl-C reaches the program instead of stopping the runner; `RawMode`
+//! switches it there and back.
+
+use std::io;
+
+/// Key that ends an interactive session instead of reaching the program
+/// (Ctrl-], as in telnet)
+pub const END_SESSION_KEY: u8 = 0x1d;
+
+/// Keeps standard input in raw mode while alive; dropping it (also while
+/// unwinding from a panic) restores the settings it found
+pub struct RawMode {
+    #[cfg(unix)]
+    saved: libc::termios,
+}
+
+impl RawMode {
+    /// Put the terminal on standard input into raw mode
+    #[cfg(unix)]
+    pub fn enable() -> io::Result<Self> {
+        // SAFETY: tcgetattr fills in the zeroed termios before it is read
+        let saved = unsafe {
+            let mut saved: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            saved
+        };
+        let mut raw = saved;
+        // SAFETY: raw is a valid termios copied from the current settings
+        let result = unsafe {
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw)
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode { saved })
+    }
+
+    /// Raw console input is not supported on Windows yet
+    #[cfg(windows)]
+    pub fn enable() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "interactive sessions are only supported on Unix",
+        ))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: saved holds the settings read in enable
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
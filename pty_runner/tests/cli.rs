@@ -0,0 +1,2235 @@
+//! End-to-end tests that run the pty_runner binary against system programs.
+
+#![cfg(unix)]
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Write a fixture file into the per-target temp dir and return its path
+fn write_fixture(name: &str, contents: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, contents).expect("failed to write fixture");
+    path
+}
+
+/// Run pty_runner with the given runner arguments and return its output
+fn run_runner<S: AsRef<OsStr>>(args: &[S]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+        .args(args)
+        .output()
+        .expect("failed to run pty_runner")
+}
+
+#[test]
+fn forwards_program_arguments() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/echo",
+        "-o",
+        "text",
+        "--rows",
+        "3",
+        "--",
+        "--level",
+        "3",
+        "input file.txt",
+        "a  b",
+    ]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_line = stdout.lines().next().unwrap_or("");
+    assert_eq!(first_line, "--level 3 input file.txt a  b");
+}
+
+#[test]
+fn forwards_non_utf8_arguments_intact() {
+    let arg = OsStr::from_bytes(b"caf\xe9");
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/echo"),
+        OsStr::new("-o"),
+        OsStr::new("raw"),
+        OsStr::new("--"),
+        arg,
+    ]);
+
+    assert!(output
+        .stdout
+        .windows(arg.len())
+        .any(|window| window == arg.as_bytes()));
+}
+
+#[test]
+fn json_output_is_structured_and_ordered() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/echo",
+        "-o",
+        "json",
+        "--rows",
+        "2",
+        "--cols",
+        "3",
+        "--",
+        "hi",
+    ]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    // serde derives keep struct field order, so the key order is part of the format
+    assert!(stdout.starts_with(r#"{"version":1,"rows":2,"cols":3,"cursor":"#));
+
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    let cells = json["cells"].as_array().unwrap();
+    assert_eq!(cells.len(), 2);
+    assert!(cells.iter().all(|row| row.as_array().unwrap().len() == 3));
+
+    let first = &cells[0][0];
+    assert_eq!(first["char"], "h");
+    assert_eq!(first["fg"], serde_json::json!([240, 240, 240]));
+    assert_eq!(first["bg"], serde_json::json!([0, 0, 0]));
+    assert_eq!(first["attrs"]["bold"], false);
+    assert_eq!(cells[1][2]["char"], " ");
+}
+
+#[test]
+fn default_hex_matches_goldens_written_before_hex_version_2() {
+    // Captured with the original runner: a bold red wide character, then 'A'
+    let golden = "00004F60CD313100000001\
+                  00000020F0F0F000000000\
+                  00000041F0F0F000000000\
+                  00000020F0F0F000000000";
+    let golden_path = write_fixture("cjk-v1.hex", golden);
+    let run = |extra: &[&OsStr]| {
+        let mut args = vec![
+            OsStr::new("-e"),
+            OsStr::new("/bin/sh"),
+            OsStr::new("--rows"),
+            OsStr::new("1"),
+            OsStr::new("--cols"),
+            OsStr::new("4"),
+        ];
+        args.extend(extra);
+        args.extend([
+            OsStr::new("--"),
+            OsStr::new("-c"),
+            OsStr::new("printf '\\033[1;31m\u{4f60}\\033[0mA'"),
+        ]);
+        run_runner(&args)
+    };
+
+    let output = run(&[OsStr::new("--expect"), golden_path.as_os_str()]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), golden);
+
+    let v2 = run(&[
+        OsStr::new("--hex-version"),
+        OsStr::new("2"),
+        OsStr::new("--expect"),
+        golden_path.as_os_str(),
+    ]);
+    assert!(!v2.status.success());
+    assert!(String::from_utf8_lossy(&v2.stdout)
+        .starts_with("00004F60CD31310000008100000000F0F0F000000080"));
+}
+
+#[test]
+fn live_mirrors_output_to_stdout_next_to_the_output_file() {
+    let output_file = write_fixture("live_capture.hex", "");
+    let output = run_runner(&[
+        "-e",
+        "/usr/bin/printf",
+        "--rows",
+        "1",
+        "--cols",
+        "4",
+        "--live",
+        "--output-file",
+        output_file.to_str().unwrap(),
+        "--",
+        "\\033[31mred",
+    ]);
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"\x1b[31mred");
+    let capture = std::fs::read_to_string(&output_file).unwrap();
+    assert!(capture.starts_with("00000072CD3131"), "{}", capture);
+}
+
+#[test]
+fn alternate_screen_is_reported() {
+    let run = |script: &str| {
+        let output = run_runner(&[
+            "-e", "/bin/sh", "-o", "json", "--rows", "2", "--cols", "4", "--", "-c", script,
+        ]);
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        (json["alternate_screen"].clone(), stderr)
+    };
+
+    let (json, stderr) = run("printf '\\033[?1049hTUI'");
+    assert_eq!(json, true);
+    assert!(stderr.contains("\nALT_SCREEN true\n"), "{}", stderr);
+
+    let (json, stderr) = run("printf '\\033[?1049hTUI\\033[?1049l'");
+    assert_eq!(json, false);
+    assert!(stderr.contains("\nALT_SCREEN false\n"), "{}", stderr);
+}
+
+#[test]
+fn capture_picks_the_screen_shown_last() {
+    let script = "printf 'sh\\033[?1049hTUI\\033[?1049l'";
+    let run = |capture: &str| {
+        run_runner(&[
+            "-e",
+            "/bin/sh",
+            "-o",
+            "text",
+            "--rows",
+            "2",
+            "--cols",
+            "4",
+            "--capture",
+            capture,
+            "--",
+            "-c",
+            script,
+        ])
+    };
+
+    assert_eq!(run("final").stdout, b"sh\n\n");
+    assert_eq!(run("main-last").stdout, b"sh\n\n");
+    assert_eq!(run("alt-last").stdout, b"TUI\n\n");
+    assert_eq!(run("both").stdout, b"TUI\n\nsh\n\n");
+
+    // The alternate screen mode combined with another in one sequence
+    let combined = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--cols",
+        "4",
+        "--capture",
+        "alt-last",
+        "--",
+        "-c",
+        "printf 'sh\\033[?1049;25hTUI\\033[?25;1049l'",
+    ]);
+    assert_eq!(combined.stdout, b"TUI\n\n");
+    let stderr = String::from_utf8_lossy(&combined.stderr);
+    assert!(!stderr.contains("never used"), "{}", stderr);
+
+    let output = run_runner(&[
+        "-e", "/bin/sh", "-o", "json", "--rows", "2", "--cols", "4", "--", "-c", script,
+    ]);
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["meta"]["alternate_screen_used"], true);
+    assert_eq!(json["alternate_screen"], false);
+
+    // Never used: the final screen, with a warning
+    let output = run_runner(&[
+        "-e",
+        "/bin/echo",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--cols",
+        "4",
+        "--capture",
+        "alt-last",
+        "--",
+        "hi",
+    ]);
+    assert_eq!(output.stdout, b"hi\n\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("alternate screen was never used"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn keyboard_script_sends_and_sleeps() {
+    let script = write_fixture(
+        "keyboard_script_sends_and_sleeps.txt",
+        "# comment\nsend hello\\r\nsleep 100\nsend \\x77orld\\r\n",
+    );
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("--keyboard-script"),
+        script.as_os_str(),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--rows"),
+        OsStr::new("6"),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("read a; echo \"got $a\"; read b; echo \"got $b\""),
+    ]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().take(4).collect();
+    assert_eq!(lines, ["hello", "got hello", "world", "got world"]);
+}
+
+#[test]
+fn cursor_trailer_reports_position_and_visibility() {
+    let output = run_runner(&[
+        "-e",
+        "/usr/bin/printf",
+        "-o",
+        "text",
+        "--rows",
+        "4",
+        "--cursor",
+        "--",
+        "\\033[3;5Hx\\033[?25l",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().last(), Some("CURSOR 2,5 hidden"));
+
+    let output = run_runner(&[
+        "-e",
+        "/usr/bin/printf",
+        "-o",
+        "hex",
+        "--rows",
+        "2",
+        "--cols",
+        "4",
+        "--cursor",
+        "--",
+        "\\033[2;3H",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.len(), 2 * 4 * 22 + 10);
+    assert!(stdout.ends_with("0001000201"));
+}
+
+#[test]
+fn wait_for_delays_keyboard_input_until_text_appears() {
+    let input = write_fixture("wait_for_delays_keyboard_input.txt", "1\n");
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("-k"),
+        input.as_os_str(),
+        OsStr::new("--wait-for"),
+        OsStr::new("Main Menu"),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--rows"),
+        OsStr::new("5"),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("sleep 0.3; stty -echo; echo 'Main Menu'; read x; echo \"got $x\""),
+    ]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().take(2).collect();
+    assert_eq!(lines, ["Main Menu", "got 1"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn wait_for_timeout_has_distinct_exit_code() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/echo",
+        "--wait-for",
+        "never printed",
+        "--wait-timeout",
+        "200",
+        "-o",
+        "text",
+        "--",
+        "hello",
+    ]);
+    assert_eq!(output.status.code(), Some(123));
+}
+
+#[test]
+fn input_hex_decodes_keyboard_and_stdin_files() {
+    let stdin = write_fixture("hex_stdin.txt", "6f 6e 65 0a\n");
+    let keyboard = write_fixture("hex_keyboard.txt", "74776f\n0a");
+    let run = |keyboard: &PathBuf| {
+        run_runner(&[
+            OsStr::new("-e"),
+            OsStr::new("/bin/sh"),
+            OsStr::new("-s"),
+            stdin.as_os_str(),
+            OsStr::new("-k"),
+            keyboard.as_os_str(),
+            OsStr::new("--input-hex"),
+            OsStr::new("-o"),
+            OsStr::new("text"),
+            OsStr::new("--rows"),
+            OsStr::new("2"),
+            OsStr::new("--"),
+            OsStr::new("-c"),
+            OsStr::new("stty -echo; read a; read b; echo \"$a+$b\""),
+        ])
+    };
+
+    let output = run(&keyboard);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("one+two\n"));
+
+    let bad = write_fixture("hex_bad.txt", "74 77 6");
+    let output = run(&bad);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid hex keyboard input"), "{}", stderr);
+    assert!(
+        stderr.contains("Odd number of hex digits in \"6\""),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn send_eof_ends_programs_that_read_until_eof() {
+    let run = |stdin: &str, eof: &[&str]| {
+        let stdin = write_fixture("send_eof_stdin.txt", stdin);
+        let mut args = vec!["-e", "/bin/cat", "-o", "text", "--rows", "3"];
+        args.extend(["-s", stdin.to_str().unwrap(), "--input-newlines", "raw"]);
+        args.extend(eof);
+        let started = std::time::Instant::now();
+        let output = run_runner(&args);
+        (output, started.elapsed())
+    };
+
+    let (output, elapsed) = run("hello\n", &["--send-eof"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert!(elapsed < std::time::Duration::from_secs(3), "{:?}", elapsed);
+    // Whether stdin written right at spawn is echoed depends on timing
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("hello\n"), "{}", stdout);
+
+    // A partial last line is ended first
+    let (output, _) = run("abc", &["--send-eof"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("abc"), "{}", stdout);
+
+    let (output, elapsed) = run("hello\n", &["--close-stdin-after", "800"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert!(elapsed >= std::time::Duration::from_millis(800));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Sending EOF at "), "{}", stderr);
+
+    // Without it, cat runs into the timeout
+    let (output, _) = run("hello\n", &["--timeout", "500"]);
+    assert_eq!(output.status.code(), Some(124));
+}
+
+#[test]
+fn keyboard_input_files_are_sent_in_order_with_delays() {
+    let first = write_fixture("keyboard_first.txt", "a\n");
+    let second = write_fixture("keyboard_second.txt", "b\n");
+    let run = |delay: &str| {
+        let output = run_runner(&[
+            "-e",
+            "/bin/sh",
+            "-k",
+            first.to_str().unwrap(),
+            "-k",
+            second.to_str().unwrap(),
+            "--keyboard-delay",
+            delay,
+            "-o",
+            "text",
+            "--rows",
+            "5",
+            "--",
+            "-c",
+            "read a; sleep 0.3; echo prompt; read b; echo \"$a$b\"",
+        ]);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert!(run("0").starts_with("a\nb\nprompt\nab\n"));
+    assert!(run("800").starts_with("a\nprompt\nb\nab\n"));
+}
+
+#[test]
+fn type_rate_sends_one_key_at_a_time() {
+    let keyboard = write_fixture("type_rate_keys.txt", "a\x1b[Ab");
+    let script = write_fixture("type_rate_script.txt", "send a\\x1b[Ab\n");
+    // Each read gets whatever has arrived, so each key is read on its own
+    let program = "stty -icanon -echo min 1; for i in 1 2 3; do \
+                   dd bs=16 count=1 2>/dev/null | od -An -tx1; done";
+    let run = |input: &[&str]| {
+        let mut args = vec!["-e", "/bin/sh", "-o", "text", "--rows", "4"];
+        args.extend(input);
+        args.extend(["--", "-c", program]);
+        let output = run_runner(&args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let keys = " 61\n 1b 5b 41\n 62\n";
+
+    let keyboard = keyboard.to_str().unwrap();
+    assert!(run(&["-k", keyboard, "--type-rate", "10"]).starts_with(keys));
+    let script = script.to_str().unwrap();
+    assert!(run(&["--keyboard-script", script, "--type-rate", "10"]).starts_with(keys));
+}
+
+#[test]
+fn settle_waits_for_quiet_output_before_each_line() {
+    let input = write_fixture("settle_input.txt", "1\n2\n");
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("-k"),
+        input.as_os_str(),
+        OsStr::new("--settle"),
+        OsStr::new("400"),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--rows"),
+        OsStr::new("6"),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("sleep 0.2; echo one; read x; sleep 0.3; echo two; read y; echo \"$x$y\""),
+    ]);
+
+    // Each line waits for the prompt drawn after the previous one
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("one\n1\ntwo\n2\n12\n"), "{}", stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches("Output settled after").count(),
+        3,
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn wait_regex_matches_a_pattern_and_names_it_on_timeout() {
+    let input = write_fixture("wait_regex_input.txt", "y\n");
+    let run = |pattern: &str| {
+        run_runner(&[
+            OsStr::new("-e"),
+            OsStr::new("/bin/sh"),
+            OsStr::new("-k"),
+            input.as_os_str(),
+            OsStr::new("--wait-for"),
+            OsStr::new(pattern),
+            OsStr::new("--wait-regex"),
+            OsStr::new("--wait-timeout"),
+            OsStr::new("1000"),
+            OsStr::new("--timeout"),
+            OsStr::new("2000"),
+            OsStr::new("-o"),
+            OsStr::new("text"),
+            OsStr::new("--rows"),
+            OsStr::new("3"),
+            OsStr::new("--"),
+            OsStr::new("-c"),
+            OsStr::new("sleep 0.3; stty -echo; echo 'Continue? [y/N]'; read x; echo \"got $x\""),
+        ])
+    };
+
+    let output = run(r"\[y/N\]\s*$");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().nth(1), Some("got y"));
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run(r"^Quit\?");
+    assert_eq!(output.status.code(), Some(123));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(r"Wait timeout: /^Quit\?/ did not appear within 1000 ms"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn default_colors_are_configurable() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/true",
+        "--rows",
+        "1",
+        "--cols",
+        "2",
+        "--default-fg",
+        "#102030",
+        "--default-bg",
+        "abcdef",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout, "00000020102030ABCDEF00".repeat(2));
+
+    let output = run_runner(&["-e", "/bin/true", "--default-fg", "12345"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("expected RRGGBB"));
+}
+
+#[test]
+fn expect_compares_hex_cells() {
+    let capture = |text: &str| {
+        run_runner(&["-e", "/bin/echo", "--rows", "2", "--cols", "4", "--", text]).stdout
+    };
+    let golden = write_fixture(
+        "expect_compares_hex_cells.hex",
+        &String::from_utf8(capture("abc")).unwrap(),
+    );
+    let run_with_expect = |text: &str| {
+        run_runner(&[
+            OsStr::new("-e"),
+            OsStr::new("/bin/echo"),
+            OsStr::new("--rows"),
+            OsStr::new("2"),
+            OsStr::new("--cols"),
+            OsStr::new("4"),
+            OsStr::new("--expect"),
+            golden.as_os_str(),
+            OsStr::new("--"),
+            OsStr::new(text),
+        ])
+    };
+
+    let output = run_with_expect("abc");
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_with_expect("aXc");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("row 0 col 1: expected 'b' got 'X'"));
+    assert!(stderr.contains("1 difference(s) in total"));
+    assert!(stderr.contains("abc      | aXc      |  !\n"));
+}
+
+#[test]
+fn cursor_marker_marks_text_output() {
+    let output = run_runner(&[
+        "-e",
+        "/usr/bin/printf",
+        "-o",
+        "text",
+        "--rows",
+        "3",
+        "--cursor-marker",
+        "_",
+        "--",
+        "ab\\033[2;3H",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout, "ab\n  _\n\n");
+}
+
+#[test]
+fn scrollback_keeps_rows_scrolled_off_the_top() {
+    let script = "for i in 1 2 3 4 5 6; do echo line $i; done";
+    let args = [
+        "-e",
+        "/bin/sh",
+        "--rows",
+        "3",
+        "--cols",
+        "10",
+        "--scrollback",
+    ];
+
+    let text = run_runner(&[&args[..], &["10", "-o", "text", "--", "-c", script]].concat());
+    assert_eq!(
+        String::from_utf8_lossy(&text.stdout),
+        "line 1\nline 2\nline 3\nline 4\n-- end of scrollback (4 rows) --\nline 5\nline 6\n\n"
+    );
+
+    // Only the newest N rows are kept
+    let hex = run_runner(&[&args[..], &["2", "-o", "hex", "--", "-c", script]].concat());
+    let hex = String::from_utf8_lossy(&hex.stdout);
+    let (screen, section) = hex.split_once('\n').expect("scrollback section");
+    assert_eq!(screen.len(), 3 * 10 * 22);
+    let (header, cells) = section.split_once('\n').unwrap();
+    assert_eq!(header, "SCROLLBACK 2");
+    assert_eq!(cells.len(), 2 * 10 * 22);
+    assert!(cells.starts_with("0000006CF0F0F000000000")); // 'l' of "line 3"
+
+    // Dim, blink and strikethrough survive scrolling off the top
+    let styled = r"printf '\033[2;9mdim\033[m\n1\n2\n3\n'";
+    let hex = run_runner(&[&args[..], &["2", "-o", "hex", "--", "-c", styled]].concat());
+    let hex = String::from_utf8_lossy(&hex.stdout);
+    let cells = hex.lines().nth(2).expect("scrollback cells");
+    assert!(cells.starts_with("00000064F0F0F000000050"), "{}", cells);
+    let json = run_runner(&[&args[..], &["2", "-o", "json", "--", "-c", styled]].concat());
+    let json: serde_json::Value = serde_json::from_slice(&json.stdout).unwrap();
+    let attrs = &json["scrollback"]["cells"][0][0]["attrs"];
+    assert_eq!(
+        (&attrs["dim"], &attrs["strikethrough"]),
+        (&true.into(), &true.into())
+    );
+    assert_ne!(json["scrollback"]["cells"][0][3]["attrs"]["dim"], true);
+}
+
+#[test]
+fn dump_scrollback_prints_history_on_stderr() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "--rows",
+        "2",
+        "--scrollback",
+        "10",
+        "--dump-scrollback",
+        "--",
+        "-c",
+        "echo one; echo two; echo three",
+    ]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Scrollback history:\none\ntwo\nthree\n"),
+        "{}",
+        stderr
+    );
+    // The hex screen keeps its fixed size ahead of the scrollback section
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.split('\n').next().unwrap().len(), 2 * 80 * 22);
+}
+
+#[test]
+fn record_asciicast_writes_header_and_events() {
+    let cast_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("session.cast");
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/echo"),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--record-asciicast"),
+        cast_path.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("recorded"),
+    ]);
+    assert!(output.status.success());
+
+    let cast = std::fs::read_to_string(&cast_path).expect("asciicast written");
+    let mut lines = cast.lines();
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with(r#"{"version":2,"width":80,"height":25,"timestamp":"#));
+    let events: String = lines.collect();
+    assert!(events.contains(r#""o","recorded\r\n"]"#), "{}", events);
+    // The final screen is still captured as usual
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("recorded\n"));
+}
+
+#[test]
+fn output_written_right_before_exit_is_not_truncated() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "raw",
+        "--",
+        "-c",
+        "head -c 1000000 /dev/zero | tr '\\0' x",
+    ]);
+
+    assert!(output.status.success());
+    let count = output.stdout.iter().filter(|&&b| b == b'x').count();
+    assert_eq!(count, 1_000_000);
+}
+
+#[test]
+fn clear_env_leaves_only_term_and_explicit_vars() {
+    let output = run_runner(&[
+        "-e",
+        "/usr/bin/env",
+        "-o",
+        "text",
+        "--rows",
+        "5",
+        "--clear-env",
+        "--env",
+        "LANG=C",
+    ]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // portable-pty always provides SHELL, so it is the one extra variable
+    let mut vars: Vec<&str> = stdout
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with("SHELL="))
+        .collect();
+    vars.sort_unstable();
+    assert_eq!(vars, ["LANG=C", "TERM=xterm"]);
+}
+
+#[test]
+fn idle_timeout_kills_silent_program_with_distinct_exit_code() {
+    let exit_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("idle.exit");
+    let started = std::time::Instant::now();
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("--idle-timeout"),
+        OsStr::new("300"),
+        OsStr::new("--timeout"),
+        OsStr::new("10000"),
+        OsStr::new("--exit-file"),
+        exit_path.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("echo hi; sleep 30"),
+    ]);
+
+    assert_eq!(output.status.code(), Some(122));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Idle timeout reached"));
+    assert_eq!(
+        std::fs::read_to_string(&exit_path).unwrap(),
+        "IDLE_TIMEOUT\n"
+    );
+}
+
+#[test]
+fn exit_on_output_idle_treats_quiet_program_as_complete() {
+    let started = std::time::Instant::now();
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--idle-timeout",
+        "300",
+        "--exit-on-output-idle",
+        "--",
+        "-c",
+        "echo prompt; read line",
+    ]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(started.elapsed() < std::time::Duration::from_secs(4));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).lines().next(),
+        Some("prompt")
+    );
+}
+
+#[test]
+fn term_overrides_the_default_terminal_type() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--term",
+        "vt100",
+        "--clear-env",
+        "--",
+        "-c",
+        "echo $TERM",
+    ]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().next(), Some("vt100"));
+}
+
+#[test]
+fn capture_title_reports_last_osc_title() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--capture-title",
+        "--",
+        "-c",
+        r"printf '\033]0;first\007\033]2;caf\303\251; menu\033\\ok\n'",
+    ]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().next(), Some("ok"));
+    assert_eq!(stdout.lines().last(), Some("TITLE caf\u{e9}; menu"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\nTITLE caf\u{e9}; menu\n"), "{}", stderr);
+
+    // The icon name set with OSC 1 counts as a title
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--capture-title",
+        "--",
+        "-c",
+        r"printf '\033]2;window\007\033]1;icon\007ok\n'",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().last(), Some("TITLE icon"));
+}
+
+#[test]
+fn input_newlines_rewrites_keyboard_line_endings() {
+    let keys_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("newlines.keys");
+    std::fs::write(&keys_path, "x\n").unwrap();
+    let hex_of_input = |flags: &[&str]| {
+        let mut args = vec![
+            OsStr::new("-e"),
+            OsStr::new("/bin/sh"),
+            OsStr::new("-o"),
+            OsStr::new("text"),
+            OsStr::new("--rows"),
+            OsStr::new("3"),
+            OsStr::new("--keyboard-input"),
+            keys_path.as_os_str(),
+            OsStr::new("--wait-for"),
+            OsStr::new("ready"),
+        ];
+        args.extend(flags.iter().map(OsStr::new));
+        args.extend([
+            OsStr::new("--"),
+            OsStr::new("-c"),
+            // Raw mode first so the line discipline does not translate CR itself
+            OsStr::new("stty raw -echo; echo ready; head -c 2 | od -An -tx1"),
+        ]);
+        let output = run_runner(&args);
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .nth(1)
+            .unwrap_or("")
+            .trim()
+            .to_string()
+    };
+
+    assert_eq!(hex_of_input(&["--input-newlines", "raw"]), "78 0a");
+    assert_eq!(hex_of_input(&["--input-newlines", "cr"]), "78 0d");
+    assert_eq!(hex_of_input(&["--input-newlines", "crlf"]), "78 0d");
+    assert_eq!(hex_of_input(&["--no-crlf-normalize"]), "78 0a");
+    // Unix PTYs get the file's bytes unless a mode is asked for
+    assert_eq!(hex_of_input(&[]), "78 0a");
+}
+
+#[test]
+fn record_writes_a_hex_frame_per_output_chunk() {
+    let record_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("frames");
+    let _ = std::fs::remove_dir_all(&record_dir);
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("--rows"),
+        OsStr::new("2"),
+        OsStr::new("--cols"),
+        OsStr::new("4"),
+        OsStr::new("--record"),
+        record_dir.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("printf a; sleep 0.3; printf b"),
+    ]);
+
+    let mut frames: Vec<_> = std::fs::read_dir(&record_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    frames.sort();
+    assert!(frames.len() >= 2, "frames: {:?}", frames);
+    let first = std::fs::read_to_string(&frames[0]).unwrap();
+    let last = std::fs::read_to_string(frames.last().unwrap()).unwrap();
+    assert!(first.starts_with("00000061"));
+    assert!(!first.contains("00000062"));
+    assert_eq!(last.as_bytes(), output.stdout.as_slice());
+}
+
+#[test]
+fn kill_grace_lets_program_handle_ctrl_c_before_kill() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--timeout",
+        "500",
+        "--kill-grace",
+        "2000",
+        "--",
+        "-c",
+        "trap 'echo cleaned up; exit 3' INT; while :; do sleep 0.1; done",
+    ]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("exited after Ctrl-C"), "stderr: {}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("cleaned up"));
+    assert_eq!(output.status.code(), Some(124));
+}
+
+#[test]
+fn timeout_kills_grandchildren_too() {
+    let pid_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("grandchild.pid");
+    let _ = std::fs::remove_file(&pid_path);
+    let script = format!(
+        "trap '' HUP; sleep 1000 & echo $! > {}; wait",
+        pid_path.display()
+    );
+    let output = run_runner(&["-e", "/bin/sh", "--timeout", "500", "--", "-c", &script]);
+    assert_eq!(output.status.code(), Some(124));
+
+    let pid = std::fs::read_to_string(&pid_path).unwrap();
+    // A killed process may linger as a zombie until its new parent reaps it
+    let alive = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+        .map(|stat| !stat.contains(") Z "))
+        .unwrap_or(false);
+    assert!(!alive, "grandchild {} survived the timeout", pid.trim());
+}
+
+#[test]
+fn interrupting_the_runner_kills_the_program() {
+    let pid_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("interrupted.pid");
+    let _ = std::fs::remove_file(&pid_path);
+    let script = format!(
+        "trap '' HUP; sleep 1000 & echo $! > {}; wait",
+        pid_path.display()
+    );
+    let started = std::time::Instant::now();
+    let runner = Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+        .args([
+            "-e",
+            "/bin/sh",
+            "-o",
+            "both",
+            "--timeout",
+            "20000",
+            "--",
+            "-c",
+            &script,
+        ])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    while !pid_path.exists() && started.elapsed().as_secs() < 5 {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    Command::new("kill")
+        .args(["-TERM", &runner.id().to_string()])
+        .status()
+        .unwrap();
+    let output = runner.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    assert!(started.elapsed().as_secs() < 10);
+    assert!(output.stdout.ends_with(b"\nEXIT INTERRUPTED\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Interrupted, stopping process"),
+        "{}",
+        stderr
+    );
+
+    let pid = std::fs::read_to_string(&pid_path).unwrap();
+    let alive = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+        .map(|stat| !stat.contains(") Z "))
+        .unwrap_or(false);
+    assert!(!alive, "grandchild {} survived the interrupt", pid.trim());
+}
+
+#[test]
+fn large_pastes_are_written_while_output_is_read() {
+    let paste = write_fixture("paste.txt", &"x".repeat(1 << 20));
+    let run = |script: &str, timeout: &str| {
+        run_runner(&[
+            OsStr::new("-e"),
+            OsStr::new("/bin/sh"),
+            OsStr::new("-k"),
+            paste.as_os_str(),
+            OsStr::new("--timeout"),
+            OsStr::new(timeout),
+            OsStr::new("-o"),
+            OsStr::new("text"),
+            OsStr::new("--rows"),
+            OsStr::new("2"),
+            OsStr::new("--cols"),
+            OsStr::new("20"),
+            OsStr::new("--"),
+            OsStr::new("-c"),
+            OsStr::new(script),
+        ])
+    };
+
+    // All 1 MB comes back from the program
+    let output = run("stty raw -echo; head -c 1048576; printf done", "20000");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.ends_with("xxxxxxxxxxxxxxxxdone\n"), "{}", stdout);
+
+    // A program that never reads fills the PTY's input buffer, yet the
+    // timeout still stops it
+    let started = std::time::Instant::now();
+    let output = run("stty raw -echo; sleep 10", "1000");
+    assert_eq!(output.status.code(), Some(124));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn max_output_bytes_stops_a_runaway_program() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let (meta_path, exit_path) = (dir.join("limit.meta.json"), dir.join("limit.exit"));
+    let started = std::time::Instant::now();
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/usr/bin/yes"),
+        OsStr::new("-o"),
+        OsStr::new("raw"),
+        OsStr::new("--max-output-bytes"),
+        OsStr::new("10000"),
+        OsStr::new("--timeout"),
+        OsStr::new("20000"),
+        OsStr::new("--meta-file"),
+        meta_path.as_os_str(),
+        OsStr::new("--exit-file"),
+        exit_path.as_os_str(),
+    ]);
+    assert_eq!(output.status.code(), Some(121));
+    assert!(started.elapsed().as_secs() < 10);
+    assert_eq!(output.stdout.len(), 10000);
+    assert!(output.stdout.starts_with(b"y\r\ny\r\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("output exceeded 10000 bytes, the rest is dropped"),
+        "{}",
+        stderr
+    );
+
+    let meta: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&meta_path).unwrap()).unwrap();
+    assert_eq!(meta["output_truncated"], true);
+    assert_eq!(meta["bytes_captured"], 10000);
+    assert_eq!(
+        std::fs::read_to_string(&exit_path).unwrap(),
+        "OUTPUT_LIMIT\n"
+    );
+}
+
+#[test]
+fn meta_file_reports_the_run_even_when_spawn_fails() {
+    let meta_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("run.meta.json");
+    let read_meta = |executable: &str, args: &[&str]| {
+        let mut runner_args = vec![
+            OsStr::new("-e"),
+            OsStr::new(executable),
+            OsStr::new("--meta-file"),
+            meta_path.as_os_str(),
+            OsStr::new("--"),
+        ];
+        runner_args.extend(args.iter().map(OsStr::new));
+        let output = run_runner(&runner_args);
+        let meta: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&meta_path).unwrap()).unwrap();
+        (output, meta)
+    };
+
+    let (_, meta) = read_meta("/bin/sh", &["-c", "printf '\\033]0;t\\007hi'; exit 3"]);
+    assert_eq!(meta["executable"], "/bin/sh");
+    assert_eq!(meta["exit_code"], 3);
+    assert_eq!(meta["timed_out"], false);
+    assert_eq!(meta["bytes_captured"], 8);
+    assert_eq!(meta["bytes_filtered"], 2);
+    assert!(meta["duration_ms"].is_u64());
+
+    let (output, meta) = read_meta("/nonexistent/program", &[]);
+    assert_eq!(output.status.code(), Some(125));
+    assert!(meta["spawn_error"].is_string());
+    assert!(meta["exit_code"].is_null());
+    assert!(meta["duration_ms"].is_null());
+}
+
+#[test]
+fn cursor_position_queries_are_answered() {
+    let script = "stty -echo -icanon; printf 'ab\\033[6n'; head -c 6 | od -An -tx1";
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "2",
+        "--timeout",
+        "2000",
+        "--",
+        "-c",
+        script,
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1b 5b 31 3b 33 52"), "stdout: {}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "--timeout",
+        "500",
+        "--no-query-replies",
+        "--",
+        "-c",
+        script,
+    ]);
+    assert_eq!(output.status.code(), Some(124));
+}
+
+#[test]
+fn raw_out_saves_unfiltered_bytes_alongside_hex() {
+    let raw_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("capture.raw");
+    let _ = std::fs::remove_file(&raw_path);
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/usr/bin/printf"),
+        OsStr::new("--rows"),
+        OsStr::new("1"),
+        OsStr::new("--cols"),
+        OsStr::new("2"),
+        OsStr::new("--raw-out"),
+        raw_path.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("\\033]0;t\\007ok"),
+    ]);
+
+    assert_eq!(std::fs::read(&raw_path).unwrap(), b"\x1b]0;t\x07ok");
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("0000006F"));
+}
+
+#[test]
+fn batch_runs_manifest_cases_and_summarises() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("batch");
+    std::fs::create_dir_all(&dir).unwrap();
+    let manifest = dir.join("tests.toml");
+    // Later cases compare against the capture the first case wrote
+    std::fs::write(
+        &manifest,
+        r#"
+[[case]]
+name = "hello"
+executable = "/bin/echo"
+args = ["hi"]
+rows = 2
+cols = 4
+output = "text"
+output_path = "hello.txt"
+
+[[case]]
+name = "same"
+executable = "/bin/echo"
+args = ["hi"]
+rows = 2
+cols = 4
+output = "text"
+expect = "hello.txt"
+
+[[case]]
+name = "different"
+executable = "/bin/echo"
+args = ["ho"]
+rows = 2
+cols = 4
+output = "text"
+expect = "hello.txt"
+
+[[case]]
+name = "slow"
+executable = "/bin/sleep"
+args = ["5"]
+timeout = 300
+"#,
+    )
+    .unwrap();
+
+    let report = dir.join("report.xml");
+    let output = run_runner(&[
+        OsStr::new("batch"),
+        OsStr::new("--manifest"),
+        manifest.as_os_str(),
+        OsStr::new("--report-junit"),
+        report.as_os_str(),
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let report = std::fs::read_to_string(report).unwrap();
+    assert!(
+        report.contains("<testsuite name=\"tests\" tests=\"4\" failures=\"2\" errors=\"0\""),
+        "report: {}",
+        report
+    );
+    assert!(
+        report.contains("<failure type=\"timeout\""),
+        "report: {}",
+        report
+    );
+    assert!(std::fs::read_to_string(dir.join("hello.txt"))
+        .unwrap()
+        .starts_with("hi"));
+    assert!(stdout.contains("hello      pass\n"), "stdout: {}", stdout);
+    assert!(stdout.contains("same       pass\n"), "stdout: {}", stdout);
+    assert!(stdout.contains("different  fail"), "stdout: {}", stdout);
+    assert!(
+        stdout.contains("slow       timeout\n"),
+        "stdout: {}",
+        stdout
+    );
+    assert!(stdout.contains("2 of 4 cases passed"), "stdout: {}", stdout);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn batch_manifest_errors_name_the_case() {
+    let manifest = write_fixture(
+        "bad.toml",
+        "[[case]]\nname = \"menu\"\nexecutable = \"x\"\ncols = \"wide\"\n",
+    );
+    let output = run_runner(&[
+        OsStr::new("batch"),
+        OsStr::new("--manifest"),
+        manifest.as_os_str(),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("case \"menu\""), "stderr: {}", stderr);
+    assert_ne!(output.status.code(), Some(0));
+}
+
+#[test]
+fn batch_jobs_keep_manifest_order_and_prefix_diagnostics() {
+    let manifest = write_fixture(
+        "jobs.toml",
+        r#"
+[[case]]
+name = "slow"
+executable = "/bin/sleep"
+args = ["1"]
+
+[[case]]
+name = "fast"
+executable = "/bin/echo"
+args = ["hi"]
+"#,
+    );
+    let output = run_runner(&[
+        OsStr::new("batch"),
+        OsStr::new("--manifest"),
+        manifest.as_os_str(),
+        OsStr::new("--jobs"),
+        OsStr::new("2"),
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stdout.starts_with("case  result\nslow  pass\nfast  pass\n"),
+        "stdout: {}",
+        stdout
+    );
+    // The fast case finishes while the slow one is still running
+    let fast_done = stderr.find("[fast] Child process exited").unwrap();
+    let slow_done = stderr.find("[slow] Child process exited").unwrap();
+    assert!(fast_done < slow_done, "stderr: {}", stderr);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn separate_stderr_keeps_diagnostics_off_the_screen() {
+    let stderr_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("child.stderr");
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--rows"),
+        OsStr::new("3"),
+        OsStr::new("--separate-stderr"),
+        stderr_path.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("echo out; echo err >&2"),
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("out\n"), "stdout: {}", stdout);
+    assert!(!stdout.contains("err"), "stdout: {}", stdout);
+    assert_eq!(std::fs::read_to_string(&stderr_path).unwrap(), "err\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn run_time_is_reported_and_flags_timeouts() {
+    let output = run_runner(&["-e", "/bin/echo", "-o", "json", "--", "hi"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr
+        .lines()
+        .find(|line| line.starts_with("DURATION_MS "))
+        .unwrap();
+    assert!(!line.ends_with("TIMED_OUT"), "stderr: {}", stderr);
+    let state: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(state["meta"]["run_time_ms"].as_u64().unwrap() < 5000);
+
+    let output = run_runner(&[
+        "-e",
+        "/bin/sleep",
+        "-o",
+        "json",
+        "--timeout",
+        "300",
+        "--",
+        "5",
+    ]);
+    assert_eq!(output.status.code(), Some(124));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr
+        .lines()
+        .find(|line| line.starts_with("DURATION_MS "))
+        .unwrap();
+    assert!(line.ends_with(" TIMED_OUT"), "stderr: {}", stderr);
+    let state: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(state["meta"]["run_time_ms"].as_u64().unwrap() >= 300);
+    assert_eq!(state["meta"]["timed_out"], true);
+}
+
+#[test]
+fn update_expect_writes_golden_files_and_refuses_broken_runs() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    let _ = std::fs::remove_dir_all(&dir);
+    let golden = dir.join("nested").join("hello.txt");
+    let update = |executable: &str, arg: &str, extra: &[&str]| {
+        let mut args = vec![
+            OsStr::new("-e"),
+            OsStr::new(executable),
+            OsStr::new("-o"),
+            OsStr::new("text"),
+            OsStr::new("--rows"),
+            OsStr::new("2"),
+            OsStr::new("--timeout"),
+            OsStr::new("300"),
+            OsStr::new("--expect"),
+            golden.as_os_str(),
+            OsStr::new("--update-expect"),
+        ];
+        args.extend(extra.iter().map(OsStr::new));
+        args.extend([OsStr::new("--"), OsStr::new(arg)]);
+        run_runner(&args)
+    };
+
+    let output = update("/bin/echo", "hi", &[]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Updated"));
+    assert_eq!(output.status.code(), Some(0));
+    let captured = std::fs::read_to_string(&golden).unwrap();
+    assert!(captured.starts_with("hi\n"), "{:?}", captured);
+
+    let output = update("/bin/echo", "hi", &[]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Already up to date"));
+
+    let output = update("/bin/sleep", "5", &[]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("timed out"));
+    assert_ne!(output.status.code(), Some(0));
+    assert_eq!(std::fs::read_to_string(&golden).unwrap(), captured);
+
+    update("/bin/sleep", "5", &["--update-expect-force"]);
+    assert_ne!(std::fs::read_to_string(&golden).unwrap(), captured);
+}
+
+#[test]
+fn batch_update_expect_lists_changed_and_unchanged_files() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("batch-update");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let manifest = dir.join("tests.toml");
+    std::fs::write(
+        &manifest,
+        r#"
+[[case]]
+name = "first"
+executable = "/bin/echo"
+args = ["one"]
+expect = "golden/first.hex"
+
+[[case]]
+name = "second"
+executable = "/bin/echo"
+args = ["two"]
+expect = "golden/second.hex"
+"#,
+    )
+    .unwrap();
+    let batch = || {
+        run_runner(&[
+            OsStr::new("batch"),
+            OsStr::new("--manifest"),
+            manifest.as_os_str(),
+            OsStr::new("--update-expect"),
+        ])
+    };
+
+    let output = batch();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("first   updated\nsecond  updated\n"));
+    std::fs::write(dir.join("golden/second.hex"), "stale").unwrap();
+    let output = batch();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("first   up to date\nsecond  updated\n"),
+        "stdout: {}",
+        stdout
+    );
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn snapshot_interval_writes_intermediate_screens() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("snapshots");
+    let _ = std::fs::remove_dir_all(&dir);
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--rows"),
+        OsStr::new("3"),
+        OsStr::new("--snapshot-interval"),
+        OsStr::new("400"),
+        OsStr::new("--snapshot-dir"),
+        dir.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("echo one; sleep 1; echo two; sleep 1"),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let mut snapshots: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    snapshots.sort();
+    assert!(snapshots.len() >= 4, "snapshots: {:?}", snapshots);
+    assert!(snapshots[0].ends_with("snapshot-0001.txt"));
+    let first = std::fs::read_to_string(&snapshots[0]).unwrap();
+    let last = std::fs::read_to_string(snapshots.last().unwrap()).unwrap();
+    assert!(first.starts_with("one\n"), "{:?}", first);
+    assert!(!first.contains("two"), "{:?}", first);
+    assert!(last.starts_with("one\ntwo\n"), "{:?}", last);
+}
+
+#[test]
+fn snapshot_on_captures_the_screen_when_a_pattern_first_appears() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("pattern-snapshots");
+    let _ = std::fs::remove_dir_all(&dir);
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--rows"),
+        OsStr::new("2"),
+        OsStr::new("--snapshot-dir"),
+        dir.as_os_str(),
+        OsStr::new("--snapshot-on"),
+        OsStr::new("G.ME OVER"),
+        OsStr::new("--snapshot-on"),
+        OsStr::new("^never$"),
+        OsStr::new("--snapshot-regex"),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("printf 'GAME OVER'; sleep 0.3; printf '\\033[2J\\033[Hbye'"),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("bye"));
+    let snapshot = std::fs::read_to_string(dir.join("match-01.txt")).unwrap();
+    assert!(snapshot.starts_with("GAME OVER"), "{:?}", snapshot);
+    assert!(!dir.join("match-02.txt").exists());
+    assert!(
+        stderr.contains("Pattern 2 /^never$/: never appeared"),
+        "stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn timing_log_lists_each_chunk() {
+    let timing_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("timing.csv");
+    run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("--timing-log"),
+        timing_path.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("printf ab; sleep 0.3; printf cde"),
+    ]);
+    let csv = std::fs::read_to_string(&timing_path).unwrap();
+    let rows: Vec<(f64, usize)> = csv
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let (ms, bytes) = line.split_once(',').unwrap();
+            (ms.parse().unwrap(), bytes.parse().unwrap())
+        })
+        .collect();
+    assert!(csv.starts_with("elapsed_ms,bytes\n"));
+    assert_eq!(rows.iter().map(|row| row.1).sum::<usize>(), 5);
+    assert!(rows.last().unwrap().0 - rows[0].0 >= 250.0, "{}", csv);
+}
+
+#[test]
+fn sequences_split_between_chunks_are_still_normalized() {
+    // The erase arrives in a later chunk than its ESC [, and must still
+    // reset the colors for the text after it
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "json",
+        "--rows",
+        "1",
+        "--cols",
+        "4",
+        "--",
+        "-c",
+        "printf '\\033[41ma\\033['; sleep 0.3; printf Kb",
+    ]);
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let row = &json["cells"][0];
+    assert_eq!(row[0]["char"], "a");
+    assert_ne!(row[0]["bg"], serde_json::json!([0, 0, 0]));
+    assert_eq!(row[1]["char"], "b");
+    assert_eq!(row[1]["bg"], serde_json::json!([0, 0, 0]));
+}
+
+#[test]
+fn output_file_takes_the_output_off_stdout() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("output_file");
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("nested").join("capture.raw");
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("-o"),
+        OsStr::new("raw"),
+        OsStr::new("--output-file"),
+        path.as_os_str(),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("printf 'caf\\351\\r\\n'"),
+    ]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let raw = std::fs::read(&path).unwrap();
+    assert!(raw.windows(4).any(|w| w == b"caf\xe9"), "{:?}", raw);
+    assert!(!dir.join("nested").join("capture.raw.tmp").exists());
+}
+
+#[test]
+fn replay_renders_saved_output_like_the_run_did() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let (raw_path, log_path) = (dir.join("replay.raw"), dir.join("replay.log"));
+    let run = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new("/bin/sh"),
+        OsStr::new("--rows"),
+        OsStr::new("3"),
+        OsStr::new("--cols"),
+        OsStr::new("10"),
+        OsStr::new("--raw-out"),
+        raw_path.as_os_str(),
+        OsStr::new("--raw-log"),
+        log_path.as_os_str(),
+        OsStr::new("--raw-log-timestamps"),
+        OsStr::new("--"),
+        OsStr::new("-c"),
+        OsStr::new("printf '\\033]0;t\\007\\033[31mred\\033[0m'; sleep 0.2; echo ok"),
+    ]);
+    let replay = |args: &[&OsStr], stdin: Option<&[u8]>| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+            .arg("replay")
+            .args(["--rows", "3", "--cols", "10"])
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut input = child.stdin.take().unwrap();
+        std::io::Write::write_all(&mut input, stdin.unwrap_or_default()).unwrap();
+        drop(input);
+        child.wait_with_output().unwrap()
+    };
+
+    let output = replay(&[OsStr::new("--input"), raw_path.as_os_str()], None);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run.stdout);
+    let output = replay(
+        &[
+            OsStr::new("--input"),
+            log_path.as_os_str(),
+            OsStr::new("--timestamps"),
+        ],
+        None,
+    );
+    assert_eq!(output.stdout, run.stdout);
+
+    let raw = std::fs::read(&raw_path).unwrap();
+    let output = replay(
+        &[
+            OsStr::new("--input"),
+            OsStr::new("-"),
+            OsStr::new("-o"),
+            OsStr::new("text"),
+        ],
+        Some(&raw),
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "redok\n\n\n");
+
+    let output = replay(
+        &[
+            OsStr::new("--input"),
+            raw_path.as_os_str(),
+            OsStr::new("-o"),
+            OsStr::new("both"),
+        ],
+        None,
+    );
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.ends_with("\nEXIT NONE\n"), "{}", report);
+
+    // No program ran, so no timeout can have fired
+    let output = replay(
+        &[
+            OsStr::new("--input"),
+            raw_path.as_os_str(),
+            OsStr::new("-o"),
+            OsStr::new("json"),
+        ],
+        None,
+    );
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        json["exit"],
+        serde_json::json!({"code": null, "timed_out": false})
+    );
+}
+
+#[test]
+fn render_turns_hex_captures_back_into_screens() {
+    let hex_path = write_fixture("render.hex", "");
+    let capture = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "--rows",
+        "2",
+        "--cols",
+        "6",
+        "--",
+        "-c",
+        "printf '\\033[1;32mok\\033[0m \\033[44mblue'",
+    ]);
+    std::fs::write(&hex_path, &capture.stdout).unwrap();
+    let render = |args: &[&OsStr]| {
+        Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+            .args(["render", "--rows", "2", "--cols", "6"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let text = render(&[OsStr::new("--input"), hex_path.as_os_str()]);
+    assert_eq!(String::from_utf8_lossy(&text.stdout), "ok blu\ne\n");
+
+    // hex -> ansi -> replay gives back the same hex
+    let ansi = render(&[
+        OsStr::new("--input"),
+        hex_path.as_os_str(),
+        OsStr::new("--to"),
+        OsStr::new("ansi"),
+    ]);
+    let ansi_path = write_fixture("render.ansi", "");
+    std::fs::write(&ansi_path, &ansi.stdout).unwrap();
+    let replayed = Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+        .args(["replay", "--rows", "2", "--cols", "6", "--input"])
+        .arg(&ansi_path)
+        .output()
+        .unwrap();
+    assert_eq!(replayed.stdout, capture.stdout);
+
+    let html = render(&[
+        OsStr::new("--input"),
+        hex_path.as_os_str(),
+        OsStr::new("--to"),
+        OsStr::new("html"),
+    ]);
+    assert!(String::from_utf8_lossy(&html.stdout).contains("font-weight:bold"));
+
+    let wrong_size = Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+        .args(["render", "--rows", "3", "--cols", "6", "--input"])
+        .arg(&hex_path)
+        .output()
+        .unwrap();
+    assert!(!wrong_size.status.success());
+    let stderr = String::from_utf8_lossy(&wrong_size.stderr);
+    assert!(
+        stderr.contains("holds 12 cells, but a 3 rows x 6 cols screen has 18"),
+        "{}",
+        stderr
+    );
+
+    let mut corrupt = capture.stdout.clone();
+    corrupt[22 * 3 + 5] = b'z';
+    std::fs::write(&hex_path, &corrupt).unwrap();
+    let stderr =
+        String::from_utf8_lossy(&render(&[OsStr::new("--input"), hex_path.as_os_str()]).stderr)
+            .into_owned();
+    assert!(
+        stderr.contains("Invalid hex cell at offset 66"),
+        "{}",
+        stderr
+    );
+
+    // A cut-off last cell or anything but the known trailers is an error
+    let truncated = &capture.stdout[..capture.stdout.len() - 5];
+    std::fs::write(&hex_path, truncated).unwrap();
+    let output = render(&[OsStr::new("--input"), hex_path.as_os_str()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Truncated hex cell at offset 242 (after 11 cells)"),
+        "{}",
+        stderr
+    );
+    let mut garbage = capture.stdout.clone();
+    garbage.extend_from_slice(b"\nnot a trailer\n");
+    std::fs::write(&hex_path, &garbage).unwrap();
+    let output = render(&[OsStr::new("--input"), hex_path.as_os_str()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Unexpected line at offset 265: \"not a trailer\""),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn diff_compares_two_hex_captures() {
+    let capture = |name: &str, script: &str| {
+        let output = run_runner(&[
+            "-e", "/bin/sh", "--rows", "2", "--cols", "4", "--", "-c", script,
+        ]);
+        let path = write_fixture(name, "");
+        std::fs::write(&path, &output.stdout).unwrap();
+        path
+    };
+    let plain = capture("diff-plain.hex", "printf 'ab'");
+    let bold = capture("diff-bold.hex", "printf '\\033[1;31mab'");
+    let other = capture("diff-other.hex", "printf 'ax'");
+    let diff = |a: &PathBuf, b: &PathBuf, extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+            .args(["diff", "--rows", "2", "--cols", "4"])
+            .args(extra)
+            .arg(a)
+            .arg(b)
+            .output()
+            .unwrap()
+    };
+
+    let same = diff(&plain, &plain, &[]);
+    assert_eq!(same.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&same.stdout).starts_with("0 of 8 cells differ"));
+
+    let styled = diff(&plain, &bold, &[]);
+    assert_eq!(styled.status.code(), Some(1));
+    let report = String::from_utf8_lossy(&styled.stdout);
+    assert!(report.starts_with("2 of 8 cells differ"), "{}", report);
+    assert!(report.ends_with("##..\n....\n"), "{}", report);
+    let ignored = diff(&plain, &bold, &["--ignore-colors", "--ignore-attrs"]);
+    assert_eq!(ignored.status.code(), Some(0));
+
+    let changed = diff(&plain, &other, &["--ignore-colors", "--ignore-attrs"]);
+    assert_eq!(changed.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&changed.stdout).ends_with(".#..\n....\n"));
+
+    let wrong_size = Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+        .args(["diff", "--rows", "3", "--cols", "4"])
+        .arg(&plain)
+        .arg(&other)
+        .output()
+        .unwrap();
+    assert_eq!(wrong_size.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&wrong_size.stderr);
+    assert!(
+        stderr.contains("holds 8 cells, but a 3 rows x 4 cols screen has 12"),
+        "{}",
+        stderr
+    );
+
+    let garbage = write_fixture("diff-garbage.hex", "");
+    let mut contents = std::fs::read(&plain).unwrap();
+    contents.extend_from_slice(b"zz");
+    std::fs::write(&garbage, &contents).unwrap();
+    let output = diff(&plain, &garbage, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid hex cell at offset 176 (after 8 cells)"),
+        "{}",
+        stderr
+    );
+    let truncated = write_fixture("diff-truncated.hex", "");
+    std::fs::write(&truncated, &contents[..22 * 7 + 4]).unwrap();
+    let output = diff(&truncated, &plain, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Truncated hex cell at offset 154 (after 7 cells)"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn base64_output_round_trips_through_decode() {
+    let run = |format: &str| {
+        run_runner(&[
+            "-e",
+            "/usr/bin/printf",
+            "-o",
+            format,
+            "--",
+            "\\033[1mbold\\033[m\\r\\n\\377",
+        ])
+        .stdout
+    };
+    let raw = run("raw");
+    let encoded = run("base64");
+    assert_eq!(encoded.iter().filter(|&&b| b == b'\n').count(), 1);
+    assert!(encoded.ends_with(b"\n"));
+
+    let file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("transcript.b64");
+    std::fs::write(&file, &encoded).unwrap();
+    let decoded = run_runner(&[OsStr::new("decode-base64"), file.as_os_str()]);
+    assert!(decoded.status.success());
+    assert_eq!(decoded.stdout, raw);
+    assert!(raw.starts_with(b"\x1b[1mbold"));
+
+    std::fs::write(&file, "not base64!").unwrap();
+    let invalid = run_runner(&[OsStr::new("decode-base64"), file.as_os_str()]);
+    assert!(!invalid.status.success());
+    assert!(String::from_utf8_lossy(&invalid.stderr).contains("Invalid base64 input"));
+}
+
+#[test]
+fn hash_output_is_one_stable_digest_line() {
+    let run = |extra: &[&str]| {
+        let mut args = vec!["-e", "/bin/echo", "--rows", "3", "--cols", "10"];
+        args.extend(extra);
+        args.extend(["--", "same"]);
+        run_runner(&args)
+    };
+    let hash = String::from_utf8(run(&["-o", "hash"]).stdout).unwrap();
+    assert_eq!(hash.len(), 65, "{:?}", hash);
+    assert!(hash.trim().bytes().all(|b| b.is_ascii_hexdigit()));
+    assert_eq!(
+        String::from_utf8(run(&["-o", "hash"]).stdout).unwrap(),
+        hash
+    );
+
+    let golden = write_fixture("screen.sha256", hash.trim());
+    let golden = golden.to_str().unwrap();
+    assert!(run(&["-o", "hash", "--expect", golden]).status.success());
+
+    let stale = write_fixture("stale.sha256", &"0".repeat(64));
+    let mismatch = run(&["-o", "hash", "--expect", stale.to_str().unwrap()]);
+    assert_eq!(mismatch.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&mismatch.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "screen hash: expected {} got {}",
+            "0".repeat(64),
+            hash.trim()
+        )),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn palette_presets_and_files_recolor_indexed_colors() {
+    let fg_of_red = |palette: &[&str]| {
+        let mut args = vec!["-e", "/usr/bin/printf", "--rows", "2", "--cols", "4"];
+        args.extend(palette);
+        args.extend(["--", "\\033[31mx"]);
+        let output = run_runner(&args);
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()[8..14].to_string()
+    };
+    assert_eq!(fg_of_red(&[]), "CD3131");
+    assert_eq!(fg_of_red(&["--palette", "xterm"]), "CD0000");
+    assert_eq!(fg_of_red(&["--palette", "windows-terminal"]), "C50F1F");
+    assert_eq!(fg_of_red(&["--palette", "vga"]), "AA0000");
+
+    let mut lines = vec!["000000"; 16];
+    lines[1] = "123456";
+    let file = write_fixture("palette.txt", &lines.join("\n"));
+    assert_eq!(fg_of_red(&["--palette", file.to_str().unwrap()]), "123456");
+    assert_eq!(
+        fg_of_red(&["--palette-file", file.to_str().unwrap()]),
+        "123456"
+    );
+
+    lines[1] = "oops";
+    let file = write_fixture("bad_palette.txt", &lines.join("\n"));
+    let output = run_runner(&["-e", "/bin/true", "--palette", file.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("line 2: expected RRGGBB"), "{}", stderr);
+}
+
+#[test]
+fn resize_at_changes_the_terminal_and_output_size() {
+    let output = run_runner(&[
+        "-e",
+        "/bin/sh",
+        "-o",
+        "text",
+        "--rows",
+        "3",
+        "--cols",
+        "20",
+        "--resize-at",
+        "300:30x5",
+        "--",
+        "-c",
+        "stty size; sleep 1; stty size",
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "3 20\n5 30\n\n\n\n", "{:?}", stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Resized terminal to 30x5 at "),
+        "{}",
+        stderr
+    );
+
+    let invalid = run_runner(&["-e", "/bin/true", "--resize-at", "300:30"]);
+    assert!(!invalid.status.success());
+    assert!(String::from_utf8_lossy(&invalid.stderr).contains("expected MS:COLSxROWS"));
+}
+
+#[test]
+fn input_order_puts_keyboard_before_stdin() {
+    let stdin = write_fixture("order_stdin.txt", "S\n");
+    let keyboard = write_fixture("order_keyboard.txt", "K\n");
+    let run = |order: &str| {
+        let output = run_runner(&[
+            "-e",
+            "/bin/sh",
+            "-o",
+            "text",
+            "--rows",
+            "6",
+            "-s",
+            stdin.to_str().unwrap(),
+            "-k",
+            keyboard.to_str().unwrap(),
+            "--input-order",
+            order,
+            "--startup-delay",
+            "20",
+            "--",
+            "-c",
+            "read a; read b; echo \"got $a$b\"",
+        ]);
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let stdin_first = run("stdin-first");
+    assert!(stdin_first.contains("got SK"), "{}", stdin_first);
+    let keyboard_first = run("keyboard-first");
+    assert!(keyboard_first.contains("got KS"), "{}", keyboard_first);
+}
+
+#[test]
+fn indexed_color_goldens_ignore_the_palette() {
+    let golden = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("indexed.hex");
+    let run = |extra: &[&str]| {
+        let mut args = vec![
+            "-e",
+            "/usr/bin/printf",
+            "--rows",
+            "2",
+            "--cols",
+            "4",
+            "--color-mode",
+            "indexed",
+        ];
+        args.extend(extra);
+        args.extend(["--", "\\033[31mred"]);
+        run_runner(&args)
+    };
+    let first = run(&[]);
+    assert!(first.status.success());
+    assert!(first
+        .stdout
+        .starts_with(b"HEX 2 indexed\n00000072010000010000000000"));
+    std::fs::write(&golden, &first.stdout).unwrap();
+
+    let golden = golden.to_str().unwrap();
+    let output = run(&["--palette", "windows-terminal", "--expect", golden]);
+    assert!(output.status.success(), "{:?}", output);
+
+    // An RGB capture is never compared against an indexed golden
+    let output = run_runner(&[
+        "-e",
+        "/usr/bin/printf",
+        "--rows",
+        "2",
+        "--cols",
+        "4",
+        "--expect",
+        golden,
+        "--",
+        "\\033[31mred",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("color mode: expected Indexed got Rgb"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn interactive_sessions_record_what_was_typed() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let recorded = dir.join("interactive_recorded.txt");
+    let capture = dir.join("interactive_capture.txt");
+    let _ = std::fs::remove_file(&recorded);
+    // The outer runner types into the inner one's terminal, then ends the
+    // session with Ctrl-]
+    let script = write_fixture(
+        "interactive_keys.txt",
+        "sleep 300\nsend abc\\r\nexpect abc\nsend \\x1d\n",
+    );
+    let output = run_runner(&[
+        OsStr::new("-e"),
+        OsStr::new(env!("CARGO_BIN_EXE_pty_runner")),
+        OsStr::new("--keyboard-script"),
+        script.as_os_str(),
+        OsStr::new("-o"),
+        OsStr::new("text"),
+        OsStr::new("--wait-for"),
+        OsStr::new("Ctrl-]"),
+        OsStr::new("--timeout"),
+        OsStr::new("10000"),
+        OsStr::new("--"),
+        OsStr::new("--interactive"),
+        OsStr::new("--record-input"),
+        recorded.as_os_str(),
+        OsStr::new("--exit-file"),
+        dir.join("interactive_exit.txt").as_os_str(),
+        OsStr::new("--output-file"),
+        capture.as_os_str(),
+        OsStr::new("-o"),
+        OsStr::new("both"),
+        OsStr::new("-e"),
+        OsStr::new("/bin/cat"),
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Recorded 4 bytes of input"), "{}", stdout);
+    assert_eq!(std::fs::read_to_string(&recorded).unwrap(), "send abc\\r\n");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("interactive_exit.txt")).unwrap(),
+        "ENDED\n"
+    );
+    let report = std::fs::read_to_string(&capture).unwrap();
+    assert!(report.starts_with("abc"), "{}", report);
+    assert!(report.ends_with("\nEXIT ENDED\n"), "{}", report);
+}
+
+#[test]
+fn unconsumed_input_is_reported() {
+    let keys = write_fixture("unconsumed_keys.txt", "hello\nworld\n");
+    let meta = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("unconsumed_meta.json");
+    let run = |program: &str| {
+        let output = run_runner(&[
+            OsStr::new("-e"),
+            OsStr::new("/bin/sh"),
+            OsStr::new("-k"),
+            keys.as_os_str(),
+            OsStr::new("--fail-on-unconsumed-input"),
+            OsStr::new("--meta-file"),
+            meta.as_os_str(),
+            OsStr::new("--"),
+            OsStr::new("-c"),
+            OsStr::new(program),
+        ]);
+        let meta: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&meta).unwrap()).unwrap();
+        (output, meta)
+    };
+
+    // Exits before the keyboard input is sent
+    let (output, meta) = run("exit 0");
+    assert_eq!(output.status.code(), Some(120), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("never got 12 of 12 input bytes (input stopped at byte offset 0"),
+        "{}",
+        stderr
+    );
+    assert_eq!(meta["input_bytes_sent"], 12);
+    assert_eq!(meta["input_bytes_written"], 0);
+
+    let (output, meta) = run("read a; read b; echo \"$a $b\"");
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("never got"));
+    assert_eq!(meta["input_bytes_sent"], 12);
+    assert_eq!(meta["input_bytes_written"], 12);
+}
+
+#[test]
+fn report_names_how_the_run_ended() {
+    let run = |extra: &[&str], script: &str| {
+        let mut args = vec!["-e", "/bin/sh", "-o", "both", "--rows", "2", "--cols", "4"];
+        args.extend(extra);
+        args.extend(["--", "-c", script]);
+        let output = run_runner(&args);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let exit = stdout.lines().last().unwrap_or_default().to_string();
+        (output.status.code(), exit)
+    };
+
+    assert_eq!(run(&[], "exit 3"), (Some(3), "EXIT 3".to_string()));
+    assert_eq!(
+        run(&["--timeout", "300"], "sleep 5"),
+        (Some(124), "EXIT TIMEOUT".to_string())
+    );
+    assert_eq!(
+        run(
+            &["--idle-timeout", "200", "--exit-on-output-idle"],
+            "echo hi; sleep 5"
+        ),
+        (Some(0), "EXIT IDLE_TIMEOUT".to_string())
+    );
+    assert_eq!(
+        run(&["--max-output-bytes", "100"], "yes"),
+        (Some(121), "EXIT OUTPUT_LIMIT".to_string())
+    );
+}
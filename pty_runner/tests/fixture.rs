@@ -0,0 +1,76 @@
+//! End-to-end tests against the bundled `ansi_fixture` helper, which runs the
+//! same on every platform.
+
+use std::process::{Command, Output};
+
+/// Run pty_runner on the fixture helper with the given extra arguments
+fn run_fixture(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pty_runner"))
+        .args([
+            "-e",
+            env!("CARGO_BIN_EXE_ansi_fixture"),
+            "--rows",
+            "3",
+            "--cols",
+            "6",
+        ])
+        .args(args)
+        .output()
+        .expect("failed to run pty_runner")
+}
+
+#[test]
+fn hex_output_encodes_each_cell_in_22_chars() {
+    let output = run_fixture(&["-o", "hex"]);
+    assert!(output.status.success());
+    let hex = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(hex.len(), 3 * 6 * 22);
+
+    let cells: Vec<&str> = (0..hex.len() / 22)
+        .map(|i| &hex[i * 22..(i + 1) * 22])
+        .collect();
+    assert_eq!(cells[0], "00000041F0F0F000000000"); // A, defaults
+    assert_eq!(cells[1], "00000042CD313100000001"); // B, bold red
+    assert_eq!(cells[2], "00000043F0F0F02472C800"); // C, blue background
+    assert_eq!(cells[3], "0000004401020300000000"); // D, truecolor
+    assert_eq!(cells[4], "00000020F0F0F000000000"); // empty cell
+    assert_eq!(cells[6], "00000045F0F0F00000000E"); // E, italic+underline+inverse
+}
+
+#[test]
+fn text_output_shows_characters_only() {
+    let output = run_fixture(&["-o", "text"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ABCD\nE\n\n");
+}
+
+#[test]
+fn html_output_styles_runs_of_cells() {
+    let output = run_fixture(&["-o", "html"]);
+    assert!(output.status.success());
+    let html = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        html.contains("<span style=\"color:#cd3131;background:#000000;font-weight:bold\">B</span>")
+    );
+    // Inverse swaps the colors
+    assert!(html.contains(
+        "<span style=\"color:#000000;background:#f0f0f0;font-style:italic;text-decoration:underline\">E</span>"
+    ));
+}
+
+#[test]
+fn svg_output_is_deterministic_and_sized_from_cells() {
+    let first = run_fixture(&["-o", "svg", "--cell-width", "10", "--cell-height", "20"]);
+    let second = run_fixture(&["-o", "svg", "--cell-width", "10", "--cell-height", "20"]);
+    assert!(first.status.success());
+    assert_eq!(first.stdout, second.stdout);
+
+    let svg = String::from_utf8_lossy(&first.stdout);
+    assert!(svg.starts_with(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"60\" height=\"60\" viewBox=\"0 0 60 60\">"
+    ));
+    // Blue background behind C, bold B, underline under E
+    assert!(svg.contains("<rect x=\"20\" y=\"0\" width=\"10\" height=\"20\" fill=\"#2472c8\"/>"));
+    assert!(svg.contains("<tspan x=\"10\" fill=\"#cd3131\" font-weight=\"bold\">B</tspan>"));
+    assert!(svg.contains("<rect x=\"0\" y=\"37\" width=\"10\" height=\"1\" fill=\"#000000\"/>"));
+}
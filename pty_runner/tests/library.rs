@@ -0,0 +1,167 @@
+//! Tests that drive the capture library directly, without the CLI.
+
+#![cfg(unix)]
+
+use pty_runner::format::{format_text_state, RenderOptions};
+use pty_runner::record::parse_raw_log;
+use pty_runner::{replay, run, LiveOutput, PtyRunner, RunConfig, SpawnError};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[test]
+fn run_captures_screen_and_exit_status() {
+    let mut config = RunConfig::new("/bin/echo");
+    config.args = vec!["hello".into(), "library".into()];
+    config.rows = 3;
+    config.cols = 20;
+
+    let result = run(&config).expect("run failed");
+
+    assert!(result.output.windows(13).any(|w| w == b"hello library"));
+    let text = format_text_state(&result.screen, 3, 20, &RenderOptions::default());
+    assert_eq!(text.lines().next(), Some("hello library"));
+    let status = result
+        .exit_status
+        .expect("child should exit before the timeout");
+    assert!(status.success());
+    assert!(!result.wait_timed_out);
+}
+
+#[test]
+fn run_reports_spawn_failure_as_spawn_error() {
+    let config = RunConfig::new("/nonexistent/program");
+    match run(&config) {
+        Err(e) => assert!(e.is::<SpawnError>(), "unexpected error: {:#}", e),
+        Ok(_) => panic!("spawning a missing program should fail"),
+    }
+}
+
+#[test]
+fn run_counts_rows_scrolled_off_without_scrollback() {
+    let mut config = RunConfig::new("/bin/sh");
+    config.args = vec!["-c".into(), "for i in 1 2 3 4 5 6; do echo $i; done".into()];
+    config.rows = 3;
+
+    let result = run(&config).expect("run failed");
+
+    assert_eq!(result.scrolled_off, 4);
+    assert!(result.scrollback.is_empty());
+}
+
+#[test]
+fn pty_runner_runs_the_same_config_repeatedly() {
+    let mut config = RunConfig::new("/bin/echo");
+    config.args = vec!["again".into()];
+    config.rows = 2;
+    let runner = PtyRunner::new(config);
+
+    for _ in 0..2 {
+        let result = runner.run().expect("run failed");
+        assert!(result.screen.contents().starts_with("again"));
+        assert!(result.duration < runner.config().timeout);
+    }
+}
+
+#[test]
+fn raw_log_holds_each_chunk_with_its_time() {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("raw_log.bin");
+    let mut config = RunConfig::new("/bin/sh");
+    config.args = vec![
+        "-c".into(),
+        "printf 'a\\033[1mb'; sleep 0.3; printf c".into(),
+    ];
+    config.raw_log = Some(path.clone());
+    config.raw_log_timestamps = true;
+
+    let result = run(&config).expect("run failed");
+
+    let log = std::fs::read(&path).unwrap();
+    let chunks = parse_raw_log(&log).unwrap();
+    let bytes: Vec<u8> = chunks
+        .iter()
+        .flat_map(|(_, chunk)| chunk.to_vec())
+        .collect();
+    assert_eq!(bytes, result.output);
+    let times: Vec<_> = chunks
+        .iter()
+        .map(|(elapsed, chunk)| (*elapsed, chunk.len()))
+        .collect();
+    // Times are kept to the microsecond
+    let expected: Vec<_> = result
+        .chunks
+        .iter()
+        .map(|&(elapsed, len)| (Duration::from_micros(elapsed.as_micros() as u64), len))
+        .collect();
+    assert_eq!(times, expected);
+    assert!(result.output.starts_with(b"a\x1b[1mb"));
+}
+
+#[test]
+fn live_output_mirrors_the_bytes_unchanged() {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("live_output.bin");
+    let _ = std::fs::remove_file(&path);
+    let mut config = RunConfig::new("/bin/sh");
+    config.args = vec![
+        "-c".into(),
+        "printf '\\033]0;title\\007\\033[31mred'; sleep 0.2; printf '\\033[2J'".into(),
+    ];
+    config.live = Some(LiveOutput::File(path.clone()));
+
+    let result = run(&config).expect("run failed");
+
+    // The OSC string the capture filters out is mirrored too
+    let mirrored = std::fs::read(&path).unwrap();
+    assert_eq!(mirrored, result.output);
+    assert!(mirrored.starts_with(b"\x1b]0;title\x07\x1b[31mred"));
+}
+
+#[test]
+fn replay_keeps_each_screen_as_it_was_left() {
+    let mut config = RunConfig::new("unused");
+    config.rows = 2;
+    config.cols = 10;
+    let text = |screen: &vt100::Screen| format_text_state(screen, 2, 10, &RenderOptions::default());
+
+    // The switch back is split from the rest of its chunk
+    let chunks: [(Duration, &[u8]); 3] = [
+        (Duration::ZERO, b"shell\x1b[?1049h"),
+        (Duration::from_millis(5), b"menu\x1b[?104"),
+        (Duration::from_millis(9), b"9lbye"),
+    ];
+    let result = replay(&config, &chunks);
+    assert!(result.alternate_screen_used);
+    assert!(!result.screen.alternate_screen());
+    assert_eq!(text(&result.screen), "shellbye\n\n");
+    let left = result
+        .alternate_left
+        .expect("the alternate screen was left");
+    assert_eq!(text(&left.screen), "menu\n\n");
+    assert_eq!(left.elapsed, Duration::from_millis(9));
+    let main = result.main_left.expect("the main screen was left");
+    assert_eq!(text(&main.screen), "shell\n\n");
+
+    let result = replay(&config, &[(Duration::ZERO, b"plain\x1b[?1049l")]);
+    assert!(!result.alternate_screen_used);
+    assert!(result.alternate_left.is_none() && result.main_left.is_none());
+
+    // The screen modes may come with others in one sequence, in any order
+    for (enter, leave) in [
+        (&b"\x1b[?1049;25h"[..], &b"\x1b[?25;1049l"[..]),
+        (b"\x1b[?25;1049h", b"\x1b[?1049;25l"),
+        (b"\x1b[?1;47h", b"\x1b[?47l"),
+    ] {
+        let data = [&b"shell"[..], enter, b"menu", leave, b"bye"].concat();
+        let result = replay(&config, &[(Duration::ZERO, &data)]);
+        assert!(result.alternate_screen_used, "{:?}", enter);
+        let left = result
+            .alternate_left
+            .expect("the alternate screen was left");
+        assert_eq!(text(&left.screen), "menu\n\n", "{:?}", enter);
+        let main = result.main_left.expect("the main screen was left");
+        assert_eq!(text(&main.screen), "shell\n\n", "{:?}", enter);
+    }
+    // vt100 does not switch for 1047, so neither screen was left
+    let result = replay(&config, &[(Duration::ZERO, b"plain\x1b[?1047;25h")]);
+    assert!(!result.alternate_screen_used);
+    assert!(result.main_left.is_none());
+}